@@ -1,3 +1,9 @@
+from .env_processing import (
+    CollectionConfig,
+    TimeoutConfig,
+    TransportConfig,
+    connect_env_process,
+)
 from .learning_coordinator import LearningCoordinator
 from .learning_coordinator_config import (
     BaseConfigModel,
@@ -11,8 +17,14 @@ from .rlgym_learn import (
     DerivedGAETrajectoryProcessorConfig as RustDerivedGAETrajectoryProcessorConfig,
 )
 from .rlgym_learn import EnvAction, EnvActionResponse, EnvActionResponseType
+from .rlgym_learn import (
+    EnvProcessError,
+    ProtocolMismatchError,
+    ShmBufferOverflowError,
+)
 from .rlgym_learn import EnvProcessInterface as RustEnvProcessInterface
 from .rlgym_learn import GAETrajectoryProcessor as RustGAETrajectoryProcessor
+from .rlgym_learn import StepLatencyStats
 from .rlgym_learn import (
     InitStrategy,
     PickleableInitStrategy,
@@ -102,9 +102,17 @@ class AgentManager(
         """
         return self.rust_agent_manager.get_env_actions(env_obs_data_dict, state_info)
 
-    def set_space_types(self, obs_space: ObsSpaceType, action_space: ActionSpaceType):
+    def process_removed_envs(self, env_ids: List[str]):
         for agent_controller in self.agent_controllers_list:
-            agent_controller.set_space_types(obs_space, action_space)
+            agent_controller.process_removed_envs(env_ids)
+
+    def set_agent_space_types(
+        self,
+        obs_spaces: Dict[AgentID, ObsSpaceType],
+        action_spaces: Dict[AgentID, ActionSpaceType],
+    ):
+        for agent_controller in self.agent_controllers_list:
+            agent_controller.set_agent_space_types(obs_spaces, action_spaces)
 
     def load_agent_controllers(
         self,
@@ -90,7 +90,7 @@ class AgentController(
         Function to handle processing of timesteps.
         :param timestep_data: Dictionary with environment ids as keys and tuples of:
 
-        timesteps from the environment (the order of agent ids in this list is fixed until a reset or set_state env action is taken),
+        timesteps from the environment, one for each agent which acted in the step (agents may join or leave the environment at any step, and agents which left have a final timestep with their last obs as the next obs, truncated unless they terminated),
 
         action associated learning data (parallel to the timestep list, and None if no timesteps exist for the environment),
 
@@ -132,9 +132,31 @@ class AgentController(
         """
         pass
 
+    def process_removed_envs(self, env_ids: List[str]):
+        """
+        Function to process environments which have been removed, such as when their env process is deleted. No more timesteps will be received for these environments, so any trajectories in progress for them should be ended.
+        :param env_ids: List of the environment ids which were removed.
+        """
+        pass
+
     def set_space_types(self, obs_space: ObsSpaceType, action_space: ActionSpaceType):
         pass
 
+    def set_agent_space_types(
+        self,
+        obs_spaces: Dict[AgentID, ObsSpaceType],
+        action_spaces: Dict[AgentID, ActionSpaceType],
+    ):
+        """
+        Function to receive the obs and action space types of each agent (as reported by the first env). Override this if spaces differ across agents.
+        By default, this calls set_space_types with the space types of the first agent.
+        :param obs_spaces: Dictionary with agent ids as keys and obs space types as values.
+        :param action_spaces: Dictionary with agent ids as keys and action space types as values.
+        """
+        self.set_space_types(
+            next(iter(obs_spaces.values())), next(iter(action_spaces.values()))
+        )
+
     def validate_config(self, config_obj: Dict[str, Any]) -> AgentControllerConfig:
         raise NotImplementedError
 
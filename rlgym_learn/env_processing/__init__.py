@@ -1,2 +1,8 @@
-from .env_process import env_process
+from .env_process import (
+    CollectionConfig,
+    TimeoutConfig,
+    TransportConfig,
+    connect_env_process,
+    env_process,
+)
 from .env_process_interface import EnvProcessInterface
@@ -1,11 +1,13 @@
 from __future__ import annotations
 
+import inspect
 import random
 import socket
 from collections.abc import Callable
 from dataclasses import dataclass
 from datetime import timedelta
-from typing import Dict, Optional
+from typing import Dict, Optional, Tuple
+from uuid import uuid4
 
 import numpy as np
 from rlgym.api import (
@@ -21,6 +23,7 @@ from rlgym.api import (
 )
 
 from ..api import StateMetrics
+from ..learning_coordinator_config import SerdeTypesModel
 from ..rlgym_learn import PickleablePyAnySerdeType
 from ..rlgym_learn import env_process as rust_env_process
 from ..rlgym_learn import recvfrom_byte_py, sendto_byte_py
@@ -38,9 +41,123 @@ class PickleableSerdeTypeConfig:
     state_metrics_serde_type: PickleablePyAnySerdeType
 
 
+@dataclass
+class TimeoutConfig:
+    """
+    Timeouts in seconds for the round trips between the EnvProcessInterface and env processes. Each is None to wait forever.
+    """
+
+    startup: Optional[float] = None
+    env_shapes: Optional[float] = None
+    step: Optional[float] = None
+    # For each env process to build the envs of a replacement env build function
+    rebuild: Optional[float] = None
+    # For each env process to acknowledge a stop and exit before it is terminated
+    shutdown: Optional[float] = 5
+
+
+@dataclass
+class TransportConfig:
+    """
+    How the EnvProcessInterface and env processes exchange messages.
+    """
+
+    # When set, env processes connect to this address (host:port) over TCP instead of using shared memory. Messages
+    # carry pickled objects and aren't encrypted, so bind to a loopback or private address only reachable from trusted
+    # hosts.
+    tcp_address: Optional[str] = None
+    # The shared secret TCP peers must identify with before anything else is exchanged. Generated by the
+    # EnvProcessInterface if None, which is only possible when it spawns the env processes itself.
+    tcp_token: Optional[str] = None
+    # Host envs on threads of the learner process instead of spawning env processes
+    in_process: bool = False
+    # Signal ready responses over Unix datagram sockets, so neither side needs the GIL to signal or wait
+    native_signalling: bool = False
+    # Check at startup that the shm buffer fits the reset and step messages of each env process
+    validate_shm_buffer_size: bool = False
+    # Remove shmem flinks left in the flinks folder by env processes which are no longer running. Only flinks guarded by
+    # a lock file which is no longer held are removed.
+    sweep_orphaned_flinks: bool = False
+    # With tcp_address set, wait for env processes started elsewhere (with connect_env_process) to connect instead of
+    # spawning them
+    external_processes: bool = False
+
+
+@dataclass
+class CollectionConfig:
+    """
+    How the EnvProcessInterface collects step data from env processes.
+    """
+
+    # Wait for every env on each collection and return them in proc id order
+    lockstep: bool = False
+    # Seconds collect_step_data waits for min_process_steps_per_inference process steps before settling for fewer
+    latency_budget: Optional[float] = None
+    # When set, min_process_steps_per_inference is tuned automatically, measuring throughput over this many collections
+    auto_tune_window: Optional[int] = None
+    # Replace env processes which die or time out instead of raising
+    respawn_dead_processes: bool = False
+    # Derive proc ids from the seed instead of generating random ones, so runs with the same seed produce identical
+    # collect_step_data output. Runs sharing a flinks folder must then use different seeds.
+    deterministic_proc_ids: bool = False
+
+
+@dataclass
+class EnvProcessConfig:
+    """
+    The settings of a single env process which aren't shared through the other configs.
+    """
+
+    proc_id: str
+    flinks_folder: str
+    shm_buffer_size: int
+    send_state_to_agent_controllers: bool
+    render: bool
+    # Seconds to wait after each step while rendering
+    render_delay: Optional[float]
+    recalculate_agent_id_every_step: bool
+    n_envs: int
+
+
+@dataclass
+class EnvProcessInterfaceConfig:
+    """
+    The settings of the Rust EnvProcessInterface which aren't shared through the other configs.
+    """
+
+    flinks_folder: str
+    min_process_steps_per_inference: int
+    send_state_to_agent_controllers: bool
+    should_collect_state_metrics: bool
+    recalculate_agent_id_every_step: bool
+    n_envs_per_process: int
+    seed: Optional[int]
+
+
+def _seed_build_env_fn(build_env_fn, seed: int):
+    """
+    If build_env_fn has a parameter named seed, passes each env built by this process its own seed derived from the
+    process seed. Envs should seed their own random.Random / np.random.Generator with it, since env threads share the
+    global RNGs of the learner process, which aren't seeded for them.
+    """
+    try:
+        seed_param = inspect.signature(build_env_fn).parameters.get("seed")
+    except (TypeError, ValueError):
+        seed_param = None
+    if seed_param is None or seed_param.kind not in (
+        inspect.Parameter.POSITIONAL_OR_KEYWORD,
+        inspect.Parameter.KEYWORD_ONLY,
+    ):
+        return build_env_fn
+    seed_sequence = np.random.SeedSequence(seed)
+    return lambda: build_env_fn(
+        seed=int(seed_sequence.spawn(1)[0].generate_state(1)[0])
+    )
+
+
 def env_process(
     proc_id: str,
-    parent_sockname,
+    parent_sockname: Optional[Tuple[str, int]],
     build_env_fn: Callable[
         [],
         RLGym[
@@ -65,34 +182,113 @@ def env_process(
     render_this_proc: bool,
     render_delay: Optional[float],
     recalculate_agent_id_every_step: bool,
+    timeout_config: TimeoutConfig,
+    transport_config: TransportConfig,
+    n_envs: int = 1,
 ):
-    child_end = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
-    child_end.bind(("127.0.0.1", 0))
-
-    random.seed(seed)
-    np.random.seed(seed)
+    # Env threads share the global RNGs of the learner process, which must not be reseeded
+    if not transport_config.in_process:
+        random.seed(seed)
+        np.random.seed(seed)
+    build_env_fn = _seed_build_env_fn(build_env_fn, seed)
 
-    sendto_byte_py(child_end, parent_sockname)
-    recvfrom_byte_py(child_end)
+    # Over TCP, the startup sync happens on the connection itself
+    child_end = None
+    if transport_config.tcp_address is None:
+        child_end = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        child_end.bind(("127.0.0.1", 0))
+        sendto_byte_py(child_end, parent_sockname)
+        child_end.settimeout(timeout_config.startup)
+        try:
+            recvfrom_byte_py(child_end)
+        except TimeoutError as e:
+            raise TimeoutError(
+                f"EnvProcessInterface did not respond within {timeout_config.startup}s during startup (proc id {proc_id})"
+            ) from e
+        child_end.settimeout(None)
 
     rust_env_process(
-        proc_id,
-        child_end,
-        parent_sockname,
+        EnvProcessConfig(
+            proc_id,
+            flinks_folder,
+            shm_buffer_size,
+            send_state_to_agent_controllers,
+            render_this_proc,
+            render_delay,
+            recalculate_agent_id_every_step,
+            n_envs,
+        ),
+        None if child_end is None else (child_end, parent_sockname),
         build_env_fn,
-        flinks_folder,
-        shm_buffer_size,
-        serde_type_config.agent_id_serde_type,
-        serde_type_config.action_serde_type,
-        serde_type_config.obs_serde_type,
-        serde_type_config.reward_serde_type,
-        serde_type_config.obs_space_serde_type,
-        serde_type_config.action_space_serde_type,
-        serde_type_config.state_serde_type,
-        serde_type_config.state_metrics_serde_type,
+        serde_type_config,
+        collect_state_metrics_fn,
+        timeout_config,
+        transport_config,
+    )
+
+
+def connect_env_process(
+    tcp_address: str,
+    tcp_token: str,
+    build_env_fn: Callable[
+        [],
+        RLGym[
+            AgentID,
+            ObsType,
+            ActionType,
+            EngineActionType,
+            RewardType,
+            StateType,
+            ObsSpaceType,
+            ActionSpaceType,
+        ],
+    ],
+    serde_types: SerdeTypesModel,
+    collect_state_metrics_fn: Optional[
+        Callable[[StateType, Dict[AgentID, RewardType]], StateMetrics]
+    ] = None,
+    send_state_to_agent_controllers: bool = False,
+    shm_buffer_size: int = 8192,
+    seed: Optional[int] = None,
+    recalculate_agent_id_every_step: bool = False,
+    timeout_config: Optional[TimeoutConfig] = None,
+    n_envs: int = 1,
+    proc_id: Optional[str] = None,
+):
+    """
+    Run an env process which connects to an EnvProcessInterface listening on tcp_address with external_processes set, e.g. from another host, identifying with tcp_token. Returns once the EnvProcessInterface stops it.
+    The serde types, n_envs, and whether state is sent to agent controllers or state metrics are collected must match the EnvProcessInterface, which checks the serde types and n_envs during the startup sync.
+    :param shm_buffer_size: The initial size of the buffer messages are written to, which grows as needed.
+    :param seed: The seed of this env process, or None to seed it randomly.
+    :param proc_id: The proc id to identify with, which must be unique among the env processes of the EnvProcessInterface, or None for a random one.
+    """
+    if seed is None:
+        seed = int(np.random.SeedSequence().generate_state(1)[0])
+    env_process(
+        str(uuid4()) if proc_id is None else proc_id,
+        None,
+        build_env_fn,
+        PickleableSerdeTypeConfig(
+            PickleablePyAnySerdeType(serde_types.agent_id_serde_type),
+            PickleablePyAnySerdeType(serde_types.action_serde_type),
+            PickleablePyAnySerdeType(serde_types.obs_serde_type),
+            PickleablePyAnySerdeType(serde_types.reward_serde_type),
+            PickleablePyAnySerdeType(serde_types.obs_space_serde_type),
+            PickleablePyAnySerdeType(serde_types.action_space_serde_type),
+            PickleablePyAnySerdeType(serde_types.state_serde_type),
+            PickleablePyAnySerdeType(serde_types.state_metrics_serde_type),
+        ),
         collect_state_metrics_fn,
         send_state_to_agent_controllers,
-        render_this_proc,
-        timedelta(seconds=render_delay),
+        "",
+        shm_buffer_size,
+        seed,
+        False,
+        0,
         recalculate_agent_id_every_step,
+        TimeoutConfig() if timeout_config is None else timeout_config,
+        TransportConfig(
+            tcp_address=tcp_address, tcp_token=tcp_token, external_processes=True
+        ),
+        n_envs,
     )
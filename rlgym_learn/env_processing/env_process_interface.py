@@ -1,13 +1,19 @@
 from __future__ import annotations
 
+import dataclasses
 import multiprocessing as mp
 import os
+import random
+import secrets
 import socket
 import time
 import traceback
 from collections.abc import Callable
-from typing import Dict, Generic, List, Optional, Tuple, Union
-from uuid import uuid4
+from datetime import timedelta
+from typing import Any, Dict, Generic, List, Optional, Tuple, Union
+from uuid import UUID, uuid4
+
+import numpy as np
 
 from rlgym.api import (
     ActionSpaceType,
@@ -24,10 +30,23 @@ from rlgym.api import (
 from ..api import ActionAssociatedLearningData, StateMetrics
 from ..experience import Timestep
 from ..learning_coordinator_config import SerdeTypesModel
-from ..rlgym_learn import EnvAction
+from ..rlgym_learn import EnvAction, EnvProcessError
 from ..rlgym_learn import EnvProcessInterface as RustEnvProcessInterface
-from ..rlgym_learn import PickleablePyAnySerdeType, recvfrom_byte_py, sendto_byte_py
-from .env_process import PickleableSerdeTypeConfig, env_process
+from ..rlgym_learn import (
+    PickleablePyAnySerdeType,
+    StepLatencyStats,
+    recvfrom_byte_py,
+    sendto_byte_py,
+)
+from .env_process import (
+    CollectionConfig,
+    EnvProcessInterfaceConfig,
+    PickleableSerdeTypeConfig,
+    TimeoutConfig,
+    TransportConfig,
+    env_process,
+)
+from .env_thread import EnvThread
 
 try:
     from tqdm import tqdm
@@ -37,6 +56,10 @@ except ImportError:
         return iterator
 
 
+def _to_timedelta(seconds: Optional[float]) -> Optional[timedelta]:
+    return None if seconds is None else timedelta(seconds=seconds)
+
+
 class EnvProcessInterface(
     Generic[
         AgentID,
@@ -74,9 +97,31 @@ class EnvProcessInterface(
         send_state_to_agent_controllers: bool,
         flinks_folder: str,
         shm_buffer_size: int,
-        seed: int,
+        seed: Optional[int],
         recalculate_agent_id_every_step: bool,
+        n_envs_per_process: int = 1,
+        timeout_config: Optional[TimeoutConfig] = None,
+        transport_config: Optional[TransportConfig] = None,
+        collection_config: Optional[CollectionConfig] = None,
     ):
+        if timeout_config is None:
+            timeout_config = TimeoutConfig()
+        if transport_config is None:
+            transport_config = TransportConfig()
+        if collection_config is None:
+            collection_config = CollectionConfig()
+        if (
+            transport_config.tcp_address is not None
+            and transport_config.tcp_token is None
+        ):
+            if transport_config.external_processes:
+                raise ValueError(
+                    "A tcp_token must be set for env processes started elsewhere to identify with"
+                )
+            # Spawned env processes are passed the transport config, so any secret token will do
+            transport_config = dataclasses.replace(
+                transport_config, tcp_token=secrets.token_hex(16)
+            )
         self.build_env_fn = build_env_fn
         self.serde_type_config = PickleableSerdeTypeConfig(
             PickleablePyAnySerdeType(serde_types.agent_id_serde_type),
@@ -93,26 +138,45 @@ class EnvProcessInterface(
         self.flinks_folder = flinks_folder
         self.shm_buffer_size = shm_buffer_size
         self.seed = seed
+        # Every spawned process gets its own seed from this, including processes spawned to replace others
+        self.process_seed_sequence = np.random.SeedSequence(seed)
+        self.proc_id_rng = (
+            random.Random(seed)
+            if collection_config.deterministic_proc_ids and seed is not None
+            else None
+        )
         self.recalculate_agent_id_every_step = recalculate_agent_id_every_step
+        self.n_envs_per_process = n_envs_per_process
+        self.timeout_config = timeout_config
+        self.transport_config = transport_config
+        self.respawn_dead_processes = collection_config.respawn_dead_processes
+        self.latency_budget = _to_timedelta(collection_config.latency_budget)
         self.n_procs = 0
+        # Env ids of the envs of processes which have died since take_removed_env_ids was last called
+        self.removed_env_ids: List[str] = []
 
         os.makedirs(flinks_folder, exist_ok=True)
 
         should_collect_state_metrics = collect_state_metrics_fn is not None
         self.rust_env_process_interface = RustEnvProcessInterface(
-            serde_types.agent_id_serde_type,
-            serde_types.action_serde_type,
-            serde_types.obs_serde_type,
-            serde_types.reward_serde_type,
-            serde_types.obs_space_serde_type,
-            serde_types.action_space_serde_type,
-            serde_types.state_serde_type,
-            serde_types.state_metrics_serde_type,
-            self.recalculate_agent_id_every_step,
-            flinks_folder,
-            min_process_steps_per_inference,
-            self.send_state_to_agent_controllers,
-            should_collect_state_metrics,
+            serde_types,
+            EnvProcessInterfaceConfig(
+                flinks_folder,
+                min_process_steps_per_inference,
+                self.send_state_to_agent_controllers,
+                should_collect_state_metrics,
+                self.recalculate_agent_id_every_step,
+                n_envs_per_process,
+                seed,
+            ),
+            timeout_config,
+            transport_config,
+            collection_config,
+        )
+        # Env processes connect to the bound address, which differs from tcp_address if it requested an ephemeral port
+        self.transport_config = dataclasses.replace(
+            transport_config,
+            tcp_address=self.rust_env_process_interface.transport_address(),
         )
 
     def init_processes(
@@ -131,53 +195,61 @@ class EnvProcessInterface(
                 Optional[Dict[AgentID, bool]],
             ],
         ],
-        ObsSpaceType,
-        ActionSpaceType,
+        Dict[AgentID, ObsSpaceType],
+        Dict[AgentID, ActionSpaceType],
     ]:
         """
         Initialize and spawn environment processes.
-        :param n_processes: Number of processes to spawn.
+        :param n_processes: Number of processes to spawn, or to wait for if they are started elsewhere (transport_config.external_processes).
         :param collect_metrics_fn: A user-defined function that the environment processes will use to collect metrics
                about the environment at each timestep.
         :param spawn_delay: Delay between spawning environment instances. Defaults to None.
         :param render: Whether an environment should be rendered while collecting timesteps.
         :param render_delay: A period in seconds to delay a process between frames while rendering.
-        :return: A tuple containing parallel lists of agent ids and observations for inference (per environment), state info (per environment), observation space type, and action space type.
+        :return: A tuple containing parallel lists of agent ids and observations for inference (per environment), state info (per environment), observation space types (per agent id across all environments), and action space types (per agent id across all environments). Agents with the same id must have the same spaces in every environment.
         """
 
-        can_fork = "forkserver" in mp.get_all_start_methods()
-        start_method = "forkserver" if can_fork else "spawn"
-        context = mp.get_context(start_method)
         self.n_procs = n_processes
-
+        if self.transport_config.external_processes:
+            print(
+                f"Waiting for {n_processes} env processes to connect to {self.transport_config.tcp_address}..."
+            )
+            result = self.rust_env_process_interface.init_processes([], n_processes)
+            self.processes = [
+                (None, None, None, proc_id)
+                for proc_id in self.rust_env_process_interface.env_process_proc_ids()
+            ]
+            return result
+
+        process_class = self._get_process_class()
         self.processes = [None for i in range(n_processes)]
 
         # Spawn child processes
         print("Spawning processes...")
         for proc_idx in tqdm(range(n_processes)):
-            proc_id = str(uuid4())
+            proc_id = self._new_proc_id()
 
             render_this_proc = proc_idx == 0 and render
 
-            # Create socket to communicate with child
-            parent_end = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
-            parent_end.bind(("127.0.0.1", 0))
-
-            process = context.Process(
+            parent_end = self._new_parent_end()
+            process = process_class(
                 target=env_process,
                 args=(
                     proc_id,
-                    parent_end.getsockname(),
+                    None if parent_end is None else parent_end.getsockname(),
                     self.build_env_fn,
                     self.serde_type_config,
                     self.collect_state_metrics_fn,
                     self.send_state_to_agent_controllers,
                     self.flinks_folder,
                     self.shm_buffer_size,
-                    self.seed + proc_idx,
+                    self._new_process_seed(),
                     render_this_proc,
                     render_delay,
                     self.recalculate_agent_id_every_step,
+                    self.timeout_config,
+                    self.transport_config,
+                    self.n_envs_per_process,
                 ),
             )
             process.start()
@@ -189,9 +261,7 @@ class EnvProcessInterface(
         for pid_idx in tqdm(range(n_processes)):
             process, parent_end, _, proc_id = self.processes[pid_idx]
 
-            # Get child endpoint
-            _, child_sockname = recvfrom_byte_py(parent_end)
-            sendto_byte_py(parent_end, child_sockname)
+            child_sockname = self._sync_child_sockname(parent_end, proc_id)
 
             if spawn_delay is not None:
                 time.sleep(spawn_delay)
@@ -205,6 +275,52 @@ class EnvProcessInterface(
 
         return self.rust_env_process_interface.init_processes(self.processes)
 
+    def _new_proc_id(self) -> str:
+        if self.proc_id_rng is None:
+            return str(uuid4())
+        return str(UUID(int=self.proc_id_rng.getrandbits(128), version=4))
+
+    def _new_process_seed(self) -> int:
+        return int(self.process_seed_sequence.spawn(1)[0].generate_state(1)[0])
+
+    def _get_process_class(self):
+        if self.transport_config.in_process:
+            return EnvThread
+        can_fork = "forkserver" in mp.get_all_start_methods()
+        start_method = "forkserver" if can_fork else "spawn"
+        return mp.get_context(start_method).Process
+
+    def _new_parent_end(self) -> Optional[socket.socket]:
+        """
+        Create the socket the startup sync with a spawned process goes through, unless it happens over TCP.
+        """
+        if self.transport_config.tcp_address is not None:
+            return None
+        parent_end = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        parent_end.bind(("127.0.0.1", 0))
+        return parent_end
+
+    def _sync_child_sockname(self, parent_end: Optional[socket.socket], proc_id: str):
+        """
+        Exchange sockets with a spawned process, returning the address of its end.
+        """
+        if parent_end is None:
+            return None
+        child_sockname = self._recv_child_sockname(parent_end, proc_id)
+        sendto_byte_py(parent_end, child_sockname)
+        return child_sockname
+
+    def _recv_child_sockname(self, parent_end: socket.socket, proc_id: str):
+        parent_end.settimeout(self.timeout_config.startup)
+        try:
+            _, child_sockname = recvfrom_byte_py(parent_end)
+        except TimeoutError as e:
+            raise TimeoutError(
+                f"Env process with proc id {proc_id} did not respond within {self.timeout_config.startup}s during process startup"
+            ) from e
+        parent_end.settimeout(None)
+        return child_sockname
+
     def increase_min_process_steps_per_inference(self) -> int:
         return (
             self.rust_env_process_interface.increase_min_process_steps_per_inference()
@@ -216,38 +332,43 @@ class EnvProcessInterface(
         )
 
     def add_process(self):
+        """
+        Spawn an env process, or wait for one started elsewhere to connect if transport_config.external_processes is set.
+        """
         self.n_procs += 1
-        can_fork = "forkserver" in mp.get_all_start_methods()
-        start_method = "forkserver" if can_fork else "spawn"
-        context = mp.get_context(start_method)
+        if self.transport_config.external_processes:
+            proc_id = self.rust_env_process_interface.add_external_process()
+            self.processes.append((None, None, None, proc_id))
+            return
 
-        self.processes.append(None)
+        process_class = self._get_process_class()
 
         # Set up process
-        proc_id = uuid4()
-        parent_end = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
-        parent_end.bind(("127.0.0.1", 0))
-        process = context.Process(
+        proc_id = self._new_proc_id()
+        parent_end = self._new_parent_end()
+        process = process_class(
             target=env_process,
             args=(
                 proc_id,
-                parent_end.getsockname(),
+                None if parent_end is None else parent_end.getsockname(),
                 self.build_env_fn,
                 self.serde_type_config,
                 self.collect_state_metrics_fn,
                 self.send_state_to_agent_controllers,
                 self.flinks_folder,
                 self.shm_buffer_size,
-                self.seed + self.n_procs,
+                self._new_process_seed(),
                 False,
                 0,
                 self.recalculate_agent_id_every_step,
+                self.timeout_config,
+                self.transport_config,
+                self.n_envs_per_process,
             ),
         )
 
         process.start()
-        _, child_sockname = recvfrom_byte_py(parent_end)
-        sendto_byte_py(parent_end, child_sockname)
+        child_sockname = self._sync_child_sockname(parent_end, proc_id)
 
         self.processes.append(
             (
@@ -267,24 +388,151 @@ class EnvProcessInterface(
             )
         )
 
-    def delete_process(self):
+    def _handle_dead_processes(self):
+        for (
+            proc_id,
+            reason,
+            env_ids,
+        ) in self.rust_env_process_interface.take_dead_processes():
+            print(f"Env process {proc_id} died ({reason}), respawning...")
+            self.removed_env_ids += env_ids
+            self.n_procs -= 1
+            pid_idx = next(
+                idx
+                for idx, (_, _, _, other_proc_id) in enumerate(self.processes)
+                if other_proc_id == proc_id
+            )
+            (process, parent_end, _, _) = self.processes.pop(pid_idx)
+
+            if process is not None:
+                try:
+                    process.join(timeout=1)
+                except Exception:
+                    print("Unable to join process")
+                    traceback.print_exc()
+
+            self._close_parent_end(parent_end)
+
+            self.add_process()
+
+    def take_removed_env_ids(self) -> List[str]:
         """
-        It is expected that this method is called after send_actions and before collect_step_data
+        :return: The env ids of the envs of processes which died (and were respawned) since the last call, whose trajectories should be finalized by the agent controllers.
         """
+        removed_env_ids = self.removed_env_ids
+        self.removed_env_ids = []
+        return removed_env_ids
+
+    def delete_process(self, proc_id: Optional[str] = None) -> List[str]:
+        """
+        Stop an env process and wait for it to exit. Any env action in flight for it is abandoned.
+        :param proc_id: The proc id of the process to delete (as in self.processes) or the env id of one of its envs, or None to delete the most recently added one.
+        :return: The env ids of the deleted process, whose trajectories should be finalized by the agent controllers.
+        """
+        if proc_id is None:
+            if not self.processes:
+                raise ValueError("There are no env processes to delete")
+            proc_id = self.processes[-1][3]
+        else:
+            proc_id = self.rust_env_process_interface.env_process_proc_id(proc_id)
+        pid_idx = next(
+            idx
+            for idx, (_, _, _, other_proc_id) in enumerate(self.processes)
+            if other_proc_id == proc_id
+        )
         self.n_procs -= 1
+        (process, parent_end, _, _) = self.processes.pop(pid_idx)
         try:
-            self.rust_env_process_interface.delete_process()
-        except Exception:
-            print("Failed to send stop signal to child process!")
-            traceback.print_exc()
-        (process, parent_end, _, _) = self.processes.pop()
+            return self.rust_env_process_interface.delete_process(proc_id)
+        finally:
+            self._join_process(process)
+            self._close_parent_end(parent_end)
+
+    def rebuild_envs(
+        self,
+        build_env_fn: Callable[
+            [],
+            RLGym[
+                AgentID,
+                ObsType,
+                ActionType,
+                EngineActionType,
+                RewardType,
+                StateType,
+                ObsSpaceType,
+                ActionSpaceType,
+            ],
+        ],
+        proc_ids: Optional[List[str]] = None,
+    ):
+        """
+        Replace the envs of running env processes without losing in-progress episodes. The new build function is sent to each process along with its next env actions, and each env is replaced at the end of its current episode.
+        If an agent of the new envs has different obs or action spaces than an agent with the same id had before, the new envs are discarded and send_env_actions raises an error (after sending all env actions).
+        :param build_env_fn: The replacement env build function, which must be pickleable.
+        :param proc_ids: The proc ids (as in self.processes) of the processes to rebuild or env ids of their envs, or None to rebuild all of them. In the latter case, processes added later also use the new build function.
+        """
+        if proc_ids is None:
+            proc_ids = [proc_id for (_, _, _, proc_id) in self.processes]
+            self.build_env_fn = build_env_fn
+        self.rust_env_process_interface.queue_env_rebuild(
+            build_env_fn,
+            [(proc_id, self._new_process_seed()) for proc_id in proc_ids],
+        )
 
+    def send_env_command(
+        self, method_name: str, payload: Any, proc_ids: Optional[List[str]] = None
+    ) -> int:
+        """
+        Call a method on the envs of running env processes, e.g. to change reward weights or game speed mid-training. The command is sent to each process along with its next env actions, and each env in the process has method_name called with the payload.
+        If the method raises, the exception is reported by take_env_command_replies and the env process keeps running. If the exchange with the env process fails otherwise, the env process is retired without being sent its env actions. It is respawned if respawn_dead_processes is set, and otherwise send_env_actions raises the error after sending all other env actions.
+        :param method_name: The name of the handler method on the env.
+        :param payload: The argument to pass to the handler method, which must be pickleable.
+        :param proc_ids: The proc ids (as in self.processes) of the processes to send the command to or env ids of their envs, or None to send it to all of them.
+        :return: The id of the env command, which its replies are reported with by take_env_command_replies.
+        """
+        return self.rust_env_process_interface.queue_env_command(
+            method_name, payload, proc_ids
+        )
+
+    def take_env_command_replies(
+        self,
+    ) -> List[Tuple[int, str, Optional[Any], Optional[EnvProcessError]]]:
+        """
+        :return: The env command id, env id, reply (None if the handler method returned None or raised), and EnvProcessError describing the exception raised by the handler method (None if it returned) of each env which has handled an env command since the last call.
+        """
+        return self.rust_env_process_interface.take_env_command_replies()
+
+    def get_env_space_types(
+        self, env_id: str
+    ) -> Tuple[Dict[AgentID, ObsSpaceType], Dict[AgentID, ActionSpaceType]]:
+        """
+        Query the current obs and action spaces by agent id of an env, for when its agent composition has changed. Call this after collect_step_data has returned a reset for the env and before send_env_actions sends it an env action.
+        :param env_id: The env id of the env to query.
+        :return: Tuple of (obs spaces, action spaces), each a dict from agent id to space.
+        """
+        return self.rust_env_process_interface.get_space_types(env_id)
+
+    def _join_process(self, process):
+        """
+        Wait up to shutdown_timeout for a stopped process to exit, terminating it if it doesn't. Processes started elsewhere (None) exit on their own once stopped.
+        """
+        if process is None:
+            return
         try:
-            process.join()
+            process.join(timeout=self.timeout_config.shutdown)
+            if process.is_alive():
+                print(
+                    f"Env process did not exit within {self.timeout_config.shutdown} seconds, terminating..."
+                )
+                process.terminate()
+                process.join(timeout=self.timeout_config.shutdown)
         except Exception:
             print("Unable to join process")
             traceback.print_exc()
 
+    def _close_parent_end(self, parent_end: Optional[socket.socket]):
+        if parent_end is None:
+            return
         try:
             parent_end.close()
         except Exception:
@@ -316,24 +564,39 @@ class EnvProcessInterface(
         """
         :return: Total timesteps collected, parallel lists of AgentID and ObsType for inference (per environment), a dict of timesteps and related data (per environment), and a dict of state info (per environment).
         """
-        return self.rust_env_process_interface.collect_step_data()
+        step_data = self.rust_env_process_interface.collect_step_data(
+            self.latency_budget
+        )
+        if self.respawn_dead_processes:
+            self._handle_dead_processes()
+        return step_data
+
+    def get_stats(self) -> Dict[str, Any]:
+        """
+        :return: Counters and timings (in seconds) of step data collection, including the env call timings reported by env processes. Per-process env call timings are under "processes", by proc id.
+        """
+        return self.rust_env_process_interface.get_stats()
+
+    def get_min_process_steps_tuner_metrics(self) -> Optional[Dict[str, float]]:
+        """
+        :return: The measurements behind the last automatic adjustment of min_process_steps_per_inference, or None if it isn't tuned automatically.
+        """
+        return self.rust_env_process_interface.min_process_steps_tuner_metrics()
+
+    def get_step_latency_stats(self) -> Dict[str, StepLatencyStats]:
+        """
+        :return: Step latency statistics by proc id of env process, which can be used to choose the latency budget.
+        """
+        return self.rust_env_process_interface.step_latency_stats()
 
     def cleanup(self):
         """
         Clean up resources and terminate processes.
         """
-        self.rust_env_process_interface.cleanup()
+        unacknowledged_proc_ids = self.rust_env_process_interface.cleanup()
+        for proc_id in unacknowledged_proc_ids:
+            print(f"Env process {proc_id} did not acknowledge the stop")
         for _ in range(len(self.processes)):
             (process, parent_end, _, _) = self.processes.pop()
-
-            try:
-                process.join()
-            except Exception:
-                print("Unable to join process")
-                traceback.print_exc()
-
-            try:
-                parent_end.close()
-            except Exception:
-                print("Unable to close parent connection")
-                traceback.print_exc()
+            self._join_process(process)
+            self._close_parent_end(parent_end)
@@ -0,0 +1,25 @@
+import threading
+import traceback
+
+
+class EnvThread(threading.Thread):
+    """
+    Hosts an env process on a thread of the current process, so env code can be stepped through with a debugger.
+    Provides the subset of the multiprocessing.Process interface used by the EnvProcessInterface.
+    """
+
+    def __init__(self, target, args):
+        super().__init__(target=target, args=args, daemon=True)
+        self.exitcode = None
+
+    def run(self):
+        try:
+            super().run()
+            self.exitcode = 0
+        except BaseException:
+            traceback.print_exc()
+            self.exitcode = 1
+
+    def terminate(self):
+        # Threads can't be killed. The env thread exits once the EnvProcessInterface drops its end of the channel.
+        pass
@@ -19,7 +19,12 @@ from rlgym.api import (
 
 from .agent import AgentManager
 from .api import ActionAssociatedLearningData, AgentController, StateMetrics
-from .env_processing import EnvProcessInterface
+from .env_processing import (
+    CollectionConfig,
+    EnvProcessInterface,
+    TimeoutConfig,
+    TransportConfig,
+)
 from .learning_coordinator_config import (
     DEFAULT_CONFIG_FILENAME,
     LearningCoordinatorConfigModel,
@@ -103,12 +108,36 @@ class LearningCoordinator(
             self.config.base_config.shm_buffer_size,
             self.config.base_config.random_seed,
             self.config.process_config.recalculate_agent_id_every_step,
+            self.config.process_config.n_envs_per_process,
+            TimeoutConfig(
+                startup=self.config.process_config.startup_timeout,
+                env_shapes=self.config.process_config.env_shapes_timeout,
+                step=self.config.process_config.step_timeout,
+                rebuild=self.config.process_config.rebuild_timeout,
+                shutdown=self.config.process_config.shutdown_timeout,
+            ),
+            TransportConfig(
+                tcp_address=self.config.process_config.tcp_address,
+                tcp_token=self.config.process_config.tcp_token,
+                in_process=self.config.process_config.in_process,
+                native_signalling=self.config.process_config.native_signalling,
+                validate_shm_buffer_size=self.config.base_config.validate_shm_buffer_size,
+                sweep_orphaned_flinks=self.config.process_config.sweep_orphaned_flinks,
+                external_processes=self.config.process_config.external_processes,
+            ),
+            CollectionConfig(
+                lockstep=self.config.process_config.lockstep,
+                latency_budget=self.config.process_config.latency_budget,
+                auto_tune_window=self.config.process_config.min_process_steps_auto_tune_window,
+                respawn_dead_processes=self.config.process_config.respawn_dead_processes,
+                deterministic_proc_ids=self.config.process_config.deterministic_proc_ids,
+            ),
         )
         (
             initial_env_obs_data_dict,
             initial_state_info,
-            obs_space,
-            action_space,
+            obs_spaces,
+            action_spaces,
         ) = self.env_process_interface.init_processes(
             n_processes=self.config.process_config.n_proc,
             spawn_delay=self.config.process_config.instance_launch_delay,
@@ -116,7 +145,7 @@ class LearningCoordinator(
             render_delay=self.config.process_config.render_delay,
         )
         print("Loading agent controllers...")
-        self.agent_manager.set_space_types(obs_space, action_space)
+        self.agent_manager.set_agent_space_types(obs_spaces, action_spaces)
         self.agent_manager.load_agent_controllers(self.config)
         # Handle actions for observations created on process init
         self.initial_env_actions = self.agent_manager.get_env_actions(
@@ -176,6 +205,9 @@ class LearningCoordinator(
             )
             self.cumulative_timesteps += total_timesteps_collected
             self.agent_manager.process_timestep_data(timestep_data)
+            removed_env_ids = self.env_process_interface.take_removed_env_ids()
+            if removed_env_ids:
+                self.agent_manager.process_removed_envs(removed_env_ids)
 
             self.env_process_interface.send_env_actions(
                 self.agent_manager.get_env_actions(env_obs_data_dict, state_info)
@@ -214,7 +246,8 @@ class LearningCoordinator(
                 print(f"Process added. ({self.env_process_interface.n_procs} total)")
             if c == "d":
                 print("Deleting process...")
-                self.env_process_interface.delete_process()
+                env_ids = self.env_process_interface.delete_process()
+                self.agent_manager.process_removed_envs(env_ids)
                 print(f"Process deleted. ({self.env_process_interface.n_procs} total)")
             if c == "j":
                 min_process_steps_per_inference = (
@@ -15,11 +15,46 @@ class ProcessConfigModel(BaseModel):
     render_delay: float = 0
     instance_launch_delay: Optional[float] = None
     recalculate_agent_id_every_step: bool = False
+    startup_timeout: Optional[float] = None
+    env_shapes_timeout: Optional[float] = None
+    step_timeout: Optional[float] = None
+    # Seconds to wait for each env process to build the envs of a replacement env build function. None waits forever.
+    rebuild_timeout: Optional[float] = None
+    respawn_dead_processes: bool = False
+    # Derive proc ids from random_seed instead of generating random ones, so runs with the same seed produce identical
+    # collect_step_data output. Runs sharing a flinks folder must then use different seeds.
+    deterministic_proc_ids: bool = False
+    native_signalling: bool = False
+    n_envs_per_process: int = 1
+    # When set, env processes connect to this address (host:port) over TCP instead of using shared memory. Messages
+    # carry pickled objects and aren't encrypted, so bind to a loopback or private address only reachable from trusted
+    # hosts.
+    tcp_address: Optional[str] = None
+    # The shared secret env processes must identify with over TCP. Generated if None, unless external_processes is set.
+    tcp_token: Optional[str] = None
+    # With tcp_address set, wait for n_proc env processes started elsewhere (with connect_env_process) to connect
+    # instead of spawning them
+    external_processes: bool = False
+    # Host envs on threads of the learner process instead of spawning env processes, which is useful for debugging
+    in_process: bool = False
+    # Wait for every env on each collection and return them in proc id order, ignoring min_process_steps_per_inference
+    lockstep: bool = False
+    # Seconds collect_step_data waits for min_process_steps_per_inference process steps before settling for fewer
+    latency_budget: Optional[float] = None
+    # When set, min_process_steps_per_inference is tuned automatically, measuring throughput over this many collections
+    min_process_steps_auto_tune_window: Optional[int] = None
+    # Seconds to wait for each env process to acknowledge a stop and exit before terminating it. None waits forever.
+    shutdown_timeout: Optional[float] = 5
+    # Remove shmem flinks left in the flinks folder by env processes which are no longer running (e.g. after a crash).
+    # Only flinks guarded by a lock file which is no longer held are removed.
+    sweep_orphaned_flinks: bool = False
 
     @model_validator(mode="after")
     def set_default_min_process_steps_per_inference(self):
         if self.min_process_steps_per_inference < 0:
-            self.min_process_steps_per_inference = max(1, int(0.45 * self.n_proc))
+            self.min_process_steps_per_inference = max(
+                1, int(0.45 * self.n_proc * self.n_envs_per_process)
+            )
         return self
 
 
@@ -42,6 +77,7 @@ class BaseConfigModel(BaseModel):
     device: str = "auto"
     random_seed: int = 123
     shm_buffer_size: int = 8192
+    validate_shm_buffer_size: bool = False
     flinks_folder: str = "shmem_flinks"
     timestep_limit: int = 5_000_000_000
     batched_tensor_action_associated_learning_data: bool = True
@@ -36,7 +36,16 @@ from rlgym.api import (
 from rlgym.rocket_league.api import Car, GameConfig, GameState, PhysicsObject
 
 from rlgym_learn.api import ActionAssociatedLearningData, AgentController, StateMetrics
+from rlgym_learn.env_processing.env_process import (
+    CollectionConfig,
+    EnvProcessConfig,
+    EnvProcessInterfaceConfig,
+    PickleableSerdeTypeConfig,
+    TimeoutConfig,
+    TransportConfig,
+)
 from rlgym_learn.experience import Timestep
+from rlgym_learn.learning_coordinator_config import SerdeTypesModel
 from rlgym_learn.standard_impl import BatchRewardTypeNumpyConverter
 
 if TYPE_CHECKING:
@@ -46,6 +55,10 @@ if TYPE_CHECKING:
 
 class EnvAction: ...
 
+class EnvProcessError(Exception): ...
+class ShmBufferOverflowError(Exception): ...
+class ProtocolMismatchError(Exception): ...
+
 class EnvActionResponseType:
     STEP = ...
     RESET = ...
@@ -77,6 +90,13 @@ class DerivedGAETrajectoryProcessorConfig:
         cls, gamma: float, lmbda: float, dtype: dtype
     ) -> DerivedGAETrajectoryProcessorConfig: ...
 
+class StepLatencyStats:
+    n_steps: int
+    last: float
+    mean: float
+    ema: float
+    max: float
+
 class EnvProcessInterface(
     Generic[
         AgentID,
@@ -93,37 +113,57 @@ class EnvProcessInterface(
 ):
     def __new__(
         cls,
-        agent_id_serde: PyAnySerdeType[AgentID],
-        action_serde: PyAnySerdeType[ActionType],
-        obs_serde: PyAnySerdeType[ObsType],
-        reward_serde: PyAnySerdeType[RewardType],
-        obs_space_serde: PyAnySerdeType[ObsSpaceType],
-        action_space_serde: PyAnySerdeType[ActionSpaceType],
-        state_serde_option: Optional[PyAnySerdeType[StateType]],
-        state_metrics_serde_option: Optional[PyAnySerdeType[StateMetrics]],
-        recalculate_agent_id_every_step: bool,
-        flinks_folder_option: str,
-        min_process_steps_per_inference: int,
-        send_state_to_agent_controllers: bool,
-        should_collect_state_metrics: bool,
+        serde_type_config: SerdeTypesModel,
+        env_process_interface_config: EnvProcessInterfaceConfig,
+        timeout_config: TimeoutConfig,
+        transport_config: TransportConfig,
+        collection_config: CollectionConfig,
     ) -> EnvProcessInterface: ...
     def init_processes(
-        self, proc_package_defs: List[Process, socket, _RetAddress, str]
+        self,
+        proc_package_defs: List[
+            Tuple[Process, Optional[socket], Optional[_RetAddress], str]
+        ],
+        n_external_processes: int = 0,
     ) -> Tuple[
         Dict[str, Tuple[List[AgentID], List[ObsType]]],
         Dict[str, Tuple[Optional[StateType], None, None]],
-        ObsSpaceType,
-        ActionSpaceType,
+        Dict[AgentID, ObsSpaceType],
+        Dict[AgentID, ActionSpaceType],
     ]: ...
     def add_process(
-        self, proc_package_def: Tuple[Process, socket, _RetAddress, str]
+        self,
+        proc_package_def: Tuple[Process, Optional[socket], Optional[_RetAddress], str],
+    ): ...
+    def add_external_process(self) -> str: ...
+    def env_process_proc_ids(self) -> List[str]: ...
+    def env_process_proc_id(self, proc_id: str) -> str: ...
+    def delete_process(self, proc_id_option: Optional[str] = None) -> List[str]: ...
+    def take_dead_processes(self) -> List[Tuple[str, str, List[str]]]: ...
+    def queue_env_rebuild(
+        self, build_env_fn: Callable[[], Any], proc_id_seed_list: List[Tuple[str, int]]
     ): ...
-    def delete_process(self): ...
+    def queue_env_command(
+        self,
+        method_name: str,
+        payload: Any,
+        proc_id_list_option: Optional[List[str]] = None,
+    ) -> int: ...
+    def take_env_command_replies(
+        self,
+    ) -> List[Tuple[int, str, Optional[Any], Optional[EnvProcessError]]]: ...
+    def get_space_types(
+        self, proc_id: str
+    ) -> Tuple[Dict[AgentID, ObsSpaceType], Dict[AgentID, ActionSpaceType]]: ...
     def increase_min_process_steps_per_inference(self) -> int: ...
     def decrease_min_process_steps_per_inference(self) -> int: ...
-    def cleanup(self): ...
+    def transport_address(self) -> Optional[str]: ...
+    def step_latency_stats(self) -> Dict[str, StepLatencyStats]: ...
+    def get_stats(self) -> Dict[str, Any]: ...
+    def min_process_steps_tuner_metrics(self) -> Optional[Dict[str, float]]: ...
+    def cleanup(self) -> List[str]: ...
     def collect_step_data(
-        self,
+        self, latency_budget_option: Optional[timedelta] = None
     ) -> Tuple[
         int,
         Dict[str, Tuple[List[AgentID], List[ObsType]]],
@@ -202,9 +242,8 @@ class GAETrajectoryProcessor:
     ]: ...
 
 def env_process(
-    proc_id: str,
-    child_end,
-    parent_sockname,
+    env_process_config: EnvProcessConfig,
+    startup_sync_option: Optional[Tuple[socket, _RetAddress]],
     build_env_fn: Callable[
         [],
         RLGym[
@@ -218,23 +257,12 @@ def env_process(
             ActionSpaceType,
         ],
     ],
-    flinks_folder: str,
-    shm_buffer_size: int,
-    agent_id_serde: PyAnySerdeType[AgentID],
-    action_serde: PyAnySerdeType[ActionType],
-    obs_serde: PyAnySerdeType[ObsType],
-    reward_serde: PyAnySerdeType[RewardType],
-    obs_space_serde: PyAnySerdeType[ObsSpaceType],
-    action_space_serde: PyAnySerdeType[ActionSpaceType],
-    state_serde_option: Optional[PyAnySerdeType[StateType]],
-    state_metrics_serde_option: Optional[PyAnySerdeType[StateMetrics]],
+    serde_type_config: PickleableSerdeTypeConfig,
     collect_state_metrics_fn_option: Optional[
         Callable[[StateType, Dict[AgentID, RewardType]], StateMetrics]
     ],
-    send_state_to_agent_controllers: bool,
-    render: bool,
-    render_delay_option: Optional[timedelta],
-    recalculate_agent_id_every_step: bool,
+    timeout_config: TimeoutConfig,
+    transport_config: TransportConfig,
 ): ...
 def recvfrom_byte_py(socket: socket): ...
 def sendto_byte_py(socket: socket, address: _RetAddress): ...
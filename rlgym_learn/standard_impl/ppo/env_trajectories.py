@@ -17,50 +17,65 @@ class EnvTrajectories(Generic[AgentID, ObsType, ActionType, RewardType]):
             [List[AgentID]], List[int]
         ] = lambda agent_id_list: list(range(len(agent_id_list))),
     ) -> None:
-        self.used_agent_id_idx_map = {
-            agent_ids[idx]: idx for idx in agent_choice_fn(agent_ids)
-        }
+        self.agent_choice_fn = agent_choice_fn
+        self.seen_agent_ids = set()
+        self.used_agent_ids: List[AgentID] = []
         self.obs_lists: Dict[AgentID, List[ObsType]] = {}
         self.action_lists: Dict[AgentID, List[ActionType]] = {}
+        self.log_prob_lists: Dict[AgentID, List[Tensor]] = {}
         self.reward_lists: Dict[AgentID, List[RewardType]] = {}
         self.final_obs: Dict[AgentID, Optional[ObsType]] = {}
         self.dones: Dict[AgentID, bool] = {}
         self.truncateds: Dict[AgentID, bool] = {}
-        for agent_id in self.used_agent_id_idx_map:
+        self._add_agents(agent_ids)
+
+    def _add_agents(self, agent_ids: List[AgentID]):
+        """
+        Start trajectories for the agent ids chosen by the agent choice fn which haven't been seen in this env yet.
+        """
+        for idx in self.agent_choice_fn(agent_ids):
+            agent_id = agent_ids[idx]
+            if agent_id in self.seen_agent_ids:
+                continue
+            self.used_agent_ids.append(agent_id)
             self.obs_lists[agent_id] = []
             self.action_lists[agent_id] = []
+            self.log_prob_lists[agent_id] = []
             self.reward_lists[agent_id] = []
             self.final_obs[agent_id] = None
             self.dones[agent_id] = False
             self.truncateds[agent_id] = False
-        self.log_probs_list = []
+        self.seen_agent_ids.update(agent_ids)
 
     def add_steps(self, timesteps: List[Timestep], log_probs: Tensor):
         steps_added = 0
-        for timestep in timesteps:
+        # Agents can join the env partway through an episode
+        if any(timestep.agent_id not in self.seen_agent_ids for timestep in timesteps):
+            self._add_agents([timestep.agent_id for timestep in timesteps])
+        # The log probs are parallel with the timesteps
+        for timestep, log_prob in zip(timesteps, log_probs):
             agent_id = timestep.agent_id
-            # We only want to process the timesteps of agent ids we included from this env when creating the EnvTrajectories instance
-            if agent_id not in self.used_agent_id_idx_map:
+            # We only want to process the timesteps of agent ids chosen from this env
+            if agent_id not in self.dones:
                 continue
             if not self.dones[agent_id]:
                 steps_added += 1
                 self.obs_lists[agent_id].append(timestep.obs)
                 self.action_lists[agent_id].append(timestep.action)
+                self.log_prob_lists[agent_id].append(log_prob)
                 self.reward_lists[agent_id].append(timestep.reward)
                 self.final_obs[agent_id] = timestep.next_obs
                 now_done = timestep.terminated or timestep.truncated
                 if now_done:
                     self.dones[agent_id] = True
                     self.truncateds[agent_id] = timestep.truncated
-        # We append all the log probs but we will deal with this later when getting trajectories
-        self.log_probs_list.append(log_probs)
         return steps_added
 
     def finalize(self):
         """
         Truncates any unfinished trajectories, marks all trajectories as done.
         """
-        for agent_id in self.used_agent_id_idx_map:
+        for agent_id in self.used_agent_ids:
             self.truncateds[agent_id] = (
                 self.truncateds[agent_id] or not self.dones[agent_id]
             )
@@ -72,16 +87,18 @@ class EnvTrajectories(Generic[AgentID, ObsType, ActionType, RewardType]):
         """
         :return: List of trajectories relevant to this env
         """
-        log_probs = torch.stack(self.log_probs_list)
         trajectories = []
-        for agent_id, idx in self.used_agent_id_idx_map.items():
+        for agent_id in self.used_agent_ids:
             obs_list = self.obs_lists[agent_id]
+            if not obs_list:
+                # The agent joined but never acted
+                continue
             trajectories.append(
                 Trajectory(
                     agent_id,
                     obs_list,
                     self.action_lists[agent_id],
-                    log_probs[: len(obs_list), idx],
+                    torch.stack(self.log_prob_lists[agent_id]),
                     self.reward_lists[agent_id],
                     None,
                     self.final_obs[agent_id],
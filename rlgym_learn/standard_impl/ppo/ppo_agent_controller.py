@@ -501,6 +501,13 @@ class PPOAgentController(
             else:
                 raise ValueError
 
+    def process_removed_envs(self, env_ids):
+        for env_id in env_ids:
+            if env_id in self.current_env_trajectories:
+                env_trajectories = self.current_env_trajectories.pop(env_id)
+                env_trajectories.finalize()
+                self.current_trajectories += env_trajectories.get_trajectories()
+
     def _learn(self):
         env_trajectories_list = list(self.current_env_trajectories.values())
         for env_trajectories in env_trajectories_list:
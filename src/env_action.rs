@@ -1,6 +1,6 @@
 use pyo3::{exceptions::asyncio::InvalidStateError, prelude::*, types::PyList, IntoPyObjectExt};
 
-use pyany_serde::PyAnySerde;
+use crate::shm_buffer::{append_checked, check_capacity, CheckedSerde};
 
 #[allow(non_camel_case_types)]
 #[pyclass]
@@ -74,17 +74,18 @@ pub fn append_env_action<'py>(
     buf: &mut [u8],
     offset: usize,
     env_action: &EnvAction,
-    action_serde: &Box<dyn PyAnySerde>,
-    state_serde_option: &Option<&Box<dyn PyAnySerde>>,
+    action_serde: &CheckedSerde,
+    state_serde_option: Option<&CheckedSerde>,
 ) -> PyResult<usize> {
     let mut offset = offset;
+    check_capacity(buf, offset, 1, "env action type")?;
     match env_action {
         EnvAction::STEP { action_list, .. } => {
             buf[offset] = 0;
             offset += 1;
             let action_list = action_list.bind(py);
             for action in action_list.iter() {
-                offset = action_serde.append(buf, offset, &action)?;
+                offset = append_checked(action_serde, buf, offset, &action, "action")?;
             }
         }
         EnvAction::RESET {} => {
@@ -94,13 +95,17 @@ pub fn append_env_action<'py>(
         EnvAction::SET_STATE { desired_state, .. } => {
             buf[offset] = 2;
             offset += 1;
-            offset = state_serde_option
-                .ok_or_else(|| {
+            offset = append_checked(
+                state_serde_option.ok_or_else(|| {
                     InvalidStateError::new_err(
                         "Received SET_STATE EnvAction but no state serde was provided",
                     )
-                })?
-                .append(buf, offset, desired_state.bind(py))?;
+                })?,
+                buf,
+                offset,
+                desired_state.bind(py),
+                "desired state",
+            )?;
         }
     }
     Ok(offset)
@@ -108,11 +113,11 @@ pub fn append_env_action<'py>(
 
 pub fn retrieve_env_action<'py>(
     py: Python<'py>,
-    buf: &mut [u8],
+    buf: &[u8],
     offset: usize,
     n_actions: usize,
-    action_serde: &Box<dyn PyAnySerde>,
-    state_serde_option: &Option<&Box<dyn PyAnySerde>>,
+    action_serde: &CheckedSerde,
+    state_serde_option: Option<&CheckedSerde>,
 ) -> PyResult<(EnvAction, usize)> {
     let env_action_type = buf[offset];
     let mut offset = offset + 1;
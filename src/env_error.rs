@@ -0,0 +1,107 @@
+use pyany_serde::communication::{append_bytes, retrieve_string};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyTracebackMethods;
+use std::mem::size_of;
+
+use crate::shm_buffer::append_bytes_checked;
+
+create_exception!(rlgym_learn, EnvProcessError, PyException);
+
+// Describes an exception raised inside an env process while making a call on the env
+#[derive(Clone, Debug)]
+pub struct EnvError {
+    pub env_call: String,
+    pub exception_type: String,
+    pub message: String,
+    pub traceback: String,
+}
+
+impl EnvError {
+    pub fn from_py_err<'py>(py: Python<'py>, env_call: &str, err: &PyErr) -> Self {
+        let exception_type = err
+            .get_type(py)
+            .name()
+            .map(|name| name.to_string())
+            .unwrap_or_else(|_| "<unknown>".to_string());
+        let message = err
+            .value(py)
+            .str()
+            .map(|message| message.to_string())
+            .unwrap_or_default();
+        let traceback = err
+            .traceback(py)
+            .and_then(|traceback| traceback.format().ok())
+            .unwrap_or_default();
+        EnvError {
+            env_call: env_call.to_string(),
+            exception_type,
+            message,
+            traceback,
+        }
+    }
+
+    pub fn into_py_err(self, proc_id: &str) -> PyErr {
+        EnvProcessError::new_err(format!(
+            "Env process with proc id {} raised {} during {}: {}\n{}",
+            proc_id, self.exception_type, self.env_call, self.message, self.traceback
+        ))
+    }
+}
+
+pub fn append_env_error(buf: &mut [u8], offset: usize, env_error: &EnvError) -> PyResult<usize> {
+    let mut offset = offset;
+    offset = append_bytes(buf, offset, env_error.env_call.as_bytes())?;
+    offset = append_bytes(buf, offset, env_error.exception_type.as_bytes())?;
+    offset = append_bytes(buf, offset, env_error.message.as_bytes())?;
+    // The traceback is the least important part of the message and can be arbitrarily long, so we keep only as much
+    // of its tail (the innermost frames) as fits in the remaining space
+    let available = buf.len().saturating_sub(offset + size_of::<usize>());
+    let traceback = env_error.traceback.as_bytes();
+    let mut start = traceback.len().saturating_sub(available);
+    while start < traceback.len() && !env_error.traceback.is_char_boundary(start) {
+        start += 1;
+    }
+    append_bytes(buf, offset, &traceback[start..])
+}
+
+// Like append_env_error, but keeps the whole traceback and returns a ShmBufferOverflowError instead of panicking if the
+// env error doesn't fit, for env errors which are followed by other data
+pub fn append_env_error_checked(
+    buf: &mut [u8],
+    offset: usize,
+    env_error: &EnvError,
+) -> PyResult<usize> {
+    let mut offset = offset;
+    for bytes in [
+        &env_error.env_call,
+        &env_error.exception_type,
+        &env_error.message,
+        &env_error.traceback,
+    ] {
+        offset = append_bytes_checked(buf, offset, bytes.as_bytes(), "env error")?;
+    }
+    Ok(offset)
+}
+
+pub fn retrieve_env_error(buf: &[u8], offset: usize) -> PyResult<(EnvError, usize)> {
+    let mut offset = offset;
+    let env_call;
+    (env_call, offset) = retrieve_string(buf, offset)?;
+    let exception_type;
+    (exception_type, offset) = retrieve_string(buf, offset)?;
+    let message;
+    (message, offset) = retrieve_string(buf, offset)?;
+    let traceback;
+    (traceback, offset) = retrieve_string(buf, offset)?;
+    Ok((
+        EnvError {
+            env_call,
+            exception_type,
+            message,
+            traceback,
+        },
+        offset,
+    ))
+}
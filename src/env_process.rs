@@ -1,33 +1,98 @@
-use pyany_serde::communication::{append_bool, append_usize};
-use pyany_serde::{DynPyAnySerdeOption, PyAnySerde};
+use itertools::izip;
+use pyany_serde::communication::{retrieve_bool, retrieve_bytes, retrieve_usize};
+use pyany_serde::pyany_serde_impl::PickleSerde;
+use pyany_serde::PyAnySerdeType;
 use pyo3::exceptions::asyncio::InvalidStateError;
-use pyo3::exceptions::PyValueError;
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyTuple};
+use pyo3::types::{PyBytes, PyDict, PyTuple};
 use pyo3::{intern, PyAny, PyObject, Python};
-use raw_sync::events::{Event, EventInit, EventState};
-use raw_sync::Timeout;
-use shared_memory::ShmemConf;
+use std::mem::size_of;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::env_action::{retrieve_env_action, EnvAction};
-use crate::synchronization::{get_flink, recvfrom_byte, retrieve_header, sendto_byte, Header};
+use crate::env_error::{append_env_error, append_env_error_checked, EnvError};
+use crate::env_process_config::{
+    required_tcp_token, EnvProcessConfig, SerdeTypeConfig, TimeoutConfig, TransportConfig,
+};
+use crate::flink_lock::FlinkLock;
+use crate::handshake::Handshake;
+use crate::notification::Notifier;
+use crate::shm_buffer::{
+    append_bool_checked, append_checked, append_space_dict, append_sub_env_entry,
+    append_usize_checked, overflow_err, retrieve_sub_env_entry, serialized_size, CheckedSerde,
+};
+use crate::synchronization::{append_header, recvfrom_bytes_timeout, sendto_bytes, Header};
+use crate::telemetry::{append_env_call_timing_list, EnvCall};
+use crate::transport::{take_channel_end, transport_name, ShmTransport, TcpTransport, Transport};
 
-fn sync_with_epi<'py>(py: Python<'py>, socket: &PyObject, address: &PyObject) -> PyResult<()> {
-    sendto_byte(py, socket, address)?;
-    recvfrom_byte(py, socket)?;
-    Ok(())
+// Errors which end the env process. Both kinds are reported back to the EnvProcessInterface before exiting,
+// but failures of calls made on the env (or other user-provided functions) are attributed to that call.
+enum EnvProcessFailure {
+    EnvCall(&'static str, PyErr),
+    Internal(PyErr),
+}
+
+impl From<PyErr> for EnvProcessFailure {
+    fn from(err: PyErr) -> Self {
+        EnvProcessFailure::Internal(err)
+    }
 }
 
-fn env_reset<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
+fn env_call_failure(env_call: &'static str) -> impl FnOnce(PyErr) -> EnvProcessFailure {
+    move |err| EnvProcessFailure::EnvCall(env_call, err)
+}
+
+// Exchanges handshakes with the EnvProcessInterface, returning the one it sent
+fn sync_with_epi<'py>(
+    py: Python<'py>,
+    socket: &PyObject,
+    address: &PyObject,
+    handshake: &Handshake,
+    timeout_option: Option<Duration>,
+) -> PyResult<Handshake> {
+    sendto_bytes(py, socket, address, &handshake.to_bytes())?;
+    let Some(epi_handshake_bytes) = recvfrom_bytes_timeout(py, socket, timeout_option)? else {
+        return Err(PyTimeoutError::new_err(format!(
+            "EnvProcessInterface did not respond within {:?} during startup sync",
+            timeout_option.unwrap()
+        )));
+    };
+    Handshake::from_bytes(&epi_handshake_bytes)
+}
+
+// Exchanges handshakes with the EnvProcessInterface as the first messages over the TCP connection, returning the one
+// it sent
+fn sync_with_epi_over_tcp<'py>(
+    py: Python<'py>,
+    transport: &mut dyn Transport,
+    handshake: &Handshake,
+    timeout_option: Option<Duration>,
+) -> PyResult<Handshake> {
+    handshake.send_over(py, transport)?;
+    if !transport.recv_timeout(py, timeout_option)? {
+        if transport.is_closed() {
+            return Err(InvalidStateError::new_err(
+                "EnvProcessInterface closed the connection during startup sync",
+            ));
+        }
+        return Err(PyTimeoutError::new_err(format!(
+            "EnvProcessInterface did not respond within {:?} during startup sync",
+            timeout_option.unwrap()
+        )));
+    }
+    Handshake::from_bytes(transport.slice())
+}
+
+fn env_reset<'py>(env: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
     Ok(env
         .call_method0(intern!(env.py(), "reset"))?
         .downcast_into()?)
 }
 
 fn env_set_state<'py>(
-    env: &'py Bound<'py, PyAny>,
+    env: &Bound<'py, PyAny>,
     desired_state: &Bound<'py, PyAny>,
 ) -> PyResult<Bound<'py, PyDict>> {
     Ok(env
@@ -35,40 +100,117 @@ fn env_set_state<'py>(
         .downcast_into()?)
 }
 
-fn env_render<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<()> {
+fn env_render<'py>(env: &Bound<'py, PyAny>) -> PyResult<()> {
     env.call_method0(intern!(env.py(), "render"))?;
     Ok(())
 }
 
-fn env_state<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+fn env_state<'py>(env: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
     env.getattr(intern!(env.py(), "state"))
 }
 
-fn env_shared_info<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+fn env_shared_info<'py>(env: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
     env.getattr(intern!(env.py(), "shared_info"))
 }
 
-fn env_obs_spaces<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
+fn env_obs_spaces<'py>(env: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
     Ok(env
         .getattr(intern!(env.py(), "observation_spaces"))?
         .downcast_into()?)
 }
 
-fn env_action_spaces<'py>(env: &'py Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
+fn env_action_spaces<'py>(env: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
     Ok(env
         .getattr(intern!(env.py(), "action_spaces"))?
         .downcast_into()?)
 }
 
-fn env_step<'py>(
-    env: &'py Bound<'py, PyAny>,
-    actions_dict: Bound<'py, PyDict>,
-) -> PyResult<(
+fn env_close<'py>(env: &Bound<'py, PyAny>) -> PyResult<()> {
+    env.call_method0(intern!(env.py(), "close"))?;
+    Ok(())
+}
+
+// Returns the obs and action spaces of every agent, by agent id
+fn env_spaces<'py>(
+    env: &Bound<'py, PyAny>,
+) -> Result<(Bound<'py, PyDict>, Bound<'py, PyDict>), EnvProcessFailure> {
+    let obs_spaces = env_obs_spaces(env).map_err(env_call_failure("env.observation_spaces"))?;
+    let action_spaces = env_action_spaces(env).map_err(env_call_failure("env.action_spaces"))?;
+    Ok((obs_spaces, action_spaces))
+}
+
+// Whether the agent ids are the same, in the same order
+fn same_agent_id_list(
+    agent_id_list: &[Bound<'_, PyAny>],
+    new_agent_id_list: &[Bound<'_, PyAny>],
+) -> PyResult<bool> {
+    if agent_id_list.len() != new_agent_id_list.len() {
+        return Ok(false);
+    }
+    for (agent_id, new_agent_id) in agent_id_list.iter().zip(new_agent_id_list.iter()) {
+        if !agent_id.is(new_agent_id) && !agent_id.eq(new_agent_id)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn agent_id_position(
+    agent_id_list: &[Bound<'_, PyAny>],
+    agent_id: &Bound<'_, PyAny>,
+) -> PyResult<Option<usize>> {
+    for (idx, other_agent_id) in agent_id_list.iter().enumerate() {
+        if other_agent_id.is(agent_id) || other_agent_id.eq(agent_id)? {
+            return Ok(Some(idx));
+        }
+    }
+    Ok(None)
+}
+
+// Returns the reward, terminated and truncated of an agent which acted in a step. An agent which left the env during
+// the step only needs a reward, and has its trajectory truncated unless it terminated.
+fn agent_step_result<'py>(
+    rew_dict: &Bound<'py, PyDict>,
+    terminated_dict: &Bound<'py, PyDict>,
+    truncated_dict: &Bound<'py, PyDict>,
+    agent_id: &Bound<'py, PyAny>,
+    departed: bool,
+) -> PyResult<(Bound<'py, PyAny>, bool, bool)> {
+    let reward = rew_dict
+        .get_item(agent_id)?
+        .ok_or(InvalidStateError::new_err(
+            "Step reward python dict did not contain AgentID as key",
+        ))?;
+    let terminated_option = terminated_dict
+        .get_item(agent_id)?
+        .map(|terminated| terminated.extract::<bool>())
+        .transpose()?;
+    let truncated_option = truncated_dict
+        .get_item(agent_id)?
+        .map(|truncated| truncated.extract::<bool>())
+        .transpose()?;
+    if !departed && (terminated_option.is_none() || truncated_option.is_none()) {
+        return Err(InvalidStateError::new_err(
+            "Step terminated or truncated python dict did not contain AgentID as key",
+        ));
+    }
+    let terminated = terminated_option.unwrap_or(false);
+    let truncated = truncated_option.unwrap_or(false) || (departed && !terminated);
+    Ok((reward, terminated, truncated))
+}
+
+// (obs dict, reward dict, terminated dict, truncated dict) returned by env.step
+type EnvStepResult<'py> = (
     Bound<'py, PyDict>,
     Bound<'py, PyDict>,
     Bound<'py, PyDict>,
     Bound<'py, PyDict>,
-)> {
+);
+
+fn env_step<'py>(
+    env: &Bound<'py, PyAny>,
+    actions_dict: Bound<'py, PyDict>,
+) -> PyResult<EnvStepResult<'py>> {
     let result: Bound<'py, PyTuple> = env
         .call_method1(intern!(env.py(), "step"), (actions_dict,))?
         .downcast_into()?;
@@ -81,302 +223,900 @@ fn env_step<'py>(
 }
 
 #[pyfunction]
-#[pyo3(signature=(proc_id,
-    child_end,
-    parent_sockname,
+#[pyo3(signature=(env_process_config,
+    startup_sync_option,
     build_env_fn,
-    flinks_folder,
-    shm_buffer_size,
-    agent_id_serde,
-    action_serde,
-    obs_serde,
-    reward_serde,
-    obs_space_serde,
-    action_space_serde,
-    state_serde_option,
-    state_metrics_serde_option,
+    serde_type_config,
     collect_state_metrics_fn_option,
-    send_state_to_agent_controllers=false,
-    render=false,
-    render_delay_option=None,
-    recalculate_agent_id_every_step=false))]
+    timeout_config,
+    transport_config))]
 pub fn env_process(
-    proc_id: &str,
-    child_end: PyObject,
-    parent_sockname: PyObject,
+    env_process_config: EnvProcessConfig,
+    startup_sync_option: Option<(PyObject, PyObject)>,
     build_env_fn: PyObject,
-    flinks_folder: &str,
-    shm_buffer_size: usize,
-    agent_id_serde: Box<dyn PyAnySerde>,
-    action_serde: Box<dyn PyAnySerde>,
-    obs_serde: Box<dyn PyAnySerde>,
-    reward_serde: Box<dyn PyAnySerde>,
-    obs_space_serde: Box<dyn PyAnySerde>,
-    action_space_serde: Box<dyn PyAnySerde>,
-    state_serde_option: DynPyAnySerdeOption,
-    state_metrics_serde_option: DynPyAnySerdeOption,
+    serde_type_config: SerdeTypeConfig,
     collect_state_metrics_fn_option: Option<PyObject>,
-    send_state_to_agent_controllers: bool,
-    render: bool,
-    render_delay_option: Option<Duration>,
-    recalculate_agent_id_every_step: bool,
+    timeout_config: TimeoutConfig,
+    transport_config: TransportConfig,
 ) -> PyResult<()> {
-    if send_state_to_agent_controllers && matches!(state_serde_option, DynPyAnySerdeOption::None) {
+    let EnvProcessConfig {
+        proc_id,
+        flinks_folder,
+        shm_buffer_size,
+        send_state_to_agent_controllers,
+        render,
+        render_delay_option,
+        recalculate_agent_id_every_step,
+        n_envs,
+    } = env_process_config;
+    let proc_id = proc_id.as_str();
+    let flinks_folder = flinks_folder.as_str();
+    let SerdeTypeConfig {
+        agent_id_serde,
+        action_serde,
+        obs_serde,
+        reward_serde,
+        obs_space_serde,
+        action_space_serde,
+        state_serde_option,
+        state_metrics_serde_option,
+    } = serde_type_config;
+    let startup_timeout_option = timeout_config.startup;
+    let TransportConfig {
+        tcp_address: tcp_address_option,
+        tcp_token: tcp_token_option,
+        in_process,
+        native_signalling,
+        validate_shm_buffer_size,
+        ..
+    } = transport_config;
+    let transport_name = transport_name(tcp_address_option.as_deref(), in_process)?;
+    // Over TCP, the startup sync happens on the connection itself, so env processes don't need to be on the same host
+    let sync_socket_option = match (transport_name, startup_sync_option) {
+        ("tcp", _) => None,
+        (_, Some(startup_sync)) => Some(startup_sync),
+        _ => {
+            return Err(PyValueError::new_err(
+                "child_end and parent_sockname must be passed unless connecting over TCP",
+            ))
+        }
+    };
+    let handshake = Handshake::new(
+        vec![
+            ("agent_id_serde", agent_id_serde.fingerprint),
+            ("action_serde", action_serde.fingerprint),
+            ("obs_serde", obs_serde.fingerprint),
+            ("reward_serde", reward_serde.fingerprint),
+            ("obs_space_serde", obs_space_serde.fingerprint),
+            ("action_space_serde", action_space_serde.fingerprint),
+            ("state_serde", state_serde_option.fingerprint),
+            (
+                "state_metrics_serde",
+                state_metrics_serde_option.fingerprint,
+            ),
+        ],
+        vec![
+            ("n_envs_per_process", n_envs.to_string()),
+            ("transport", transport_name.to_string()),
+        ],
+    );
+    if n_envs == 0 {
+        return Err(PyValueError::new_err(
+            "An env process must host at least one env",
+        ));
+    }
+    let agent_id_serde = CheckedSerde::from(agent_id_serde);
+    let action_serde = CheckedSerde::from(action_serde);
+    let obs_serde = CheckedSerde::from(obs_serde);
+    let reward_serde = CheckedSerde::from(reward_serde);
+    let obs_space_serde = CheckedSerde::from(obs_space_serde);
+    let action_space_serde = CheckedSerde::from(action_space_serde);
+    let state_serde_option = Option::<CheckedSerde>::from(state_serde_option);
+    let state_metrics_serde_option = Option::<CheckedSerde>::from(state_metrics_serde_option);
+    if send_state_to_agent_controllers && state_serde_option.is_none() {
         return Err(PyValueError::new_err(
             "state_serde must be passed in order to send state to agent controllers",
         ));
     }
-    if collect_state_metrics_fn_option.is_some()
-        && matches!(state_metrics_serde_option, DynPyAnySerdeOption::None)
-    {
+    if collect_state_metrics_fn_option.is_some() && state_metrics_serde_option.is_none() {
         return Err(PyValueError::new_err(
                     "state_metrics_serde must be passed in order to collect state metrics from env processes",
                 ));
     }
-    let state_serde_option: Option<Box<dyn PyAnySerde>> = state_serde_option.into();
     let state_serde_option = state_serde_option.as_ref();
-    let state_metrics_serde_option: Option<Box<dyn PyAnySerde>> = state_metrics_serde_option.into();
     let state_metrics_serde_option = state_metrics_serde_option.as_ref();
-    let flink = get_flink(flinks_folder, proc_id);
-    let mut shmem = ShmemConf::new()
-        .size(shm_buffer_size)
-        .flink(flink.clone())
-        .create()
-        .map_err(|err| {
-            InvalidStateError::new_err(format!("Unable to create shmem flink {}: {}", flink, err))
-        })?;
-    let (epi_evt, used_bytes) = unsafe {
-        Event::new(shmem.as_ptr(), true).map_err(|err| {
-            InvalidStateError::new_err(format!(
-                "Failed to create event from epi to this process: {}",
-                err.to_string()
-            ))
-        })?
-    };
-    let shm_slice = unsafe { &mut shmem.as_slice_mut()[used_bytes..] };
 
     Python::with_gil::<_, PyResult<()>>(|py| {
-        // Initial setup
-        let env = build_env_fn.call0(py)?.into_bound(py);
-        let mut game_speed_fn: Box<dyn Fn() -> PyResult<f64>> = Box::new(|| Ok(1.0));
-        let mut game_paused_fn: Box<dyn Fn() -> PyResult<bool>> = Box::new(|| Ok(false));
-        if render {
-            let rlviser = PyModule::import(py, "rlviser_py")?;
-            let get_game_speed = rlviser.getattr("get_game_speed")?;
-            let get_game_paused = rlviser.getattr("get_game_paused")?;
-            game_speed_fn = Box::new(move || Ok(get_game_speed.call0()?.extract::<f64>()?));
-            game_paused_fn = Box::new(move || Ok(get_game_paused.call0()?.extract::<bool>()?));
-        }
-
-        let collect_state_metrics_fn_option = collect_state_metrics_fn_option.as_ref();
-
-        // Startup complete
-        sync_with_epi(py, &child_end, &parent_sockname)?;
+        let new_notifier = || {
+            let (child_end, parent_sockname) = sync_socket_option.as_ref().unwrap();
+            Notifier::new(
+                native_signalling,
+                child_end.clone_ref(py),
+                parent_sockname.clone_ref(py),
+                flinks_folder,
+                proc_id,
+            )
+        };
+        // Held until this process exits, so the EnvProcessInterface can tell that the flinks of this process aren't
+        // orphaned
+        let _flink_lock_option = match transport_name {
+            "shm" => Some(FlinkLock::acquire(flinks_folder, proc_id)?),
+            _ => None,
+        };
+        let mut transport_option: Option<Box<dyn Transport>> = match transport_name {
+            "shm" => Some(Box::new(ShmTransport::create(
+                flinks_folder,
+                proc_id,
+                shm_buffer_size,
+                new_notifier()?,
+            )?)),
+            _ => None,
+        };
+        // The channel of an env thread is only guaranteed to exist once the EnvProcessInterface has added it, which
+        // happens before the startup sync completes. The TCP transport connects to carry the startup sync.
+        let connect = || -> PyResult<Box<dyn Transport>> {
+            if let Some(tcp_address) = tcp_address_option.as_deref() {
+                return Ok(Box::new(TcpTransport::connect(
+                    py,
+                    tcp_address,
+                    required_tcp_token(tcp_token_option.as_deref())?,
+                    proc_id,
+                    shm_buffer_size,
+                )?));
+            }
+            let mut channel_end = take_channel_end(proc_id)?;
+            channel_end.attach_notifier(new_notifier()?);
+            Ok(Box::new(channel_end))
+        };
+        let sync = |transport_option: &mut Option<Box<dyn Transport>>| -> PyResult<Handshake> {
+            match sync_socket_option.as_ref() {
+                Some((child_end, parent_sockname)) => sync_with_epi(
+                    py,
+                    child_end,
+                    parent_sockname,
+                    &handshake,
+                    startup_timeout_option,
+                ),
+                None => sync_with_epi_over_tcp(
+                    py,
+                    transport_option.insert(connect()?).as_mut(),
+                    &handshake,
+                    startup_timeout_option,
+                ),
+            }
+        };
+        let mut synced_with_epi = false;
+        let result = (|| -> Result<(), EnvProcessFailure> {
+            // Initial setup
+            let mut env_list = Vec::with_capacity(n_envs);
+            for _ in 0..n_envs {
+                env_list.push(
+                    build_env_fn
+                        .call0(py)
+                        .map_err(env_call_failure("build_env_fn"))?
+                        .into_bound(py),
+                );
+            }
+            let mut game_speed_fn: Box<dyn Fn() -> PyResult<f64>> = Box::new(|| Ok(1.0));
+            let mut game_paused_fn: Box<dyn Fn() -> PyResult<bool>> = Box::new(|| Ok(false));
+            if render {
+                let rlviser = PyModule::import(py, "rlviser_py")?;
+                let get_game_speed = rlviser.getattr("get_game_speed")?;
+                let get_game_paused = rlviser.getattr("get_game_paused")?;
+                game_speed_fn = Box::new(move || get_game_speed.call0()?.extract::<f64>());
+                game_paused_fn = Box::new(move || get_game_paused.call0()?.extract::<bool>());
+            }
 
-        let reset_obs = env_reset(&env)?;
-        let should_collect_state_metrics = !collect_state_metrics_fn_option.is_none();
-        let mut n_agents = reset_obs.len();
-        let mut agent_id_list = Vec::with_capacity(n_agents);
-        for agent_id in reset_obs.keys().iter() {
-            agent_id_list.push(agent_id);
-        }
+            let collect_state_metrics_fn_option = collect_state_metrics_fn_option.as_ref();
 
-        // Write reset message
-        let mut offset = 0;
-        offset = append_usize(shm_slice, offset, n_agents);
-        for agent_id in agent_id_list.iter() {
-            offset = agent_id_serde.append(shm_slice, offset, agent_id)?;
-            offset = obs_serde.append(
-                shm_slice,
-                offset,
-                &reset_obs
-                    .get_item(agent_id)?
-                    .ok_or(InvalidStateError::new_err(
-                        "Reset obs python dict did not contain AgentID as key",
-                    ))?,
+            // Startup complete
+            let epi_handshake = sync(&mut transport_option)?;
+            synced_with_epi = true;
+            if transport_option.is_none() {
+                transport_option = Some(connect()?);
+            }
+            let transport = transport_option.as_mut().unwrap();
+            handshake.check(
+                &epi_handshake,
+                "This env process",
+                "the EnvProcessInterface",
             )?;
-        }
 
-        if send_state_to_agent_controllers {
-            _ = state_serde_option
-                .unwrap()
-                .append(shm_slice, offset, &env_state(&env)?)?;
-        }
-        sendto_byte(py, &child_end, &parent_sockname)?;
-
-        // Start main loop
-        let mut has_received_env_action = false;
-        loop {
-            epi_evt
-                .wait(Timeout::Infinite)
-                .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-            epi_evt
-                .set(EventState::Clear)
-                .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-            offset = 0;
-            let header;
-            (header, offset) = retrieve_header(shm_slice, offset)?;
-            match header {
-                Header::EnvAction => {
-                    has_received_env_action = true;
-                    let env_action;
-                    (env_action, _) = retrieve_env_action(
-                        py,
-                        shm_slice,
-                        offset,
-                        agent_id_list.len(),
-                        &action_serde,
-                        &state_serde_option,
-                    )?;
-                    // Read actions message
-                    let (
-                        obs_dict,
-                        rew_dict_option,
-                        terminated_dict_option,
-                        truncated_dict_option,
-                        is_step_action,
-                    );
-                    match &env_action {
-                        EnvAction::STEP { action_list, .. } => {
-                            let mut actions_kv_list = Vec::with_capacity(agent_id_list.len());
-                            let action_list = action_list.bind(py);
-                            for (agent_id, action) in agent_id_list.iter().zip(action_list.iter()) {
-                                actions_kv_list.push((agent_id, action));
-                            }
-                            let actions_dict =
-                                PyDict::from_sequence(&actions_kv_list.into_pyobject(py)?)?;
-                            let (rew_dict, terminated_dict, truncated_dict);
-                            (obs_dict, rew_dict, terminated_dict, truncated_dict) =
-                                env_step(&env, actions_dict)?;
-                            rew_dict_option = Some(rew_dict);
-                            terminated_dict_option = Some(terminated_dict);
-                            truncated_dict_option = Some(truncated_dict);
-                            is_step_action = true;
-                        }
-                        EnvAction::RESET {} => {
-                            obs_dict = env_reset(&env)?;
-                            rew_dict_option = None;
-                            terminated_dict_option = None;
-                            truncated_dict_option = None;
-                            is_step_action = false;
-                        }
-                        EnvAction::SET_STATE { desired_state, .. } => {
-                            obs_dict = env_set_state(&env, desired_state.bind(py))?;
-                            rew_dict_option = None;
-                            terminated_dict_option = None;
-                            truncated_dict_option = None;
-                            is_step_action = false;
-                        }
-                    }
-                    let new_episode = !is_step_action;
+            let mut reset_obs_list = Vec::with_capacity(n_envs);
+            for env in env_list.iter() {
+                reset_obs_list.push(env_reset(env).map_err(env_call_failure("env.reset"))?);
+            }
+            let should_collect_state_metrics = collect_state_metrics_fn_option.is_some();
+            // Updated whenever the agents of an env change, which can happen at any step
+            let mut agent_id_list_list = reset_obs_list
+                .iter()
+                .map(|reset_obs| reset_obs.keys().iter().collect::<Vec<_>>())
+                .collect::<Vec<_>>();
 
-                    if new_episode {
-                        n_agents = obs_dict.len();
+            if validate_shm_buffer_size {
+                // This covers both the reset message and a step message, except for rewards and state metrics, which
+                // aren't available until the first step
+                let mut required = size_of::<u8>() + size_of::<usize>();
+                for (env, reset_obs) in env_list.iter().zip(reset_obs_list.iter()) {
+                    required += 3 * size_of::<usize>();
+                    for (agent_id, obs) in reset_obs.iter() {
+                        required += serialized_size(&agent_id_serde, &agent_id)?
+                            + serialized_size(&obs_serde, &obs)?
+                            + 2 * size_of::<u8>();
                     }
-
-                    // Write env step message
-                    offset = 0;
-                    if new_episode {
-                        offset = append_usize(shm_slice, offset, n_agents);
+                    if send_state_to_agent_controllers {
+                        required += serialized_size(
+                            state_serde_option.unwrap(),
+                            &env_state(env).map_err(env_call_failure("env.state"))?,
+                        )?;
                     }
-                    for agent_id in agent_id_list.iter() {
-                        if recalculate_agent_id_every_step || new_episode {
-                            offset = agent_id_serde.append(shm_slice, offset, agent_id)?;
+                }
+                let available = transport.slice_mut().len();
+                if required > available {
+                    return Err(overflow_err("first reset", required, available).into());
+                }
+            }
+
+            // Write reset message
+            let mut reset_data_list = Vec::with_capacity(n_envs);
+            for (env, reset_obs, agent_id_list) in izip!(
+                env_list.iter(),
+                reset_obs_list.iter(),
+                agent_id_list_list.iter()
+            ) {
+                let mut obs_list = Vec::with_capacity(agent_id_list.len());
+                for agent_id in agent_id_list.iter() {
+                    obs_list.push(reset_obs.get_item(agent_id)?.ok_or(
+                        InvalidStateError::new_err(
+                            "Reset obs python dict did not contain AgentID as key",
+                        ),
+                    )?);
+                }
+                let state_option = if send_state_to_agent_controllers {
+                    Some(env_state(env).map_err(env_call_failure("env.state"))?)
+                } else {
+                    None
+                };
+                reset_data_list.push((obs_list, state_option));
+            }
+            transport.write_message(py, &mut |buf| {
+                let mut offset = append_header(buf, 0, Header::EnvAction);
+                offset = append_usize_checked(buf, offset, n_envs, "sub-env count")?;
+                for (sub_env_idx, (agent_id_list, (obs_list, state_option))) in agent_id_list_list
+                    .iter()
+                    .zip(reset_data_list.iter())
+                    .enumerate()
+                {
+                    offset = append_sub_env_entry(buf, offset, sub_env_idx, |buf, offset| {
+                        let mut offset =
+                            append_usize_checked(buf, offset, agent_id_list.len(), "agent count")?;
+                        for (agent_id, obs) in agent_id_list.iter().zip(obs_list.iter()) {
+                            offset =
+                                append_checked(&agent_id_serde, buf, offset, agent_id, "agent id")?;
+                            offset = append_checked(&obs_serde, buf, offset, obs, "obs")?;
                         }
-                        offset = obs_serde.append(
-                            shm_slice,
-                            offset,
-                            &obs_dict.get_item(agent_id)?.unwrap(),
-                        )?;
-                        if is_step_action {
-                            offset = reward_serde.append(
-                                shm_slice,
+                        if let Some(state) = state_option {
+                            offset = append_checked(
+                                state_serde_option.unwrap(),
+                                buf,
                                 offset,
-                                &rew_dict_option
-                                    .as_ref()
-                                    .unwrap()
-                                    .get_item(agent_id)?
-                                    .unwrap(),
+                                state,
+                                "state",
                             )?;
-                            offset = append_bool(
+                        }
+                        Ok(offset)
+                    })?;
+                }
+                Ok(offset)
+            })?;
+            transport.send(py)?;
+
+            // Start main loop
+            // Whether each env has been reset (or had its state set) without being stepped since. Spaces can only be
+            // requested at these episode boundaries, since the agents may change from one episode to the next.
+            let mut at_episode_boundary_list = vec![true; n_envs];
+            // Rendering happens after the response has been sent, so a failure while rendering is held until
+            // the next request to avoid overwriting a response the EnvProcessInterface may still be reading
+            let mut render_failure_option = None;
+            let mut env_call_timing_list = Vec::new();
+            // Envs built by a replacement env build function, which haven't replaced the current env yet
+            let mut pending_env_option_list: Vec<Option<Bound<'_, PyAny>>> = vec![None; n_envs];
+            // Envs built by a replacement env build function whose spaces the EnvProcessInterface is still checking
+            let mut rebuilt_env_list_option: Option<Vec<Bound<'_, PyAny>>> = None;
+            // Env command payloads and replies can be anything pickleable
+            let env_command_serde = CheckedSerde::new(
+                Box::new(PickleSerde::new()?),
+                Some(PyAnySerdeType::PICKLE {}),
+            );
+            loop {
+                if !transport.recv_timeout(py, None)? {
+                    // The EnvProcessInterface closed the connection
+                    break;
+                }
+                if let Some(render_failure) = render_failure_option.take() {
+                    return Err(render_failure);
+                }
+                let (header, offset) = transport.retrieve_message_header()?;
+                match header {
+                    Header::EnvAction => {
+                        // Read env actions message
+                        let shm_slice = transport.slice();
+                        let (n_entries, mut offset) = retrieve_usize(shm_slice, offset)?;
+                        let mut sub_env_env_action_list = Vec::with_capacity(n_entries);
+                        for _ in 0..n_entries {
+                            let (sub_env_idx, entry_offset, end_offset) =
+                                retrieve_sub_env_entry(shm_slice, offset)?;
+                            let agent_id_list =
+                                agent_id_list_list.get(sub_env_idx).ok_or_else(|| {
+                                    InvalidStateError::new_err(format!(
+                                        "Received env action for sub-env {} but this env process only hosts {} envs",
+                                        sub_env_idx, n_envs
+                                    ))
+                                })?;
+                            let (env_action, _) = retrieve_env_action(
+                                py,
                                 shm_slice,
-                                offset,
-                                terminated_dict_option
-                                    .as_ref()
-                                    .unwrap()
-                                    .get_item(agent_id)?
-                                    .unwrap()
-                                    .extract::<bool>()?,
+                                entry_offset,
+                                agent_id_list.len(),
+                                &action_serde,
+                                state_serde_option,
+                            )?;
+                            sub_env_env_action_list.push((sub_env_idx, env_action));
+                            offset = end_offset;
+                        }
+
+                        // Run env actions
+                        let mut response_list = Vec::with_capacity(n_entries);
+                        for (sub_env_idx, env_action) in sub_env_env_action_list.into_iter() {
+                            // Both start a new episode, so the pending env can take over
+                            if let EnvAction::RESET {} | EnvAction::SET_STATE { .. } = env_action {
+                                if let Some(pending_env) =
+                                    pending_env_option_list[sub_env_idx].take()
+                                {
+                                    env_close(&env_list[sub_env_idx])
+                                        .map_err(env_call_failure("env.close"))?;
+                                    env_list[sub_env_idx] = pending_env;
+                                }
+                            }
+                            let env = &env_list[sub_env_idx];
+                            let agent_id_list = &agent_id_list_list[sub_env_idx];
+                            let (
+                                obs_dict,
+                                rew_dict_option,
+                                terminated_dict_option,
+                                truncated_dict_option,
+                                is_step_action,
                             );
-                            offset = append_bool(
-                                shm_slice,
+                            match &env_action {
+                                EnvAction::STEP { action_list, .. } => {
+                                    let mut actions_kv_list =
+                                        Vec::with_capacity(agent_id_list.len());
+                                    let action_list = action_list.bind(py);
+                                    for (agent_id, action) in
+                                        agent_id_list.iter().zip(action_list.iter())
+                                    {
+                                        actions_kv_list.push((agent_id, action));
+                                    }
+                                    let actions_dict =
+                                        PyDict::from_sequence(&actions_kv_list.into_pyobject(py)?)?;
+                                    let (rew_dict, terminated_dict, truncated_dict);
+                                    let step_start = Instant::now();
+                                    (obs_dict, rew_dict, terminated_dict, truncated_dict) =
+                                        env_step(env, actions_dict)
+                                            .map_err(env_call_failure("env.step"))?;
+                                    env_call_timing_list
+                                        .push((EnvCall::Step, step_start.elapsed()));
+                                    rew_dict_option = Some(rew_dict);
+                                    terminated_dict_option = Some(terminated_dict);
+                                    truncated_dict_option = Some(truncated_dict);
+                                    is_step_action = true;
+                                }
+                                EnvAction::RESET {} => {
+                                    let reset_start = Instant::now();
+                                    obs_dict =
+                                        env_reset(env).map_err(env_call_failure("env.reset"))?;
+                                    env_call_timing_list
+                                        .push((EnvCall::Reset, reset_start.elapsed()));
+                                    rew_dict_option = None;
+                                    terminated_dict_option = None;
+                                    truncated_dict_option = None;
+                                    is_step_action = false;
+                                }
+                                EnvAction::SET_STATE { desired_state, .. } => {
+                                    let set_state_start = Instant::now();
+                                    obs_dict = env_set_state(env, desired_state.bind(py))
+                                        .map_err(env_call_failure("env.set_state"))?;
+                                    env_call_timing_list
+                                        .push((EnvCall::SetState, set_state_start.elapsed()));
+                                    rew_dict_option = None;
+                                    terminated_dict_option = None;
+                                    truncated_dict_option = None;
+                                    is_step_action = false;
+                                }
+                            }
+                            let new_episode = !is_step_action;
+                            at_episode_boundary_list[sub_env_idx] = new_episode;
+
+                            let new_agent_id_list = obs_dict.keys().iter().collect::<Vec<_>>();
+                            let agents_changed = is_step_action
+                                && !same_agent_id_list(agent_id_list, &new_agent_id_list)?;
+                            let mut agent_data_list = Vec::with_capacity(new_agent_id_list.len());
+                            for agent_id in new_agent_id_list.iter() {
+                                let obs = obs_dict.get_item(agent_id)?.unwrap();
+                                let reward_data_option = if is_step_action && !agents_changed {
+                                    Some(agent_step_result(
+                                        rew_dict_option.as_ref().unwrap(),
+                                        terminated_dict_option.as_ref().unwrap(),
+                                        truncated_dict_option.as_ref().unwrap(),
+                                        agent_id,
+                                        false,
+                                    )?)
+                                } else {
+                                    None
+                                };
+                                agent_data_list.push((obs, reward_data_option));
+                            }
+                            // When the agents changed during a step, the step results are sent for the agents which
+                            // acted, along with their index in the new agent id list (None if they left the env)
+                            let prev_agent_data_list_option = if agents_changed {
+                                let mut prev_agent_data_list =
+                                    Vec::with_capacity(agent_id_list.len());
+                                for agent_id in agent_id_list.iter() {
+                                    let new_agent_idx_option =
+                                        agent_id_position(&new_agent_id_list, agent_id)?;
+                                    let step_result = agent_step_result(
+                                        rew_dict_option.as_ref().unwrap(),
+                                        terminated_dict_option.as_ref().unwrap(),
+                                        truncated_dict_option.as_ref().unwrap(),
+                                        agent_id,
+                                        new_agent_idx_option.is_none(),
+                                    )?;
+                                    prev_agent_data_list.push((new_agent_idx_option, step_result));
+                                }
+                                Some(prev_agent_data_list)
+                            } else {
+                                None
+                            };
+                            agent_id_list_list[sub_env_idx] = new_agent_id_list;
+                            let state_option = if send_state_to_agent_controllers {
+                                Some(env_state(env).map_err(env_call_failure("env.state"))?)
+                            } else {
+                                None
+                            };
+                            let state_metrics_option = if should_collect_state_metrics {
+                                Some(
+                                    collect_state_metrics_fn_option
+                                        .unwrap()
+                                        .call1(
+                                            py,
+                                            (
+                                                env_state(env)
+                                                    .map_err(env_call_failure("env.state"))?,
+                                                env_shared_info(env)
+                                                    .map_err(env_call_failure("env.shared_info"))?,
+                                            ),
+                                        )
+                                        .map_err(env_call_failure("collect_state_metrics_fn"))?
+                                        .into_bound(py),
+                                )
+                            } else {
+                                None
+                            };
+                            response_list.push((
+                                sub_env_idx,
+                                new_episode,
+                                agent_data_list,
+                                prev_agent_data_list_option,
+                                state_option,
+                                state_metrics_option,
+                            ));
+                        }
+
+                        // Write env step message
+                        transport.write_message(py, &mut |buf| {
+                            let mut offset = append_header(buf, 0, Header::EnvAction);
+                            offset = append_usize_checked(
+                                buf,
                                 offset,
-                                truncated_dict_option
-                                    .as_ref()
-                                    .unwrap()
-                                    .get_item(agent_id)?
-                                    .unwrap()
-                                    .extract::<bool>()?,
-                            );
+                                response_list.len(),
+                                "sub-env count",
+                            )?;
+                            for (
+                                sub_env_idx,
+                                new_episode,
+                                agent_data_list,
+                                prev_agent_data_list_option,
+                                state_option,
+                                state_metrics_option,
+                            ) in response_list.iter()
+                            {
+                                let agent_id_list = &agent_id_list_list[*sub_env_idx];
+                                offset = append_sub_env_entry(
+                                    buf,
+                                    offset,
+                                    *sub_env_idx,
+                                    |buf, mut offset| {
+                                        if !*new_episode {
+                                            offset = append_bool_checked(
+                                                buf,
+                                                offset,
+                                                prev_agent_data_list_option.is_some(),
+                                                "agents changed",
+                                            )?;
+                                        }
+                                        let send_agent_ids =
+                                            *new_episode || prev_agent_data_list_option.is_some();
+                                        if send_agent_ids {
+                                            offset = append_usize_checked(
+                                                buf,
+                                                offset,
+                                                agent_id_list.len(),
+                                                "agent count",
+                                            )?;
+                                        }
+                                        for (agent_id, (obs, reward_data_option)) in
+                                            agent_id_list.iter().zip(agent_data_list.iter())
+                                        {
+                                            if recalculate_agent_id_every_step || send_agent_ids {
+                                                offset = append_checked(
+                                                    &agent_id_serde,
+                                                    buf,
+                                                    offset,
+                                                    agent_id,
+                                                    "agent id",
+                                                )?;
+                                            }
+                                            offset = append_checked(
+                                                &obs_serde, buf, offset, obs, "obs",
+                                            )?;
+                                            if let Some((reward, terminated, truncated)) =
+                                                reward_data_option
+                                            {
+                                                offset = append_checked(
+                                                    &reward_serde,
+                                                    buf,
+                                                    offset,
+                                                    reward,
+                                                    "reward",
+                                                )?;
+                                                offset = append_bool_checked(
+                                                    buf,
+                                                    offset,
+                                                    *terminated,
+                                                    "terminated",
+                                                )?;
+                                                offset = append_bool_checked(
+                                                    buf,
+                                                    offset,
+                                                    *truncated,
+                                                    "truncated",
+                                                )?;
+                                            }
+                                        }
+                                        for (
+                                            new_agent_idx_option,
+                                            (reward, terminated, truncated),
+                                        ) in prev_agent_data_list_option.iter().flatten()
+                                        {
+                                            offset = append_bool_checked(
+                                                buf,
+                                                offset,
+                                                new_agent_idx_option.is_some(),
+                                                "agent present",
+                                            )?;
+                                            if let Some(new_agent_idx) = new_agent_idx_option {
+                                                offset = append_usize_checked(
+                                                    buf,
+                                                    offset,
+                                                    *new_agent_idx,
+                                                    "agent index",
+                                                )?;
+                                            }
+                                            offset = append_checked(
+                                                &reward_serde,
+                                                buf,
+                                                offset,
+                                                reward,
+                                                "reward",
+                                            )?;
+                                            offset = append_bool_checked(
+                                                buf,
+                                                offset,
+                                                *terminated,
+                                                "terminated",
+                                            )?;
+                                            offset = append_bool_checked(
+                                                buf,
+                                                offset,
+                                                *truncated,
+                                                "truncated",
+                                            )?;
+                                        }
+                                        if let Some(state) = state_option {
+                                            offset = append_checked(
+                                                state_serde_option.unwrap(),
+                                                buf,
+                                                offset,
+                                                state,
+                                                "state",
+                                            )?;
+                                        }
+                                        if let Some(state_metrics) = state_metrics_option {
+                                            offset = append_checked(
+                                                state_metrics_serde_option.unwrap(),
+                                                buf,
+                                                offset,
+                                                state_metrics,
+                                                "state metrics",
+                                            )?;
+                                        }
+                                        Ok(offset)
+                                    },
+                                )?;
+                            }
+                            append_env_call_timing_list(buf, offset, &env_call_timing_list)
+                        })?;
+                        transport.send(py)?;
+                        env_call_timing_list.clear();
+
+                        // Render (only the first sub-env is rendered)
+                        if render {
+                            render_failure_option = (|| -> PyResult<()> {
+                                let render_start = Instant::now();
+                                env_render(&env_list[0])?;
+                                env_call_timing_list
+                                    .push((EnvCall::Render, render_start.elapsed()));
+                                if let Some(render_delay) = render_delay_option {
+                                    sleep(Duration::from_micros(
+                                        ((render_delay.as_micros() as f64) * game_speed_fn()?)
+                                            .round() as u64,
+                                    ));
+                                }
+                                while game_paused_fn()? {
+                                    sleep(Duration::from_millis(100));
+                                }
+                                Ok(())
+                            })()
+                            .map_err(env_call_failure("env.render"))
+                            .err();
                         }
                     }
+                    Header::EnvShapesRequest => {
+                        let (sub_env_idx, _) = retrieve_usize(transport.slice(), offset)?;
+                        let env = env_list.get(sub_env_idx).ok_or_else(|| {
+                            InvalidStateError::new_err(format!(
+                                "Received request for env shapes of sub-env {} but this env process only hosts {} envs",
+                                sub_env_idx, n_envs
+                            ))
+                        })?;
+                        let spaces_option = if at_episode_boundary_list[sub_env_idx] {
+                            let (obs_spaces, action_spaces) = env_spaces(env)?;
+                            println!("Received request for env shapes, returning:");
+                            println!("- Observation space types: {}", obs_spaces.repr()?);
+                            println!("- Action space types: {}", action_spaces.repr()?);
+                            println!("--------------------");
+                            Some((obs_spaces, action_spaces))
+                        } else {
+                            // The EnvProcessInterface raises an error for the refusal
+                            None
+                        };
 
-                    if send_state_to_agent_controllers {
-                        offset = state_serde_option.unwrap().append(
-                            shm_slice,
-                            offset,
-                            &env_state(&env)?,
-                        )?;
+                        transport.write_message(py, &mut |buf| {
+                            let offset = append_header(buf, 0, Header::EnvShapesRequest);
+                            let offset = append_bool_checked(
+                                buf,
+                                offset,
+                                spaces_option.is_some(),
+                                "env shapes",
+                            )?;
+                            let Some((obs_spaces, action_spaces)) = &spaces_option else {
+                                return Ok(offset);
+                            };
+                            let offset = append_space_dict(
+                                buf,
+                                offset,
+                                &agent_id_serde,
+                                &obs_space_serde,
+                                obs_spaces,
+                                "obs space",
+                            )?;
+                            append_space_dict(
+                                buf,
+                                offset,
+                                &agent_id_serde,
+                                &action_space_serde,
+                                action_spaces,
+                                "action space",
+                            )
+                        })?;
+                        transport.send(py)?;
                     }
+                    Header::EnvRebuild => {
+                        let shm_slice = transport.slice();
+                        let (has_build_env_fn, offset) = retrieve_bool(shm_slice, offset)?;
+                        let spaces_list = if has_build_env_fn {
+                            let (pickled_build_env_fn, offset) = retrieve_bytes(shm_slice, offset)?;
+                            let pickled_build_env_fn = PyBytes::new(py, pickled_build_env_fn);
+                            let (seed, _) = retrieve_usize(shm_slice, offset)?;
+                            let build_env_fn = PyModule::import(py, "pickle")?
+                                .call_method1(intern!(py, "loads"), (pickled_build_env_fn,))?;
+                            let build_env_fn =
+                                PyModule::import(py, "rlgym_learn.env_processing.env_process")?
+                                    .call_method1(
+                                        intern!(py, "_seed_build_env_fn"),
+                                        (build_env_fn, seed),
+                                    )?;
+                            let rebuilt_env_list = (0..n_envs)
+                                .map(|_| build_env_fn.call0())
+                                .collect::<PyResult<Vec<_>>>()
+                                .map_err(env_call_failure("build_env_fn"))?;
+                            let spaces_list = rebuilt_env_list
+                                .iter()
+                                .map(env_spaces)
+                                .collect::<Result<Vec<_>, _>>()?;
+                            rebuilt_env_list_option = Some(rebuilt_env_list);
+                            spaces_list
+                        } else {
+                            // The EnvProcessInterface has checked the spaces of the new envs
+                            let (keep, _) = retrieve_bool(shm_slice, offset)?;
+                            let rebuilt_env_list =
+                                rebuilt_env_list_option.take().ok_or_else(|| {
+                                    InvalidStateError::new_err(
+                                        "Received EnvRebuild decision without having rebuilt envs",
+                                    )
+                                })?;
+                            for (pending_env_option, rebuilt_env) in
+                                pending_env_option_list.iter_mut().zip(rebuilt_env_list)
+                            {
+                                // The new envs replace the current ones as their episodes end, along with any
+                                // envs of an earlier rebuild which are still waiting to
+                                let discarded_env_option = if keep {
+                                    pending_env_option.replace(rebuilt_env)
+                                } else {
+                                    Some(rebuilt_env)
+                                };
+                                if let Some(discarded_env) = discarded_env_option {
+                                    env_close(&discarded_env)
+                                        .map_err(env_call_failure("env.close"))?;
+                                }
+                            }
+                            Vec::new()
+                        };
 
-                    if should_collect_state_metrics {
-                        let state_metrics = collect_state_metrics_fn_option
-                            .unwrap()
-                            .call1(py, (env_state(&env)?, env_shared_info(&env)?))?
-                            .into_bound(py);
-                        _ = state_metrics_serde_option.unwrap().append(
-                            shm_slice,
-                            offset,
-                            &state_metrics,
-                        )?;
+                        transport.write_message(py, &mut |buf| {
+                            let mut offset = append_header(buf, 0, Header::EnvRebuild);
+                            if !has_build_env_fn {
+                                return Ok(offset);
+                            }
+                            offset = append_usize_checked(
+                                buf,
+                                offset,
+                                spaces_list.len(),
+                                "sub-env count",
+                            )?;
+                            for (obs_spaces, action_spaces) in spaces_list.iter() {
+                                offset = append_space_dict(
+                                    buf,
+                                    offset,
+                                    &agent_id_serde,
+                                    &obs_space_serde,
+                                    obs_spaces,
+                                    "obs space",
+                                )?;
+                                offset = append_space_dict(
+                                    buf,
+                                    offset,
+                                    &agent_id_serde,
+                                    &action_space_serde,
+                                    action_spaces,
+                                    "action space",
+                                )?;
+                            }
+                            Ok(offset)
+                        })?;
+                        transport.send(py)?;
                     }
-                    sendto_byte(py, &child_end, &parent_sockname)?;
-
-                    // Render
-                    if render {
-                        env_render(&env)?;
-                        if let Some(render_delay) = render_delay_option {
-                            sleep(Duration::from_micros(
-                                ((render_delay.as_micros() as f64) * game_speed_fn()?).round()
-                                    as u64,
-                            ));
-                        }
-                        while game_paused_fn()? {
-                            sleep(Duration::from_millis(100));
-                        }
+                    Header::EnvCommand => {
+                        let shm_slice = transport.slice();
+                        let (method_name, offset) = retrieve_bytes(shm_slice, offset)?;
+                        let method_name = String::from_utf8_lossy(method_name).into_owned();
+                        let (payload, _) = env_command_serde.retrieve(py, shm_slice, offset)?;
+                        // Exceptions raised by the handler are reported back with the replies instead of ending
+                        // the env process
+                        let reply_result_list = env_list
+                            .iter()
+                            .map(|env| {
+                                env.call_method1(method_name.as_str(), (&payload,))
+                                    .map(|reply| (!reply.is_none()).then_some(reply))
+                                    .map_err(|err| {
+                                        EnvError::from_py_err(py, "env command handler", &err)
+                                    })
+                            })
+                            .collect::<Vec<_>>();
+
+                        transport.write_message(py, &mut |buf| {
+                            let mut offset = append_header(buf, 0, Header::EnvCommand);
+                            offset = append_usize_checked(buf, offset, n_envs, "sub-env count")?;
+                            for reply_result in reply_result_list.iter() {
+                                offset = append_bool_checked(
+                                    buf,
+                                    offset,
+                                    reply_result.is_ok(),
+                                    "env command handled",
+                                )?;
+                                match reply_result {
+                                    Ok(reply_option) => {
+                                        offset = append_bool_checked(
+                                            buf,
+                                            offset,
+                                            reply_option.is_some(),
+                                            "env command reply",
+                                        )?;
+                                        if let Some(reply) = reply_option {
+                                            offset = append_checked(
+                                                &env_command_serde,
+                                                buf,
+                                                offset,
+                                                reply,
+                                                "env command reply",
+                                            )?;
+                                        }
+                                    }
+                                    Err(env_error) => {
+                                        offset = append_env_error_checked(buf, offset, env_error)?;
+                                    }
+                                }
+                            }
+                            Ok(offset)
+                        })?;
+                        transport.send(py)?;
                     }
-                }
-                Header::EnvShapesRequest => {
-                    if has_received_env_action {
-                        println!("This env process (proc id {:?}) received request for env shapes, but this seems abnormal. Terminating...", proc_id);
+                    Header::Stop => {
+                        // The EnvProcessInterface waits for this before it stops waiting on this process
+                        transport.write_message(py, &mut |buf| {
+                            Ok(append_header(buf, 0, Header::Stop))
+                        })?;
+                        transport.send(py)?;
                         break;
                     }
-                    let obs_space = env_obs_spaces(&env)?.values().get_item(0)?;
-                    let action_space = env_action_spaces(&env)?.values().get_item(0)?;
-                    println!("Received request for env shapes, returning:");
-                    println!("- Observation space type: {}", obs_space.repr()?);
-                    println!("- Action space type: {}", action_space.repr()?);
-                    println!("--------------------");
-
-                    offset = 0;
-                    offset = obs_space_serde.append(shm_slice, offset, &obs_space)?;
-                    action_space_serde.append(shm_slice, offset, &action_space)?;
-                    sendto_byte(py, &child_end, &parent_sockname)?;
-                }
-                Header::Stop => {
-                    break;
+                    Header::EnvError => {
+                        return Err(InvalidStateError::new_err(
+                            "Received EnvError header, which is only sent from env processes",
+                        )
+                        .into());
+                    }
+                    Header::ShmResize => {
+                        return Err(InvalidStateError::new_err(
+                            "Received ShmResize header after following all resize notices",
+                        )
+                        .into());
+                    }
                 }
             }
+            Ok(())
+        })();
+
+        if let Err(failure) = result {
+            let (env_call, err) = match failure {
+                EnvProcessFailure::EnvCall(env_call, err) => (env_call, err),
+                EnvProcessFailure::Internal(err) => ("env_process", err),
+            };
+            // The EnvProcessInterface expects to sync before reading anything from this process. If it never
+            // responds there is nobody to report to.
+            if !synced_with_epi && sync(&mut transport_option).is_err() {
+                return Err(err);
+            }
+            let transport = match transport_option.as_mut() {
+                Some(transport) => transport,
+                None => transport_option.insert(connect()?),
+            };
+            let env_error = EnvError::from_py_err(py, env_call, &err);
+            transport.write_message(py, &mut |buf| {
+                let offset = append_header(buf, 0, Header::EnvError);
+                append_env_error(buf, offset, &env_error)
+            })?;
+            transport.send(py)?;
+            return Err(err);
         }
         Ok(())
     })
@@ -0,0 +1,109 @@
+use pyany_serde::{DynPyAnySerdeOption, PyAnySerde};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::time::Duration;
+
+use crate::handshake::Fingerprinted;
+
+// These mirror the config dataclasses in rlgym_learn/env_processing/env_process.py, which are read attribute by
+// attribute so they can be pickled along with the other args of an env process.
+
+fn extract_seconds_option(ob: &Bound<'_, PyAny>) -> PyResult<Option<Duration>> {
+    ob.extract::<Option<f64>>()?
+        .map(|seconds| {
+            Duration::try_from_secs_f64(seconds).map_err(|err| {
+                PyValueError::new_err(format!("Invalid number of seconds {seconds}: {err}"))
+            })
+        })
+        .transpose()
+}
+
+// Each timeout is None to wait forever
+#[derive(FromPyObject, Clone, Copy, Debug)]
+pub struct TimeoutConfig {
+    #[pyo3(from_py_with = "extract_seconds_option")]
+    pub startup: Option<Duration>,
+    #[pyo3(from_py_with = "extract_seconds_option")]
+    pub env_shapes: Option<Duration>,
+    #[pyo3(from_py_with = "extract_seconds_option")]
+    pub step: Option<Duration>,
+    #[pyo3(from_py_with = "extract_seconds_option")]
+    pub rebuild: Option<Duration>,
+    #[pyo3(from_py_with = "extract_seconds_option")]
+    pub shutdown: Option<Duration>,
+}
+
+#[derive(FromPyObject, Clone, Debug)]
+pub struct TransportConfig {
+    pub tcp_address: Option<String>,
+    pub tcp_token: Option<String>,
+    pub in_process: bool,
+    pub native_signalling: bool,
+    pub validate_shm_buffer_size: bool,
+    pub sweep_orphaned_flinks: bool,
+    pub external_processes: bool,
+}
+
+// TCP peers must identify with the token before anything else is exchanged, since messages carry pickled objects
+pub fn required_tcp_token(tcp_token_option: Option<&str>) -> PyResult<&str> {
+    match tcp_token_option {
+        Some(tcp_token) if !tcp_token.is_empty() => Ok(tcp_token),
+        _ => Err(PyValueError::new_err(
+            "A tcp_token must be set to use the TCP transport",
+        )),
+    }
+}
+
+// The latency budget is passed to each collection instead, since it can change between them
+#[derive(FromPyObject, Clone, Copy, Debug)]
+pub struct CollectionConfig {
+    pub lockstep: bool,
+    pub auto_tune_window: Option<usize>,
+    pub respawn_dead_processes: bool,
+}
+
+// Read from a PickleableSerdeTypeConfig or a SerdeTypesModel
+#[derive(FromPyObject)]
+pub struct SerdeTypeConfig {
+    #[pyo3(attribute("agent_id_serde_type"))]
+    pub agent_id_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("action_serde_type"))]
+    pub action_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("obs_serde_type"))]
+    pub obs_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("reward_serde_type"))]
+    pub reward_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("obs_space_serde_type"))]
+    pub obs_space_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("action_space_serde_type"))]
+    pub action_space_serde: Fingerprinted<Box<dyn PyAnySerde>>,
+    #[pyo3(attribute("state_serde_type"))]
+    pub state_serde_option: Fingerprinted<DynPyAnySerdeOption>,
+    #[pyo3(attribute("state_metrics_serde_type"))]
+    pub state_metrics_serde_option: Fingerprinted<DynPyAnySerdeOption>,
+}
+
+#[derive(FromPyObject, Clone, Debug)]
+pub struct EnvProcessConfig {
+    pub proc_id: String,
+    pub flinks_folder: String,
+    pub shm_buffer_size: usize,
+    pub send_state_to_agent_controllers: bool,
+    pub render: bool,
+    #[pyo3(attribute("render_delay"), from_py_with = "extract_seconds_option")]
+    pub render_delay_option: Option<Duration>,
+    pub recalculate_agent_id_every_step: bool,
+    pub n_envs: usize,
+}
+
+#[derive(FromPyObject, Clone, Debug)]
+pub struct EnvProcessInterfaceConfig {
+    pub flinks_folder: String,
+    pub min_process_steps_per_inference: usize,
+    pub send_state_to_agent_controllers: bool,
+    pub should_collect_state_metrics: bool,
+    pub recalculate_agent_id_every_step: bool,
+    pub n_envs_per_process: usize,
+    #[pyo3(attribute("seed"))]
+    pub seed_option: Option<u64>,
+}
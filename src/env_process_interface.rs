@@ -1,136 +1,657 @@
-// use rayon::prelude::*;
-
 use std::cmp::max;
 use std::cmp::min;
 use std::collections::HashMap;
-use std::thread;
+use std::ops::Range;
 use std::time::Duration;
+use std::time::Instant;
 
 use itertools::izip;
 use itertools::Itertools;
+use pyany_serde::pyany_serde_impl::PickleSerde;
 use pyany_serde::DynPyAnySerdeOption;
 use pyany_serde::{
     communication::{retrieve_bool, retrieve_usize},
-    PyAnySerde,
+    PyAnySerde, PyAnySerdeType,
 };
 use pyo3::exceptions::PyValueError;
 use pyo3::{
     exceptions::asyncio::InvalidStateError, intern, prelude::*, sync::GILOnceCell, types::PyDict,
     IntoPyObjectExt,
 };
-use raw_sync::events::Event;
-use raw_sync::events::EventInit;
-use raw_sync::events::EventState;
-use shared_memory::Shmem;
-use shared_memory::ShmemConf;
+use rayon::prelude::*;
 
 use crate::env_action::append_env_action;
 use crate::env_action::EnvAction;
+use crate::env_error::{retrieve_env_error, EnvProcessError};
+use crate::env_process_config::{
+    required_tcp_token, CollectionConfig, EnvProcessInterfaceConfig, SerdeTypeConfig,
+    TimeoutConfig, TransportConfig,
+};
+use crate::flink_lock::{self, remove_flink_lock};
+use crate::handshake::{fnv1a, Handshake};
+use crate::min_process_steps_tuner::MinProcessStepsTuner;
 use crate::misc::clone_list;
-use crate::synchronization::{append_header, get_flink, recvfrom_byte, sendto_byte, Header};
+use crate::notification::{poll_fds, NotificationReceiver};
+use crate::raw_decode::{RawAgentData, RawAgentDataDecoder, RawDecoder};
+use crate::shm_buffer::{
+    append_bool_checked, append_bytes_checked, append_checked, append_sub_env_entry,
+    append_usize_checked, retrieve_space_dict, retrieve_sub_env_entry, CheckedSerde,
+};
+use crate::synchronization::{
+    append_header, recvfrom_bytes_timeout, sendto_bytes, timeout_err, Header,
+};
+use crate::telemetry::{retrieve_env_call_timing_list, Telemetry};
+use crate::transport::{
+    channel_transport_pair, register_channel_end, take_channel_end, transport_name, ShmTransport,
+    TcpTransportListener, Transport, INITIAL_BUFFER_SIZE,
+};
 
+// Exchanges handshakes with the env process, failing if its configuration doesn't match ours
 fn sync_with_env_process<'py>(
     py: Python<'py>,
     socket: &PyObject,
     address: &PyObject,
+    proc_id: &str,
+    handshake: &Handshake,
+    timeout_option: Option<Duration>,
+) -> PyResult<()> {
+    let Some(env_process_handshake_bytes) = recvfrom_bytes_timeout(py, socket, timeout_option)?
+    else {
+        return Err(timeout_err(
+            proc_id,
+            "startup sync",
+            timeout_option.unwrap(),
+        ));
+    };
+    sendto_bytes(py, socket, address, &handshake.to_bytes())?;
+    handshake.check(
+        &Handshake::from_bytes(&env_process_handshake_bytes)?,
+        "The EnvProcessInterface",
+        &format!("env process with proc id {}", proc_id),
+    )
+}
+
+// Like sync_with_env_process, but over a TCP connection, where the handshakes are the first messages exchanged
+fn sync_with_env_process_over_tcp<'py>(
+    py: Python<'py>,
+    transport: &mut dyn Transport,
+    proc_id: &str,
+    handshake: &Handshake,
+    timeout_option: Option<Duration>,
 ) -> PyResult<()> {
-    recvfrom_byte(py, socket)?;
-    sendto_byte(py, socket, address)
+    if !transport.recv_timeout(py, timeout_option)? {
+        if transport.is_closed() {
+            return Err(EnvProcessError::new_err(format!(
+                "Env process with proc id {} closed its connection during startup sync",
+                proc_id
+            )));
+        }
+        return Err(timeout_err(
+            proc_id,
+            "startup sync",
+            timeout_option.unwrap(),
+        ));
+    }
+    let env_process_handshake = Handshake::from_bytes(transport.slice())?;
+    handshake.send_over(py, transport)?;
+    handshake.check(
+        &env_process_handshake,
+        "The EnvProcessInterface",
+        &format!("env process with proc id {}", proc_id),
+    )
+}
+
+// Reads the header of a message from an env process, raising the error it reported if there was one
+fn retrieve_response_header(
+    transport: &mut dyn Transport,
+    proc_id: &str,
+    expected_header: Header,
+) -> PyResult<usize> {
+    let (header, offset) = transport.retrieve_message_header()?;
+    if header == Header::EnvError {
+        let (env_error, _) = retrieve_env_error(transport.slice(), offset)?;
+        return Err(env_error.into_py_err(proc_id));
+    }
+    if header != expected_header {
+        return Err(InvalidStateError::new_err(format!(
+            "Expected {} response from env process with proc id {} but got {}",
+            expected_header, proc_id, header
+        )));
+    }
+    Ok(offset)
+}
+
+// Env processes can host several envs (sub-envs), which are exposed as separate logical processes. Processes hosting
+// a single env keep their own proc id.
+fn sub_env_proc_id(proc_id: &str, sub_env_idx: usize, n_envs_per_process: usize) -> String {
+    if n_envs_per_process == 1 {
+        proc_id.to_string()
+    } else {
+        format!("{}-{}", proc_id, sub_env_idx)
+    }
+}
+
+// (process, parent_end, child_sockname, proc_id) of an env process spawned by the Python EnvProcessInterface. The
+// startup sync socket is only used by transports which don't carry the startup sync themselves.
+type ProcPackageDef = (PyObject, Option<PyObject>, Option<PyObject>, String);
+
+// (process, transport, proc_id) of an env process. Env processes which weren't started by us have no process object.
+type ProcPackage = (Option<PyObject>, Box<dyn Transport>, String);
+
+// (agent id list, obs list) of an env
+type ObsData = (Vec<PyObject>, Vec<PyObject>);
+
+// (timestep list, action associated learning data, optional state metrics, optional state) of an env
+type TimestepData = (Vec<PyObject>, PyObject, Option<PyObject>, Option<PyObject>);
+
+// (optional state, optional terminated dict, optional truncated dict) of an env
+type StateInfo = (Option<PyObject>, Option<Py<PyDict>>, Option<Py<PyDict>>);
+
+// ((proc id, obs data), (proc id, state info)) of an env after reset
+type InitialObsData = ((PyObject, ObsData), (PyObject, StateInfo));
+
+// (number of timesteps, then the obs data, timestep data and state info of an env, each paired with its proc id)
+type EnvResponse = (
+    usize,
+    (PyObject, ObsData),
+    (PyObject, TimestepData),
+    (PyObject, StateInfo),
+);
+
+// (number of timesteps collected, then the obs data, timestep data and state info of the collected envs by proc id)
+type StepData = (usize, Py<PyDict>, Py<PyDict>, Py<PyDict>);
+
+// (env command id, proc id of env, optional reply, optional EnvProcessError raised by the handler method)
+type EnvCommandReply = (usize, String, Option<PyObject>, Option<PyObject>);
+
+// The exchanges of an env rebuild. The env process builds the new envs and reports their spaces, and is then told
+// whether to keep them.
+enum EnvRebuildStep<'a> {
+    Build(&'a [u8], u64),
+    Keep,
+    Discard,
 }
 
 static SELECTORS_EVENT_READ: GILOnceCell<u8> = GILOnceCell::new();
 
+// How often collect_step_data checks on processes which have not responded yet
+const PROCESS_HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Weight of the latest step in StepLatencyStats.ema
+const STEP_LATENCY_EMA_WEIGHT: f64 = 0.1;
+
+// Step latencies of an env process in seconds, where the latency of a step is the time between sending env actions
+// to the process and being notified of its response
+#[pyclass(module = "rlgym_learn", get_all)]
+#[derive(Clone, Default)]
+pub struct StepLatencyStats {
+    n_steps: u64,
+    last: f64,
+    mean: f64,
+    ema: f64,
+    max: f64,
+}
+
+impl StepLatencyStats {
+    fn record(&mut self, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        self.n_steps += 1;
+        self.last = latency;
+        self.mean += (latency - self.mean) / self.n_steps as f64;
+        if self.n_steps == 1 {
+            self.ema = latency;
+        } else {
+            self.ema += STEP_LATENCY_EMA_WEIGHT * (latency - self.ema);
+        }
+        self.max = self.max.max(latency);
+    }
+}
+
 #[pyclass(module = "rlgym_learn", unsendable)]
 pub struct EnvProcessInterface {
     agent_id_serde: Box<dyn PyAnySerde>,
-    action_serde: Box<dyn PyAnySerde>,
+    action_serde: CheckedSerde,
     obs_serde: Box<dyn PyAnySerde>,
     reward_serde: Box<dyn PyAnySerde>,
     obs_space_serde: Box<dyn PyAnySerde>,
     action_space_serde: Box<dyn PyAnySerde>,
-    state_serde_option: Option<Box<dyn PyAnySerde>>,
+    state_serde_option: Option<CheckedSerde>,
     state_metrics_serde_option: Option<Box<dyn PyAnySerde>>,
+    agent_id_raw_decoder_option: Option<RawDecoder>,
+    obs_raw_decoder_option: Option<RawDecoder>,
+    reward_raw_decoder_option: Option<RawDecoder>,
     recalculate_agent_id_every_step: bool,
     flinks_folder: String,
-    proc_packages: Vec<(PyObject, Shmem, String)>,
+    handshake: Handshake,
+    n_envs_per_process: usize,
+    // One per env process. Everything indexed by pid_idx is per sub-env instead, where the sub-envs of the process
+    // at package_idx have pid_idx package_idx * n_envs_per_process..(package_idx + 1) * n_envs_per_process.
+    proc_packages: Vec<ProcPackage>,
+    // Only used with the TCP transport
+    tcp_listener_option: Option<TcpTransportListener>,
+    // Whether envs are hosted on threads of this process, which are reached through in-process channels
+    in_process: bool,
+    // Whether collect_step_data waits for every env with an env action in flight and returns them in proc id order
+    lockstep: bool,
     min_process_steps_per_inference: usize,
+    min_process_steps_tuner_option: Option<MinProcessStepsTuner>,
     send_state_to_agent_controllers: bool,
     should_collect_state_metrics: bool,
+    startup_timeout_option: Option<Duration>,
+    env_shapes_timeout_option: Option<Duration>,
+    step_timeout_option: Option<Duration>,
+    // How long to wait for each env process to build its replacement envs
+    rebuild_timeout_option: Option<Duration>,
+    // How long to wait for each env process to acknowledge a stop
+    shutdown_timeout_option: Option<Duration>,
+    respawn_dead_processes: bool,
+    native_signalling: bool,
+    seed_option: Option<u64>,
     selector: PyObject,
     timestep_class: PyObject,
+    // Obs and action spaces by agent id of every agent seen so far
+    space_types_option: Option<(Py<PyDict>, Py<PyDict>)>,
+    // Pickled replacement env build functions and seeds waiting to be sent, by proc id of env process
+    pending_env_rebuild_map: HashMap<String, (Vec<u8>, u64)>,
+    // Env command payloads and replies can be anything pickleable
+    env_command_serde: CheckedSerde,
+    next_env_command_id: usize,
+    // (env command id, handler method name, payload) lists waiting to be sent, by proc id of env process
+    pending_env_command_map: HashMap<String, Vec<(usize, String, PyObject)>>,
+    // Env commands which have been handled
+    env_command_reply_list: Vec<EnvCommandReply>,
     proc_id_pid_idx_map: HashMap<String, usize>,
     pid_idx_current_env_action_list: Vec<Option<EnvAction>>,
     pid_idx_current_agent_id_list: Vec<Option<Vec<PyObject>>>,
     pid_idx_prev_timestep_id_list: Vec<Vec<Option<u128>>>,
+    pid_idx_timestep_id_rng_list: Vec<fastrand::Rng>,
     pid_idx_current_obs_list: Vec<Vec<PyObject>>,
     pid_idx_current_action_list: Vec<Vec<PyObject>>,
     pid_idx_current_aald_list: Vec<Option<PyObject>>,
-    added_process_obs_data_kv_list: Vec<(Py<PyAny>, (Vec<PyObject>, Vec<PyObject>))>,
-    added_process_state_info_kv_list: Vec<(
-        Py<PyAny>,
-        (Option<Py<PyAny>>, Option<Py<PyDict>>, Option<Py<PyDict>>),
-    )>,
+    pid_idx_env_action_sent_instant_list: Vec<Option<Instant>>,
+    // By the proc id of the env process, not of its sub-envs
+    proc_id_step_latency_stats_map: HashMap<String, StepLatencyStats>,
+    telemetry: Telemetry,
+    dead_process_list: Vec<(String, String, Vec<String>)>,
+    added_process_obs_data_kv_list: Vec<(Py<PyAny>, ObsData)>,
+    added_process_state_info_kv_list: Vec<(Py<PyAny>, StateInfo)>,
 }
 
 impl EnvProcessInterface {
-    fn get_initial_obs_data_proc<'py>(
+    fn n_envs(&self) -> usize {
+        self.proc_packages.len() * self.n_envs_per_process
+    }
+
+    fn package_pid_idx_range(&self, package_idx: usize) -> Range<usize> {
+        package_idx * self.n_envs_per_process..(package_idx + 1) * self.n_envs_per_process
+    }
+
+    fn pid_idx_proc_id(&self, pid_idx: usize) -> String {
+        sub_env_proc_id(
+            &self.proc_packages[pid_idx / self.n_envs_per_process].2,
+            pid_idx % self.n_envs_per_process,
+            self.n_envs_per_process,
+        )
+    }
+
+    fn package_idx(&self, proc_id: &str) -> usize {
+        self.proc_id_pid_idx_map[&sub_env_proc_id(proc_id, 0, self.n_envs_per_process)]
+            / self.n_envs_per_process
+    }
+
+    // Like package_idx, but also accepts the proc id of any sub-env of the process, and errors on unknown proc ids
+    fn find_package_idx(&self, proc_id: &str) -> PyResult<usize> {
+        if let Some(package_idx) = self
+            .proc_packages
+            .iter()
+            .position(|(_, _, other_proc_id)| other_proc_id == proc_id)
+        {
+            return Ok(package_idx);
+        }
+        self.proc_id_pid_idx_map
+            .get(proc_id)
+            .map(|pid_idx| pid_idx / self.n_envs_per_process)
+            .ok_or_else(|| {
+                PyValueError::new_err(format!("No env process or env with proc id {}", proc_id))
+            })
+    }
+
+    // Returns the initial obs data of each sub-env of the process, in sub-env order
+    fn get_initial_obs_data_package<'py>(
         &mut self,
         py: Python<'py>,
-        pid_idx: usize,
-    ) -> PyResult<(
-        (PyObject, (Vec<PyObject>, Vec<PyObject>)),
-        (
-            PyObject,
-            (Option<PyObject>, Option<Py<PyDict>>, Option<Py<PyDict>>),
-        ),
-    )> {
-        let (parent_end, shmem, proc_id) = self.proc_packages.get(pid_idx).unwrap();
-        let shm_slice = unsafe { &shmem.as_slice()[Event::size_of(None)..] };
-        recvfrom_byte(py, parent_end)?;
-        let mut offset = 0;
-        let n_agents;
-        (n_agents, offset) = retrieve_usize(shm_slice, offset)?;
-        let mut agent_id_list: Vec<PyObject> = Vec::with_capacity(n_agents);
-        let mut obs_list: Vec<PyObject> = Vec::with_capacity(n_agents);
-        let mut agent_id;
-        let mut obs;
-        for _ in 0..n_agents {
-            (agent_id, offset) = self.agent_id_serde.retrieve(py, shm_slice, offset)?;
-            agent_id_list.push(agent_id.unbind());
-            (obs, offset) = self.obs_serde.retrieve(py, shm_slice, offset)?;
-            obs_list.push(obs.unbind());
+        package_idx: usize,
+    ) -> PyResult<Vec<InitialObsData>> {
+        let (_, ref mut transport, ref proc_id) = self.proc_packages[package_idx];
+        if !transport.recv_timeout(py, self.startup_timeout_option)? {
+            return Err(timeout_err(
+                proc_id,
+                "initial reset",
+                self.startup_timeout_option.unwrap(),
+            ));
         }
+        let offset = retrieve_response_header(transport.as_mut(), proc_id, Header::EnvAction)?;
+        let shm_slice = transport.slice();
+        let (n_entries, mut entry_offset) = retrieve_usize(shm_slice, offset)?;
+        let mut initial_obs_data_list = Vec::with_capacity(n_entries);
+        for expected_sub_env_idx in 0..n_entries {
+            let (sub_env_idx, mut offset, end_offset) =
+                retrieve_sub_env_entry(shm_slice, entry_offset)?;
+            if sub_env_idx != expected_sub_env_idx || n_entries != self.n_envs_per_process {
+                return Err(InvalidStateError::new_err(format!(
+                    "Env process with proc id {} sent initial obs for {} sub-envs but {} were expected",
+                    proc_id, n_entries, self.n_envs_per_process
+                )));
+            }
+            entry_offset = end_offset;
+            let n_agents;
+            (n_agents, offset) = retrieve_usize(shm_slice, offset)?;
+            let mut agent_id_list: Vec<PyObject> = Vec::with_capacity(n_agents);
+            let mut obs_list: Vec<PyObject> = Vec::with_capacity(n_agents);
+            let mut agent_id;
+            let mut obs;
+            for _ in 0..n_agents {
+                (agent_id, offset) = self.agent_id_serde.retrieve(py, shm_slice, offset)?;
+                agent_id_list.push(agent_id.unbind());
+                (obs, offset) = self.obs_serde.retrieve(py, shm_slice, offset)?;
+                obs_list.push(obs.unbind());
+            }
 
-        let state_option;
-        if self.send_state_to_agent_controllers {
-            let state;
-            (state, _) = self
-                .state_serde_option
-                .as_ref()
-                .unwrap()
-                .retrieve(py, shm_slice, offset)?;
-            state_option = Some(state.unbind());
-        } else {
-            state_option = None;
+            let state_option;
+            if self.send_state_to_agent_controllers {
+                let state;
+                (state, _) = self
+                    .state_serde_option
+                    .as_ref()
+                    .unwrap()
+                    .retrieve(py, shm_slice, offset)?;
+                state_option = Some(state.unbind());
+            } else {
+                state_option = None;
+            }
+
+            let py_proc_id =
+                sub_env_proc_id(proc_id, sub_env_idx, self.n_envs_per_process).into_py_any(py)?;
+            initial_obs_data_list.push((
+                (py_proc_id.clone_ref(py), (agent_id_list, obs_list)),
+                (py_proc_id, (state_option, None, None)),
+            ));
         }
+        Ok(initial_obs_data_list)
+    }
 
-        let py_proc_id = proc_id.into_py_any(py)?;
-        Ok((
-            (py_proc_id.clone_ref(py), (agent_id_list, obs_list)),
-            (py_proc_id, (state_option, None, None)),
-        ))
+    // Each sub-env draws timestep ids from its own generator, so with a seed they don't depend on the order in which
+    // responses are collected
+    fn new_timestep_id_rng(&self, proc_id: &str) -> fastrand::Rng {
+        match self.seed_option {
+            Some(seed) => {
+                let mut bytes = seed.to_le_bytes().to_vec();
+                bytes.extend_from_slice(proc_id.as_bytes());
+                fastrand::Rng::with_seed(fnv1a(&bytes))
+            }
+            None => fastrand::Rng::new(),
+        }
     }
 
     fn update_with_initial_obs<'py>(
         &mut self,
         py: Python<'py>,
     ) -> PyResult<(Py<PyDict>, Py<PyDict>)> {
-        let n_procs = self.proc_packages.len();
-        let mut obs_data_kv_list = Vec::with_capacity(n_procs);
-        let mut state_info_kv_list = Vec::with_capacity(n_procs);
-        for pid_idx in 0..n_procs {
-            let ((py_proc_id, (agent_id_list, obs_list)), state_info_kv) =
-                self.get_initial_obs_data_proc(py, pid_idx)?;
+        let n_envs = self.n_envs();
+        let mut obs_data_kv_list = Vec::with_capacity(n_envs);
+        let mut state_info_kv_list = Vec::with_capacity(n_envs);
+        for package_idx in 0..self.proc_packages.len() {
+            for ((py_proc_id, (agent_id_list, obs_list)), state_info_kv) in
+                self.get_initial_obs_data_package(py, package_idx)?
+            {
+                let n_agents = agent_id_list.len();
+                self.pid_idx_current_agent_id_list
+                    .push(Some(clone_list(py, &agent_id_list)));
+                self.pid_idx_current_obs_list
+                    .push(clone_list(py, &obs_list));
+                self.pid_idx_prev_timestep_id_list
+                    .push(vec![None; n_agents]);
+                self.pid_idx_timestep_id_rng_list
+                    .push(self.new_timestep_id_rng(&py_proc_id.extract::<String>(py)?));
+                obs_data_kv_list.push((py_proc_id, (agent_id_list, obs_list)));
+                state_info_kv_list.push(state_info_kv);
+            }
+        }
+        Ok((
+            PyDict::from_sequence(&obs_data_kv_list.into_pyobject(py)?)?.unbind(),
+            PyDict::from_sequence(&state_info_kv_list.into_pyobject(py)?)?.unbind(),
+        ))
+    }
+
+    // Requests the obs and action spaces by agent id of the env at pid_idx, whose process must not have an env action
+    // in flight. Returns None if the env isn't at an episode boundary.
+    fn request_space_types<'py>(
+        &mut self,
+        py: Python<'py>,
+        pid_idx: usize,
+    ) -> PyResult<Option<(Bound<'py, PyDict>, Bound<'py, PyDict>)>> {
+        let (_, transport, proc_id) = &mut self.proc_packages[pid_idx / self.n_envs_per_process];
+        let sub_env_idx = pid_idx % self.n_envs_per_process;
+        transport.write_message(py, &mut |buf| {
+            let offset = append_header(buf, 0, Header::EnvShapesRequest);
+            append_usize_checked(buf, offset, sub_env_idx, "sub-env index")
+        })?;
+        transport.send(py)?;
+        if !transport.recv_timeout(py, self.env_shapes_timeout_option)? {
+            return Err(timeout_err(
+                proc_id,
+                "env shapes handshake",
+                self.env_shapes_timeout_option.unwrap(),
+            ));
+        }
+        let offset =
+            retrieve_response_header(transport.as_mut(), proc_id, Header::EnvShapesRequest)?;
+        let shm_slice = transport.slice();
+        let (at_episode_boundary, offset) = retrieve_bool(shm_slice, offset)?;
+        if !at_episode_boundary {
+            return Ok(None);
+        }
+        let (obs_spaces, offset) = retrieve_space_dict(
+            py,
+            shm_slice,
+            offset,
+            self.agent_id_serde.as_ref(),
+            self.obs_space_serde.as_ref(),
+        )?;
+        let (action_spaces, _) = retrieve_space_dict(
+            py,
+            shm_slice,
+            offset,
+            self.agent_id_serde.as_ref(),
+            self.action_space_serde.as_ref(),
+        )?;
+        Ok(Some((obs_spaces, action_spaces)))
+    }
+
+    // Checks the spaces of each agent against those seen before for agents with the same id, returning a description
+    // of the first mismatch. Agents which haven't been seen before can have any spaces.
+    fn find_space_types_mismatch<'py>(
+        &self,
+        py: Python<'py>,
+        obs_spaces: &Bound<'py, PyDict>,
+        action_spaces: &Bound<'py, PyDict>,
+    ) -> PyResult<Option<String>> {
+        let Some((known_obs_spaces, known_action_spaces)) = &self.space_types_option else {
+            return Ok(None);
+        };
+        for (kind, known_spaces, spaces) in [
+            ("obs", known_obs_spaces.bind(py), obs_spaces),
+            ("action", known_action_spaces.bind(py), action_spaces),
+        ] {
+            for (agent_id, space) in spaces.iter() {
+                let Some(known_space) = known_spaces.get_item(&agent_id)? else {
+                    continue;
+                };
+                if !known_space.eq(&space)? {
+                    return Ok(Some(format!(
+                        "agent {} has {} space {} but {} was expected",
+                        agent_id.repr()?,
+                        kind,
+                        space.repr()?,
+                        known_space.repr()?
+                    )));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Keeps the spaces of the agents which haven't been seen before
+    fn record_space_types<'py>(
+        &mut self,
+        py: Python<'py>,
+        obs_spaces: &Bound<'py, PyDict>,
+        action_spaces: &Bound<'py, PyDict>,
+    ) -> PyResult<()> {
+        let (known_obs_spaces, known_action_spaces) = self
+            .space_types_option
+            .get_or_insert_with(|| (PyDict::new(py).unbind(), PyDict::new(py).unbind()));
+        for (known_spaces, spaces) in [
+            (known_obs_spaces.bind(py), obs_spaces),
+            (known_action_spaces.bind(py), action_spaces),
+        ] {
+            for (agent_id, space) in spaces.iter() {
+                if !known_spaces.contains(&agent_id)? {
+                    known_spaces.set_item(agent_id, space)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the env process described by proc_package_def once the startup sync with it completes. Over TCP, a
+    // proc_package_def of None waits for any env process started elsewhere to connect instead. Returns the proc id of
+    // the added process.
+    fn add_proc_package<'py>(
+        &mut self,
+        py: Python<'py>,
+        proc_package_def_option: Option<ProcPackageDef>,
+    ) -> PyResult<String> {
+        let (process_option, parent_end_option, child_sockname_option, proc_id_option) =
+            match proc_package_def_option {
+                Some((process, parent_end, child_sockname, proc_id)) => {
+                    (Some(process), parent_end, child_sockname, Some(proc_id))
+                }
+                None => (None, None, None, None),
+            };
+        let (transport, proc_id): (Box<dyn Transport>, String) = if let Some(tcp_listener) =
+            self.tcp_listener_option.as_mut()
+        {
+            let (proc_id, mut transport) =
+                tcp_listener.accept(py, proc_id_option.as_deref(), self.startup_timeout_option)?;
+            // Env processes started elsewhere choose their own proc id
+            if self.proc_id_pid_idx_map.contains_key(&sub_env_proc_id(
+                &proc_id,
+                0,
+                self.n_envs_per_process,
+            )) {
+                return Err(PyValueError::new_err(format!(
+                    "An env process with proc id {} is already connected",
+                    proc_id
+                )));
+            }
+            sync_with_env_process_over_tcp(
+                py,
+                &mut transport,
+                &proc_id,
+                &self.handshake,
+                self.startup_timeout_option,
+            )?;
+            (Box::new(transport), proc_id)
+        } else {
+            let (Some(parent_end), Some(child_sockname), Some(proc_id)) =
+                (parent_end_option, child_sockname_option, proc_id_option)
+            else {
+                return Err(PyValueError::new_err(
+                        "Env processes must be spawned with a startup sync socket unless they connect over TCP",
+                    ));
+            };
+            let receiver = NotificationReceiver::new(
+                self.native_signalling,
+                parent_end.clone_ref(py),
+                &self.flinks_folder,
+                &proc_id,
+            )?;
+            // The env thread takes its end of the channel once the startup sync completes
+            let mut channel_end_option = None;
+            if self.in_process {
+                let (channel_end, env_thread_channel_end) =
+                    channel_transport_pair(INITIAL_BUFFER_SIZE);
+                register_channel_end(&proc_id, env_thread_channel_end);
+                channel_end_option = Some(channel_end);
+            }
+            if let Err(err) = sync_with_env_process(
+                py,
+                &parent_end,
+                &child_sockname,
+                &proc_id,
+                &self.handshake,
+                self.startup_timeout_option,
+            ) {
+                if self.in_process {
+                    let _ = take_channel_end(&proc_id);
+                }
+                return Err(err);
+            }
+            let transport: Box<dyn Transport> = match channel_end_option {
+                Some(mut channel_end) => {
+                    channel_end.attach_receiver(receiver);
+                    Box::new(channel_end)
+                }
+                None => Box::new(ShmTransport::open(&self.flinks_folder, &proc_id, receiver)?),
+            };
+            (transport, proc_id)
+        };
+        if let Some(socket) = transport.py_socket() {
+            self.selector.call_method1(
+                py,
+                intern!(py, "register"),
+                (
+                    socket.clone_ref(py),
+                    SELECTORS_EVENT_READ.get_or_init(py, || {
+                        PyModule::import(py, "selectors")
+                            .unwrap()
+                            .getattr("EVENT_READ")
+                            .unwrap()
+                            .extract()
+                            .unwrap()
+                    }),
+                    &proc_id,
+                ),
+            )?;
+        }
+        let package_idx = self.proc_packages.len();
+        for sub_env_idx in 0..self.n_envs_per_process {
+            self.proc_id_pid_idx_map.insert(
+                sub_env_proc_id(&proc_id, sub_env_idx, self.n_envs_per_process),
+                package_idx * self.n_envs_per_process + sub_env_idx,
+            );
+        }
+        self.proc_id_step_latency_stats_map
+            .insert(proc_id.clone(), StepLatencyStats::default());
+        self.proc_packages
+            .push((process_option, transport, proc_id.clone()));
+
+        Ok(proc_id)
+    }
+
+    // Like add_proc_package, but also sets up the pid_idx-indexed state of the new process, for processes added after
+    // init_processes
+    fn add_initialized_proc_package<'py>(
+        &mut self,
+        py: Python<'py>,
+        proc_package_def_option: Option<ProcPackageDef>,
+    ) -> PyResult<String> {
+        let package_idx = self.proc_packages.len();
+        let proc_id = self.add_proc_package(py, proc_package_def_option)?;
+        for ((py_proc_id, (agent_id_list, obs_list)), state_info_kv) in
+            self.get_initial_obs_data_package(py, package_idx)?
+        {
             let n_agents = agent_id_list.len();
             self.pid_idx_current_agent_id_list
                 .push(Some(clone_list(py, &agent_id_list)));
@@ -138,73 +659,539 @@ impl EnvProcessInterface {
                 .push(clone_list(py, &obs_list));
             self.pid_idx_prev_timestep_id_list
                 .push(vec![None; n_agents]);
-            obs_data_kv_list.push((py_proc_id, (agent_id_list, obs_list)));
-            state_info_kv_list.push(state_info_kv);
+            self.pid_idx_timestep_id_rng_list
+                .push(self.new_timestep_id_rng(&py_proc_id.extract::<String>(py)?));
+            self.pid_idx_current_env_action_list.push(None);
+            self.pid_idx_current_action_list
+                .push(Vec::with_capacity(n_agents));
+            self.pid_idx_current_aald_list.push(None);
+            self.pid_idx_env_action_sent_instant_list.push(None);
+            self.added_process_obs_data_kv_list
+                .push((py_proc_id, (agent_id_list, obs_list)));
+            self.added_process_state_info_kv_list.push(state_info_kv);
         }
-        Ok((
-            PyDict::from_sequence(&obs_data_kv_list.into_pyobject(py)?)?.unbind(),
-            PyDict::from_sequence(&state_info_kv_list.into_pyobject(py)?)?.unbind(),
-        ))
+        Ok(proc_id)
+    }
+
+    // Returns an error describing why the process is considered dead, or None if it is healthy
+    fn get_process_failure<'py>(
+        &self,
+        py: Python<'py>,
+        package_idx: usize,
+    ) -> PyResult<Option<PyErr>> {
+        let (process_option, transport, proc_id) = &self.proc_packages[package_idx];
+        if let Some(process) = process_option {
+            let process = process.bind(py);
+            if !process
+                .call_method0(intern!(py, "is_alive"))?
+                .extract::<bool>()?
+            {
+                let exitcode = process.getattr(intern!(py, "exitcode"))?;
+                return Ok(Some(EnvProcessError::new_err(format!(
+                    "Env process with proc id {} exited unexpectedly with exit code {}",
+                    proc_id,
+                    exitcode.repr()?
+                ))));
+            }
+        }
+        // Env processes started elsewhere can only be seen to exit through their connection
+        if transport.is_closed() {
+            return Ok(Some(EnvProcessError::new_err(format!(
+                "Env process with proc id {} closed its connection unexpectedly",
+                proc_id
+            ))));
+        }
+        // The process is as late as its earliest outstanding env action
+        if let (Some(step_timeout), Some(sent_instant)) = (
+            self.step_timeout_option,
+            self.pid_idx_env_action_sent_instant_list[self.package_pid_idx_range(package_idx)]
+                .iter()
+                .flatten()
+                .min(),
+        ) {
+            if sent_instant.elapsed() > step_timeout {
+                return Ok(Some(timeout_err(proc_id, "step", step_timeout)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Dead processes are retired while collecting, so the target is re-evaluated against the number of live processes.
+    // Once the deadline (if any) has passed, any number of collected process steps is enough.
+    fn is_done_collecting(
+        &self,
+        n_process_steps_collected: usize,
+        deadline_option: Option<Instant>,
+    ) -> bool {
+        // Lockstep collection waits for every env regardless of the deadline
+        if self.lockstep {
+            return self
+                .pid_idx_env_action_sent_instant_list
+                .iter()
+                .all(Option::is_none);
+        }
+        n_process_steps_collected >= min(self.min_process_steps_per_inference, self.n_envs())
+            || (n_process_steps_collected > 0
+                && deadline_option.is_some_and(|deadline| Instant::now() >= deadline))
+    }
+
+    // Checks every process which has an env action in flight and retires any which are dead or hung.
+    // Returns an error describing the first failure unless respawn_dead_processes is set.
+    fn check_process_health<'py>(&mut self, py: Python<'py>) -> PyResult<()> {
+        let mut package_idx = 0;
+        while package_idx < self.proc_packages.len() {
+            if self.pid_idx_env_action_sent_instant_list[self.package_pid_idx_range(package_idx)]
+                .iter()
+                .all(Option::is_none)
+            {
+                package_idx += 1;
+                continue;
+            }
+            if let Some(err) = self.get_process_failure(py, package_idx)? {
+                let (proc_id, sub_env_proc_id_list) = self.retire_process(py, package_idx)?;
+                if !self.respawn_dead_processes {
+                    return Err(err);
+                }
+                self.dead_process_list
+                    .push((proc_id, err.to_string(), sub_env_proc_id_list));
+                continue;
+            }
+            package_idx += 1;
+        }
+        Ok(())
     }
 
-    fn get_space_types<'py>(&mut self, py: Python<'py>) -> PyResult<(PyObject, PyObject)> {
-        let (parent_end, shmem, _) = self.proc_packages.get_mut(0).unwrap();
-        let (ep_evt, used_bytes) = unsafe {
-            Event::from_existing(shmem.as_ptr()).map_err(|err| {
-                InvalidStateError::new_err(format!("Failed to get event: {}", err.to_string()))
-            })?
+    // Waits up to timeout for env processes to signal that their response is ready and receives the responses,
+    // returning their proc ids
+    fn wait_for_notifications<'py>(
+        &mut self,
+        py: Python<'py>,
+        timeout: Duration,
+    ) -> PyResult<Vec<String>> {
+        let ready_package_idx_list = if self.native_signalling || self.tcp_listener_option.is_some()
+        {
+            let fd_list = self
+                .proc_packages
+                .iter()
+                .map(|(_, transport, _)| {
+                    transport.poll_fd().ok_or_else(|| {
+                        InvalidStateError::new_err(
+                            "Tried to poll a transport without a file descriptor",
+                        )
+                    })
+                })
+                .collect::<PyResult<Vec<_>>>()?;
+            poll_fds(py, &fd_list, timeout)?
+        } else {
+            self.selector
+                .bind(py)
+                .call_method1(intern!(py, "select"), (timeout.as_secs_f64(),))?
+                .extract::<Vec<(PyObject, u8)>>()?
+                .into_iter()
+                .filter(|(_, event)| event & SELECTORS_EVENT_READ.get(py).unwrap() != 0)
+                .map(|(key, _)| {
+                    let (_, _, _, proc_id) =
+                        key.extract::<(PyObject, PyObject, PyObject, String)>(py)?;
+                    Ok(self.package_idx(&proc_id))
+                })
+                .collect::<PyResult<Vec<_>>>()?
         };
-        let shm_slice = unsafe { &mut shmem.as_slice_mut()[used_bytes..] };
-        append_header(shm_slice, 0, Header::EnvShapesRequest);
-        ep_evt
-            .set(EventState::Signaled)
-            .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-        recvfrom_byte(py, parent_end)?;
-        let mut offset = 0;
-        let obs_space;
-        (obs_space, offset) = self.obs_space_serde.retrieve(py, shm_slice, offset)?;
-        let action_space;
-        (action_space, _) = self.action_space_serde.retrieve(py, shm_slice, offset)?;
-        Ok((obs_space.unbind(), action_space.unbind()))
+        let mut proc_id_list = Vec::with_capacity(ready_package_idx_list.len());
+        for package_idx in ready_package_idx_list.into_iter() {
+            let (_, ref mut transport, ref proc_id) = self.proc_packages[package_idx];
+            if transport.recv_timeout(py, None)? {
+                proc_id_list.push(proc_id.clone());
+            }
+        }
+        Ok(proc_id_list)
     }
 
-    fn add_proc_package<'py>(
+    // Removes the process at package_idx from proc_packages and compacts all pid_idx-indexed state. Returns the
+    // removed package and the proc ids of its sub-envs.
+    fn remove_proc_package(&mut self, package_idx: usize) -> (ProcPackage, Vec<String>) {
+        let pid_idx_range = self.package_pid_idx_range(package_idx);
+        let proc_package = self.proc_packages.remove(package_idx);
+        let proc_id = &proc_package.2;
+        self.proc_id_step_latency_stats_map.remove(proc_id);
+        self.telemetry.remove_process(proc_id);
+        self.pending_env_rebuild_map.remove(proc_id);
+        self.pending_env_command_map.remove(proc_id);
+        let sub_env_proc_id_list = (0..self.n_envs_per_process)
+            .map(|sub_env_idx| sub_env_proc_id(proc_id, sub_env_idx, self.n_envs_per_process))
+            .collect::<Vec<_>>();
+        for sub_env_proc_id in sub_env_proc_id_list.iter() {
+            self.proc_id_pid_idx_map.remove(sub_env_proc_id);
+        }
+        for idx in self.proc_id_pid_idx_map.values_mut() {
+            if *idx >= pid_idx_range.end {
+                *idx -= self.n_envs_per_process;
+            }
+        }
+        self.pid_idx_current_env_action_list
+            .drain(pid_idx_range.clone());
+        self.pid_idx_current_agent_id_list
+            .drain(pid_idx_range.clone());
+        self.pid_idx_prev_timestep_id_list
+            .drain(pid_idx_range.clone());
+        self.pid_idx_timestep_id_rng_list
+            .drain(pid_idx_range.clone());
+        self.pid_idx_current_obs_list.drain(pid_idx_range.clone());
+        self.pid_idx_current_action_list
+            .drain(pid_idx_range.clone());
+        self.pid_idx_current_aald_list.drain(pid_idx_range.clone());
+        self.pid_idx_env_action_sent_instant_list
+            .drain(pid_idx_range);
+        // The initial obs of a process which hasn't been collected yet shouldn't be handed out after it is gone
+        self.added_process_obs_data_kv_list
+            .retain(|(py_proc_id, _)| !sub_env_proc_id_list.contains(&py_proc_id.to_string()));
+        self.added_process_state_info_kv_list
+            .retain(|(py_proc_id, _)| !sub_env_proc_id_list.contains(&py_proc_id.to_string()));
+        self.min_process_steps_per_inference =
+            min(self.min_process_steps_per_inference, max(self.n_envs(), 1));
+        (proc_package, sub_env_proc_id_list)
+    }
+
+    // Forcefully removes the process at package_idx, removing its shmem flink and compacting all pid_idx-indexed
+    // state. Returns the proc id of the removed process.
+    fn retire_process<'py>(
         &mut self,
         py: Python<'py>,
-        proc_package_def: (PyObject, PyObject, PyObject, String),
-    ) -> PyResult<()> {
-        let (_, parent_end, child_sockname, proc_id) = proc_package_def;
-        sync_with_env_process(py, &parent_end, &child_sockname)?;
-        let flink = get_flink(&self.flinks_folder[..], proc_id.as_str());
-        let shmem = ShmemConf::new()
-            .flink(flink.clone())
-            .open()
-            .map_err(|err| {
-                InvalidStateError::new_err(format!("Unable to open shmem flink {}: {}", flink, err))
-            })?;
-        self.selector.call_method1(
+        package_idx: usize,
+    ) -> PyResult<(String, Vec<String>)> {
+        let ((process_option, mut transport, proc_id), sub_env_proc_id_list) =
+            self.remove_proc_package(package_idx);
+        if let Some(process) = process_option {
+            let process = process.bind(py);
+            if process
+                .call_method0(intern!(py, "is_alive"))?
+                .extract::<bool>()?
+            {
+                process.call_method0(intern!(py, "terminate"))?;
+            }
+        }
+        if let Some(socket) = transport.py_socket() {
+            self.selector
+                .call_method1(py, intern!(py, "unregister"), (socket,))?;
+        }
+        // The child process would normally clean up its end of the transport on exit, so we take over to make sure
+        // it is cleaned up when it is dropped here
+        transport.take_ownership();
+        drop(transport);
+        remove_flink_lock(&self.flinks_folder, &proc_id);
+        Ok((proc_id, sub_env_proc_id_list))
+    }
+
+    // Asks the env process to stop and waits up to shutdown_timeout for it to acknowledge, first discarding the
+    // response to the env action in flight (if any). An env process which doesn't acknowledge may never clean up
+    // after itself, so we take over cleaning up its end of the transport. Returns whether it acknowledged.
+    fn stop_proc_package<'py>(
+        &self,
+        py: Python<'py>,
+        transport: &mut Box<dyn Transport>,
+        proc_id: &str,
+        env_action_in_flight: bool,
+    ) -> PyResult<bool> {
+        let acknowledged = (|| -> PyResult<bool> {
+            if env_action_in_flight
+                && (!transport.recv_timeout(py, self.shutdown_timeout_option)?
+                    || matches!(transport.retrieve_message_header()?.0, Header::EnvError))
+            {
+                return Ok(false);
+            }
+            transport.write_message(py, &mut |buf| Ok(append_header(buf, 0, Header::Stop)))?;
+            transport.send(py)?;
+            Ok(transport.recv_timeout(py, self.shutdown_timeout_option)?
+                && matches!(transport.retrieve_message_header()?.0, Header::Stop))
+        })()
+        .unwrap_or(false);
+        if let Some(socket) = transport.py_socket() {
+            self.selector
+                .call_method1(py, intern!(py, "unregister"), (socket,))?;
+        }
+        if !acknowledged {
+            transport.take_ownership();
+            remove_flink_lock(&self.flinks_folder, proc_id);
+        }
+        Ok(acknowledged)
+    }
+
+    // Exchanges one step of an env rebuild with the env process at package_idx. Building returns the obs and action
+    // spaces by agent id of each new env.
+    fn exchange_env_rebuild<'py>(
+        &mut self,
+        py: Python<'py>,
+        package_idx: usize,
+        step: EnvRebuildStep<'_>,
+    ) -> PyResult<Vec<(Bound<'py, PyDict>, Bound<'py, PyDict>)>> {
+        let (_, transport, proc_id) = &mut self.proc_packages[package_idx];
+        transport.write_message(py, &mut |buf| {
+            let mut offset = append_header(buf, 0, Header::EnvRebuild);
+            offset = append_bool_checked(
+                buf,
+                offset,
+                matches!(step, EnvRebuildStep::Build(..)),
+                "env rebuild",
+            )?;
+            match step {
+                EnvRebuildStep::Build(pickled_build_env_fn, seed) => {
+                    offset =
+                        append_bytes_checked(buf, offset, pickled_build_env_fn, "env build fn")?;
+                    offset = append_usize_checked(buf, offset, seed as usize, "env build fn seed")?;
+                }
+                EnvRebuildStep::Keep => {
+                    offset = append_bool_checked(buf, offset, true, "env rebuild decision")?;
+                }
+                EnvRebuildStep::Discard => {
+                    offset = append_bool_checked(buf, offset, false, "env rebuild decision")?;
+                }
+            }
+            Ok(offset)
+        })?;
+        transport.send(py)?;
+        if !transport.recv_timeout(py, self.rebuild_timeout_option)? {
+            return Err(timeout_err(
+                proc_id,
+                "env rebuild",
+                self.rebuild_timeout_option.unwrap(),
+            ));
+        }
+        let offset = retrieve_response_header(transport.as_mut(), proc_id, Header::EnvRebuild)?;
+        if !matches!(step, EnvRebuildStep::Build(..)) {
+            return Ok(Vec::new());
+        }
+        let shm_slice = transport.slice();
+        let (n_envs, mut offset) = retrieve_usize(shm_slice, offset)?;
+        let mut spaces_list = Vec::with_capacity(n_envs);
+        for _ in 0..n_envs {
+            let obs_spaces;
+            (obs_spaces, offset) = retrieve_space_dict(
+                py,
+                shm_slice,
+                offset,
+                self.agent_id_serde.as_ref(),
+                self.obs_space_serde.as_ref(),
+            )?;
+            let action_spaces;
+            (action_spaces, offset) = retrieve_space_dict(
+                py,
+                shm_slice,
+                offset,
+                self.agent_id_serde.as_ref(),
+                self.action_space_serde.as_ref(),
+            )?;
+            spaces_list.push((obs_spaces, action_spaces));
+        }
+        Ok(spaces_list)
+    }
+
+    // Sends the replacement env build function queued for the process at package_idx (if any), which must not have
+    // an env action in flight. If an agent of any of the new envs has different spaces than an agent with the same id
+    // had before (or has in another new env), the env process is told to discard them and keeps its current envs, and
+    // the error describing the mismatch is returned in Ok. Errs are failures of the exchange itself.
+    fn send_env_rebuild<'py>(
+        &mut self,
+        py: Python<'py>,
+        package_idx: usize,
+    ) -> PyResult<Option<PyErr>> {
+        let proc_id = &self.proc_packages[package_idx].2;
+        let Some((pickled_build_env_fn, seed)) =
+            self.pending_env_rebuild_map.remove(proc_id.as_str())
+        else {
+            return Ok(None);
+        };
+        let spaces_list = self.exchange_env_rebuild(
             py,
-            intern!(py, "register"),
-            (
-                parent_end.clone_ref(py),
-                SELECTORS_EVENT_READ.get_or_init(py, || {
-                    PyModule::import(py, "selectors")
-                        .unwrap()
-                        .getattr("EVENT_READ")
-                        .unwrap()
-                        .extract()
-                        .unwrap()
-                }),
-                self.proc_packages.len(),
-            ),
+            package_idx,
+            EnvRebuildStep::Build(&pickled_build_env_fn, seed),
         )?;
-        self.proc_id_pid_idx_map
-            .insert(proc_id.clone(), self.proc_packages.len());
-        self.proc_packages.push((parent_end, shmem, proc_id));
+        // The spaces of each new env are recorded once checked, so the new envs are checked against each other as
+        // well. They're only kept if all of the new envs match.
+        let prev_space_types_option = self
+            .space_types_option
+            .as_ref()
+            .map(|(obs_spaces, action_spaces)| {
+                PyResult::Ok((
+                    obs_spaces.bind(py).copy()?.unbind(),
+                    action_spaces.bind(py).copy()?.unbind(),
+                ))
+            })
+            .transpose()?;
+        for (sub_env_idx, (obs_spaces, action_spaces)) in spaces_list.iter().enumerate() {
+            let Some(mismatch) = self.find_space_types_mismatch(py, obs_spaces, action_spaces)?
+            else {
+                self.record_space_types(py, obs_spaces, action_spaces)?;
+                continue;
+            };
+            self.space_types_option = prev_space_types_option;
+            let err = PyValueError::new_err(format!(
+                "In the env with proc id {} rebuilt by its env process, {}. The env process keeps its current envs.",
+                sub_env_proc_id(
+                    &self.proc_packages[package_idx].2,
+                    sub_env_idx,
+                    self.n_envs_per_process
+                ),
+                mismatch
+            ));
+            self.exchange_env_rebuild(py, package_idx, EnvRebuildStep::Discard)?;
+            return Ok(Some(err));
+        }
+        self.exchange_env_rebuild(py, package_idx, EnvRebuildStep::Keep)?;
+        Ok(None)
+    }
 
+    // Sends the env commands queued for the process at package_idx (if any), which must not have an env action in
+    // flight, and keeps the replies of its sub-envs
+    fn send_env_commands<'py>(&mut self, py: Python<'py>, package_idx: usize) -> PyResult<()> {
+        let (_, transport, proc_id) = &mut self.proc_packages[package_idx];
+        let Some(env_command_list) = self.pending_env_command_map.remove(proc_id.as_str()) else {
+            return Ok(());
+        };
+        for (env_command_id, method_name, payload) in env_command_list.into_iter() {
+            transport.write_message(py, &mut |buf| {
+                let offset = append_header(buf, 0, Header::EnvCommand);
+                let offset = append_bytes_checked(
+                    buf,
+                    offset,
+                    method_name.as_bytes(),
+                    "env command handler name",
+                )?;
+                append_checked(
+                    &self.env_command_serde,
+                    buf,
+                    offset,
+                    payload.bind(py),
+                    "env command payload",
+                )
+            })?;
+            transport.send(py)?;
+            if !transport.recv_timeout(py, self.step_timeout_option)? {
+                return Err(timeout_err(
+                    proc_id,
+                    "env command",
+                    self.step_timeout_option.unwrap(),
+                ));
+            }
+            let offset = retrieve_response_header(transport.as_mut(), proc_id, Header::EnvCommand)?;
+            let shm_slice = transport.slice();
+            let (n_envs, mut offset) = retrieve_usize(shm_slice, offset)?;
+            for sub_env_idx in 0..n_envs {
+                let env_id = sub_env_proc_id(proc_id, sub_env_idx, self.n_envs_per_process);
+                let handled;
+                (handled, offset) = retrieve_bool(shm_slice, offset)?;
+                if !handled {
+                    let env_error;
+                    (env_error, offset) = retrieve_env_error(shm_slice, offset)?;
+                    let err = env_error.into_py_err(&env_id).into_value(py).into_any();
+                    self.env_command_reply_list
+                        .push((env_command_id, env_id, None, Some(err)));
+                    continue;
+                }
+                let has_reply;
+                (has_reply, offset) = retrieve_bool(shm_slice, offset)?;
+                let reply_option = if has_reply {
+                    let reply;
+                    (reply, offset) = self.env_command_serde.retrieve(py, shm_slice, offset)?;
+                    Some(reply.unbind())
+                } else {
+                    None
+                };
+                self.env_command_reply_list
+                    .push((env_command_id, env_id, reply_option, None));
+            }
+        }
         Ok(())
     }
 
+    // Decodes the agent data of the responses in parallel without the GIL, for the responses where the agent id, obs
+    // and reward serdes allow it. Responses which can't be decoded this way get None and are decoded in
+    // collect_response instead.
+    fn decode_raw_agent_data<'py>(
+        &self,
+        py: Python<'py>,
+        pid_idx_offset_list: &[(usize, usize)],
+    ) -> PyResult<Vec<Option<RawAgentData>>> {
+        let obs_decoder = match self.obs_raw_decoder_option {
+            Some(obs_decoder) if pid_idx_offset_list.len() > 1 => obs_decoder,
+            _ => return Ok(pid_idx_offset_list.iter().map(|_| None).collect()),
+        };
+        let mut job_option_list = Vec::with_capacity(pid_idx_offset_list.len());
+        for &(pid_idx, offset) in pid_idx_offset_list.iter() {
+            let env_action = self.pid_idx_current_env_action_list[pid_idx]
+                .as_ref()
+                .ok_or_else(|| {
+                    InvalidStateError::new_err(
+                        "Tried to collect response from env which doesn't have an env action yet",
+                    )
+                })?;
+            let is_step_action = matches!(env_action, EnvAction::STEP { .. });
+            let new_episode = !is_step_action;
+            let agent_id_decoder_option = if self.recalculate_agent_id_every_step || new_episode {
+                match self.agent_id_raw_decoder_option {
+                    Some(agent_id_decoder) => Some(agent_id_decoder),
+                    None => {
+                        job_option_list.push(None);
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            let reward_decoder_option = if is_step_action {
+                match self.reward_raw_decoder_option {
+                    Some(reward_decoder) => Some(reward_decoder),
+                    None => {
+                        job_option_list.push(None);
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            let shm_slice = self.proc_packages[pid_idx / self.n_envs_per_process]
+                .1
+                .slice();
+            let offset = if is_step_action {
+                let (agents_changed, offset) = retrieve_bool(shm_slice, offset)?;
+                if agents_changed {
+                    job_option_list.push(None);
+                    continue;
+                }
+                offset
+            } else {
+                offset
+            };
+            let (n_agents, offset) = if new_episode {
+                retrieve_usize(shm_slice, offset)?
+            } else {
+                (
+                    self.pid_idx_current_agent_id_list[pid_idx]
+                        .as_ref()
+                        .unwrap()
+                        .len(),
+                    offset,
+                )
+            };
+            let decoder = RawAgentDataDecoder {
+                agent_id_decoder_option,
+                obs_decoder,
+                reward_decoder_option,
+            };
+            job_option_list.push(Some((decoder, shm_slice, offset, n_agents)));
+        }
+        py.allow_threads(|| {
+            job_option_list
+                .into_par_iter()
+                .map(|job_option| {
+                    job_option
+                        .map(|(decoder, shm_slice, offset, n_agents)| {
+                            decoder.decode(shm_slice, offset, n_agents)
+                        })
+                        .transpose()
+                })
+                .collect()
+        })
+    }
+
     // Returns number of timesteps collected, plus three kv pairs: the keys are all the proc id,
     // and the values are (agent id list, obs list),
     // (timestep list, optional state metrics, optional state),
@@ -212,18 +1199,9 @@ impl EnvProcessInterface {
     fn collect_response(
         &mut self,
         pid_idx: usize,
-    ) -> PyResult<(
-        usize,
-        (PyObject, (Vec<PyObject>, Vec<PyObject>)),
-        (
-            PyObject,
-            (Vec<PyObject>, PyObject, Option<PyObject>, Option<PyObject>),
-        ),
-        (
-            PyObject,
-            (Option<PyObject>, Option<Py<PyDict>>, Option<Py<PyDict>>),
-        ),
-    )> {
+        offset: usize,
+        raw_agent_data_option: Option<RawAgentData>,
+    ) -> PyResult<EnvResponse> {
         let env_action = self.pid_idx_current_env_action_list[pid_idx]
             .as_ref()
             .ok_or_else(|| {
@@ -233,12 +1211,12 @@ impl EnvProcessInterface {
             })?;
         let is_step_action = matches!(env_action, EnvAction::STEP { .. });
         let new_episode = !is_step_action;
-        let (_, shmem, proc_id) = self.proc_packages.get(pid_idx).unwrap();
-        let evt_used_bytes = Event::size_of(None);
-        let shm_slice = unsafe { &shmem.as_slice()[evt_used_bytes..] };
-        let mut offset = 0;
+        let proc_id = &self.pid_idx_proc_id(pid_idx);
+        let transport = &self.proc_packages[pid_idx / self.n_envs_per_process].1;
+        let mut offset = offset;
+        let shm_slice = transport.slice();
         Python::with_gil(|py| {
-            let current_agent_id_list = self
+            let mut current_agent_id_list = self
                 .pid_idx_current_agent_id_list
                 .get_mut(pid_idx)
                 .unwrap()
@@ -255,51 +1233,153 @@ impl EnvProcessInterface {
                 mut truncated_list_option,
             );
 
-            if new_episode {
-                (n_agents, offset) = retrieve_usize(shm_slice, offset)?;
-                agent_id_list = Vec::with_capacity(n_agents);
+            // The agents which acted, and the index of each in agent_id_list (None if it left the env), when the
+            // agents changed during a step
+            let mut agent_change_option = None;
+
+            if let Some(raw_agent_data) = raw_agent_data_option {
+                n_agents = raw_agent_data.obs_list.len();
+                agent_id_list = match raw_agent_data.agent_id_list_option {
+                    Some(raw_agent_id_list) => raw_agent_id_list
+                        .into_iter()
+                        .map(|agent_id| agent_id.into_py_any(py))
+                        .collect::<PyResult<_>>()?,
+                    None => current_agent_id_list,
+                };
+                obs_list = raw_agent_data
+                    .obs_list
+                    .into_iter()
+                    .map(|obs| obs.into_py_any(py))
+                    .collect::<PyResult<_>>()?;
+                reward_list_option = raw_agent_data
+                    .reward_list_option
+                    .map(|raw_reward_list| {
+                        raw_reward_list
+                            .into_iter()
+                            .map(|reward| reward.into_py_any(py))
+                            .collect::<PyResult<Vec<_>>>()
+                    })
+                    .transpose()?;
+                terminated_list_option = raw_agent_data.terminated_list_option;
+                truncated_list_option = raw_agent_data.truncated_list_option;
+                offset = raw_agent_data.offset;
             } else {
-                n_agents = current_agent_id_list.len();
-                if self.recalculate_agent_id_every_step {
+                let agents_changed;
+                if is_step_action {
+                    (agents_changed, offset) = retrieve_bool(shm_slice, offset)?;
+                } else {
+                    agents_changed = false;
+                }
+                if new_episode || agents_changed {
+                    (n_agents, offset) = retrieve_usize(shm_slice, offset)?;
                     agent_id_list = Vec::with_capacity(n_agents);
                 } else {
-                    agent_id_list = current_agent_id_list;
+                    n_agents = current_agent_id_list.len();
+                    if self.recalculate_agent_id_every_step {
+                        agent_id_list = Vec::with_capacity(n_agents);
+                    } else {
+                        agent_id_list = std::mem::take(&mut current_agent_id_list);
+                    }
+                }
+                obs_list = Vec::with_capacity(n_agents);
+                if agents_changed {
+                    // The step results follow the new agents
+                    reward_list_option = None;
+                    terminated_list_option = None;
+                    truncated_list_option = None;
+                } else if is_step_action {
+                    reward_list_option = Some(Vec::with_capacity(n_agents));
+                    terminated_list_option = Some(Vec::with_capacity(n_agents));
+                    truncated_list_option = Some(Vec::with_capacity(n_agents));
+                } else {
+                    reward_list_option = None;
+                    terminated_list_option = None;
+                    truncated_list_option = None;
                 }
-            }
-            obs_list = Vec::with_capacity(n_agents);
-            if is_step_action {
-                reward_list_option = Some(Vec::with_capacity(n_agents));
-                terminated_list_option = Some(Vec::with_capacity(n_agents));
-                truncated_list_option = Some(Vec::with_capacity(n_agents));
-            } else {
-                reward_list_option = None;
-                terminated_list_option = None;
-                truncated_list_option = None;
-            }
 
-            // Populate lists
-            for _ in 0..n_agents {
-                if self.recalculate_agent_id_every_step || new_episode {
-                    let agent_id;
-                    (agent_id, offset) = self.agent_id_serde.retrieve(py, shm_slice, offset)?;
-                    agent_id_list.push(agent_id.unbind());
+                // Populate lists
+                for _ in 0..n_agents {
+                    if self.recalculate_agent_id_every_step || new_episode || agents_changed {
+                        let agent_id;
+                        (agent_id, offset) = self.agent_id_serde.retrieve(py, shm_slice, offset)?;
+                        agent_id_list.push(agent_id.unbind());
+                    }
+                    let obs;
+                    (obs, offset) = self.obs_serde.retrieve(py, shm_slice, offset)?;
+                    obs_list.push(obs.unbind());
+                    if is_step_action && !agents_changed {
+                        let reward;
+                        (reward, offset) = self.reward_serde.retrieve(py, shm_slice, offset)?;
+                        reward_list_option.as_mut().unwrap().push(reward.unbind());
+                        let terminated;
+                        (terminated, offset) = retrieve_bool(shm_slice, offset)?;
+                        terminated_list_option.as_mut().unwrap().push(terminated);
+                        let truncated;
+                        (truncated, offset) = retrieve_bool(shm_slice, offset)?;
+                        truncated_list_option.as_mut().unwrap().push(truncated);
+                    }
                 }
-                let obs;
-                (obs, offset) = self.obs_serde.retrieve(py, shm_slice, offset)?;
-                obs_list.push(obs.unbind());
-                if is_step_action {
-                    let reward;
-                    (reward, offset) = self.reward_serde.retrieve(py, shm_slice, offset)?;
-                    reward_list_option.as_mut().unwrap().push(reward.unbind());
-                    let terminated;
-                    (terminated, offset) = retrieve_bool(shm_slice, offset)?;
-                    terminated_list_option.as_mut().unwrap().push(terminated);
-                    let truncated;
-                    (truncated, offset) = retrieve_bool(shm_slice, offset)?;
-                    truncated_list_option.as_mut().unwrap().push(truncated);
+                if agents_changed {
+                    let n_prev_agents = current_agent_id_list.len();
+                    let mut new_agent_idx_option_list = Vec::with_capacity(n_prev_agents);
+                    let mut reward_list = Vec::with_capacity(n_prev_agents);
+                    let mut terminated_list = Vec::with_capacity(n_prev_agents);
+                    let mut truncated_list = Vec::with_capacity(n_prev_agents);
+                    for _ in 0..n_prev_agents {
+                        let present;
+                        (present, offset) = retrieve_bool(shm_slice, offset)?;
+                        if present {
+                            let new_agent_idx;
+                            (new_agent_idx, offset) = retrieve_usize(shm_slice, offset)?;
+                            new_agent_idx_option_list.push(Some(new_agent_idx));
+                        } else {
+                            new_agent_idx_option_list.push(None);
+                        }
+                        let reward;
+                        (reward, offset) = self.reward_serde.retrieve(py, shm_slice, offset)?;
+                        reward_list.push(reward.unbind());
+                        let terminated;
+                        (terminated, offset) = retrieve_bool(shm_slice, offset)?;
+                        terminated_list.push(terminated);
+                        let truncated;
+                        (truncated, offset) = retrieve_bool(shm_slice, offset)?;
+                        truncated_list.push(truncated);
+                    }
+                    reward_list_option = Some(reward_list);
+                    terminated_list_option = Some(terminated_list);
+                    truncated_list_option = Some(truncated_list);
+                    agent_change_option = Some((current_agent_id_list, new_agent_idx_option_list));
                 }
             }
 
+            // Timesteps and the terminated and truncated dicts are for the agents which acted
+            let step_agent_id_list = agent_change_option
+                .as_ref()
+                .map_or(&agent_id_list, |(prev_agent_id_list, _)| prev_agent_id_list);
+            // Agents which left the env during the step keep their last obs as their next obs
+            let changed_next_obs_list_option = agent_change_option
+                .as_ref()
+                .map(|(_, new_agent_idx_option_list)| {
+                    new_agent_idx_option_list
+                        .iter()
+                        .zip(self.pid_idx_current_obs_list[pid_idx].iter())
+                        .map(|(new_agent_idx_option, obs)| match new_agent_idx_option {
+                            Some(new_agent_idx) => obs_list
+                                .get(*new_agent_idx)
+                                .map(|next_obs| next_obs.clone_ref(py))
+                                .ok_or_else(|| {
+                                    InvalidStateError::new_err(format!(
+                                        "Env process sent agent index {} for env with {} agents",
+                                        new_agent_idx, n_agents
+                                    ))
+                                }),
+                            None => Ok(obs.clone_ref(py)),
+                        })
+                        .collect::<PyResult<Vec<_>>>()
+                })
+                .transpose()?;
+            let next_obs_list = changed_next_obs_list_option.as_ref().unwrap_or(&obs_list);
+
             let state_option;
             if self.send_state_to_agent_controllers {
                 let state;
@@ -330,8 +1410,8 @@ impl EnvProcessInterface {
             let mut timestep_list;
             if is_step_action {
                 let timestep_class = self.timestep_class.bind(py);
-                let mut timestep_id_list = Vec::with_capacity(n_agents);
-                timestep_list = Vec::with_capacity(n_agents);
+                let mut timestep_id_list = Vec::with_capacity(step_agent_id_list.len());
+                timestep_list = Vec::with_capacity(step_agent_id_list.len());
                 for (
                     prev_timestep_id,
                     agent_id,
@@ -343,15 +1423,15 @@ impl EnvProcessInterface {
                     &truncated,
                 ) in izip!(
                     self.pid_idx_prev_timestep_id_list.get(pid_idx).unwrap(),
-                    &agent_id_list,
+                    step_agent_id_list,
                     self.pid_idx_current_obs_list.get(pid_idx).unwrap(),
-                    &obs_list,
+                    next_obs_list,
                     &self.pid_idx_current_action_list[pid_idx],
                     reward_list_option.as_ref().unwrap(),
                     terminated_list_option.as_ref().unwrap(),
                     truncated_list_option.as_ref().unwrap()
                 ) {
-                    let timestep_id = fastrand::u128(..);
+                    let timestep_id = self.pid_idx_timestep_id_rng_list[pid_idx].u128(..);
                     timestep_id_list.push(Some(timestep_id));
                     timestep_list.push(
                         timestep_class
@@ -383,10 +1463,10 @@ impl EnvProcessInterface {
                 terminated_dict_option = None;
                 truncated_dict_option = None;
             } else {
-                let mut terminated_kv_list = Vec::with_capacity(n_agents);
-                let mut truncated_kv_list = Vec::with_capacity(n_agents);
+                let mut terminated_kv_list = Vec::with_capacity(step_agent_id_list.len());
+                let mut truncated_kv_list = Vec::with_capacity(step_agent_id_list.len());
                 for (agent_id, terminated, truncated) in izip!(
-                    &agent_id_list,
+                    step_agent_id_list,
                     terminated_list_option.unwrap(),
                     truncated_list_option.unwrap()
                 ) {
@@ -401,7 +1481,19 @@ impl EnvProcessInterface {
 
             // Set prev_timestep_id_list for proc
             let prev_timestep_id_list = &mut self.pid_idx_prev_timestep_id_list[pid_idx];
-            if is_step_action {
+            if let Some((_, new_agent_idx_option_list)) = &agent_change_option {
+                // Agents which joined the env start new trajectories
+                prev_timestep_id_list.clear();
+                prev_timestep_id_list.resize(n_agents, None);
+                for (new_agent_idx_option, timestep_id) in new_agent_idx_option_list
+                    .iter()
+                    .zip(timestep_id_list_option.unwrap())
+                {
+                    if let Some(new_agent_idx) = new_agent_idx_option {
+                        prev_timestep_id_list[*new_agent_idx] = timestep_id;
+                    }
+                }
+            } else if is_step_action {
                 prev_timestep_id_list.clear();
                 prev_timestep_id_list.append(&mut timestep_id_list_option.unwrap());
             } else if let EnvAction::SET_STATE {
@@ -452,36 +1544,106 @@ impl EnvProcessInterface {
 #[pymethods]
 impl EnvProcessInterface {
     #[new]
-    #[pyo3(signature = (
-        agent_id_serde,
-        action_serde,
-        obs_serde,
-        reward_serde,
-        obs_space_serde,
-        action_space_serde,
-        state_serde_option,
-        state_metrics_serde_option,
-        recalculate_agent_id_every_step,
-        flinks_folder,
-        min_process_steps_per_inference,
-        send_state_to_agent_controllers,
-        should_collect_state_metrics,
-        ))]
     pub fn new(
-        agent_id_serde: Box<dyn PyAnySerde>,
-        action_serde: Box<dyn PyAnySerde>,
-        obs_serde: Box<dyn PyAnySerde>,
-        reward_serde: Box<dyn PyAnySerde>,
-        obs_space_serde: Box<dyn PyAnySerde>,
-        action_space_serde: Box<dyn PyAnySerde>,
-        state_serde_option: DynPyAnySerdeOption,
-        state_metrics_serde_option: DynPyAnySerdeOption,
-        recalculate_agent_id_every_step: bool,
-        flinks_folder: String,
-        min_process_steps_per_inference: usize,
-        send_state_to_agent_controllers: bool,
-        should_collect_state_metrics: bool,
+        serde_type_config: SerdeTypeConfig,
+        env_process_interface_config: EnvProcessInterfaceConfig,
+        timeout_config: TimeoutConfig,
+        transport_config: TransportConfig,
+        collection_config: CollectionConfig,
     ) -> PyResult<Self> {
+        let SerdeTypeConfig {
+            agent_id_serde,
+            action_serde,
+            obs_serde,
+            reward_serde,
+            obs_space_serde,
+            action_space_serde,
+            state_serde_option,
+            state_metrics_serde_option,
+        } = serde_type_config;
+        let EnvProcessInterfaceConfig {
+            flinks_folder,
+            min_process_steps_per_inference,
+            send_state_to_agent_controllers,
+            should_collect_state_metrics,
+            recalculate_agent_id_every_step,
+            n_envs_per_process,
+            seed_option,
+        } = env_process_interface_config;
+        let TransportConfig {
+            tcp_address: tcp_address_option,
+            tcp_token: tcp_token_option,
+            in_process,
+            native_signalling,
+            sweep_orphaned_flinks,
+            external_processes,
+            ..
+        } = transport_config;
+        if n_envs_per_process == 0 {
+            return Err(PyValueError::new_err(
+                "n_envs_per_process must be at least 1",
+            ));
+        }
+        if external_processes && tcp_address_option.is_none() {
+            return Err(PyValueError::new_err(
+                "Env processes started elsewhere can only connect over TCP",
+            ));
+        }
+        if sweep_orphaned_flinks {
+            let n_removed = flink_lock::sweep_orphaned_flinks(&flinks_folder)?;
+            if n_removed > 0 {
+                println!(
+                    "Removed {} orphaned files from flinks folder {}",
+                    n_removed, flinks_folder
+                );
+            }
+        }
+        let handshake = Handshake::new(
+            vec![
+                ("agent_id_serde", agent_id_serde.fingerprint),
+                ("action_serde", action_serde.fingerprint),
+                ("obs_serde", obs_serde.fingerprint),
+                ("reward_serde", reward_serde.fingerprint),
+                ("obs_space_serde", obs_space_serde.fingerprint),
+                ("action_space_serde", action_space_serde.fingerprint),
+                ("state_serde", state_serde_option.fingerprint),
+                (
+                    "state_metrics_serde",
+                    state_metrics_serde_option.fingerprint,
+                ),
+            ],
+            vec![
+                ("n_envs_per_process", n_envs_per_process.to_string()),
+                (
+                    "transport",
+                    transport_name(tcp_address_option.as_deref(), in_process)?.to_string(),
+                ),
+            ],
+        );
+        let tcp_listener_option = tcp_address_option
+            .as_deref()
+            .map(|tcp_address| {
+                TcpTransportListener::bind(
+                    tcp_address,
+                    required_tcp_token(tcp_token_option.as_deref())?,
+                )
+            })
+            .transpose()?;
+        let state_serde_option = Option::<CheckedSerde>::from(state_serde_option);
+        let state_metrics_serde_option = state_metrics_serde_option.value;
+        // Agent data using these serde types can be decoded in parallel without the GIL
+        let agent_id_raw_decoder_option = agent_id_serde
+            .serde_type_option
+            .as_ref()
+            .and_then(RawDecoder::from_serde_type);
+        let obs_raw_decoder_option = obs_serde
+            .serde_type_option
+            .as_ref()
+            .and_then(RawDecoder::from_serde_type);
+        let reward_raw_decoder_option = reward_serde
+            .serde_type_option
+            .as_ref()
+            .and_then(RawDecoder::from_serde_type);
         Python::with_gil::<_, PyResult<Self>>(|py| {
             let timestep_class = PyModule::import(py, "rlgym_learn.experience.timestep")?
                 .getattr("Timestep")?
@@ -490,9 +1652,7 @@ impl EnvProcessInterface {
                 .getattr("DefaultSelector")?
                 .call0()?
                 .unbind();
-            if send_state_to_agent_controllers
-                && matches!(state_serde_option, DynPyAnySerdeOption::None)
-            {
+            if send_state_to_agent_controllers && state_serde_option.is_none() {
                 return Err(PyValueError::new_err(
                     "state_serde must be passed in order to send state to agent controllers",
                 ));
@@ -505,29 +1665,62 @@ impl EnvProcessInterface {
                 ));
             }
             Ok(EnvProcessInterface {
-                agent_id_serde,
-                action_serde,
-                obs_serde,
-                reward_serde,
-                obs_space_serde,
-                action_space_serde,
-                state_serde_option: state_serde_option.into(),
+                agent_id_serde: agent_id_serde.value,
+                action_serde: action_serde.into(),
+                obs_serde: obs_serde.value,
+                reward_serde: reward_serde.value,
+                obs_space_serde: obs_space_serde.value,
+                action_space_serde: action_space_serde.value,
+                state_serde_option,
                 state_metrics_serde_option: state_metrics_serde_option.into(),
+                agent_id_raw_decoder_option,
+                obs_raw_decoder_option,
+                reward_raw_decoder_option,
                 recalculate_agent_id_every_step,
                 flinks_folder,
+                handshake,
+                n_envs_per_process,
                 proc_packages: Vec::new(),
+                tcp_listener_option,
+                in_process,
+                lockstep: collection_config.lockstep,
                 min_process_steps_per_inference,
+                min_process_steps_tuner_option: collection_config
+                    .auto_tune_window
+                    .map(MinProcessStepsTuner::new),
                 send_state_to_agent_controllers,
                 should_collect_state_metrics,
+                startup_timeout_option: timeout_config.startup,
+                env_shapes_timeout_option: timeout_config.env_shapes,
+                step_timeout_option: timeout_config.step,
+                rebuild_timeout_option: timeout_config.rebuild,
+                shutdown_timeout_option: timeout_config.shutdown,
+                respawn_dead_processes: collection_config.respawn_dead_processes,
+                native_signalling,
+                seed_option,
                 selector,
                 timestep_class,
+                space_types_option: None,
+                pending_env_rebuild_map: HashMap::new(),
+                env_command_serde: CheckedSerde::new(
+                    Box::new(PickleSerde::new()?),
+                    Some(PyAnySerdeType::PICKLE {}),
+                ),
+                next_env_command_id: 0,
+                pending_env_command_map: HashMap::new(),
+                env_command_reply_list: Vec::new(),
                 proc_id_pid_idx_map: HashMap::new(),
                 pid_idx_current_env_action_list: Vec::new(),
                 pid_idx_current_agent_id_list: Vec::new(),
                 pid_idx_prev_timestep_id_list: Vec::new(),
+                pid_idx_timestep_id_rng_list: Vec::new(),
                 pid_idx_current_obs_list: Vec::new(),
                 pid_idx_current_action_list: Vec::new(),
                 pid_idx_current_aald_list: Vec::new(),
+                pid_idx_env_action_sent_instant_list: Vec::new(),
+                proc_id_step_latency_stats_map: HashMap::new(),
+                telemetry: Telemetry::default(),
+                dead_process_list: Vec::new(),
                 added_process_obs_data_kv_list: Vec::new(),
                 added_process_state_info_kv_list: Vec::new(),
             })
@@ -542,101 +1735,259 @@ impl EnvProcessInterface {
     // ObsSpaceType,
     // ActionSpaceType
     // )
+    // Over TCP, n_external_processes env processes started elsewhere are also waited for after the given ones
+    #[pyo3(signature = (proc_package_defs, n_external_processes=0))]
     fn init_processes(
         &mut self,
-        proc_package_defs: Vec<(PyObject, PyObject, PyObject, String)>,
+        proc_package_defs: Vec<ProcPackageDef>,
+        n_external_processes: usize,
     ) -> PyResult<(Py<PyDict>, Py<PyDict>, PyObject, PyObject)> {
+        if n_external_processes > 0 && self.tcp_listener_option.is_none() {
+            return Err(PyValueError::new_err(
+                "Env processes started elsewhere can only connect over TCP",
+            ));
+        }
         Python::with_gil(|py| {
-            proc_package_defs
-                .into_iter()
-                .try_for_each::<_, PyResult<()>>(|proc_package_def| {
-                    self.add_proc_package(py, proc_package_def)
-                })?;
+            for proc_package_def in proc_package_defs.into_iter() {
+                self.add_proc_package(py, Some(proc_package_def))?;
+            }
+            for _ in 0..n_external_processes {
+                self.add_proc_package(py, None)?;
+            }
             let (initial_obs_data_dict, initial_state_info_dict) =
                 self.update_with_initial_obs(py)?;
-            let n_procs = self.proc_packages.len();
+            let n_envs = self.n_envs();
             self.min_process_steps_per_inference =
-                min(self.min_process_steps_per_inference, n_procs);
-            for _ in 0..n_procs {
+                min(self.min_process_steps_per_inference, n_envs);
+            for _ in 0..n_envs {
                 self.pid_idx_current_env_action_list.push(None);
                 self.pid_idx_current_action_list.push(Vec::new());
                 self.pid_idx_current_aald_list.push(None);
+                self.pid_idx_env_action_sent_instant_list.push(None);
+            }
+            // Every env has just been reset. Agents with the same id must have the same spaces in every env.
+            for pid_idx in 0..n_envs {
+                let (obs_spaces, action_spaces) = self.request_space_types(py, pid_idx)?.unwrap();
+                if let Some(mismatch) =
+                    self.find_space_types_mismatch(py, &obs_spaces, &action_spaces)?
+                {
+                    return Err(PyValueError::new_err(format!(
+                        "In the env with proc id {}, {}",
+                        sub_env_proc_id(
+                            &self.proc_packages[pid_idx / self.n_envs_per_process].2,
+                            pid_idx % self.n_envs_per_process,
+                            self.n_envs_per_process
+                        ),
+                        mismatch
+                    )));
+                }
+                self.record_space_types(py, &obs_spaces, &action_spaces)?;
             }
-            let (obs_space, action_space) = self.get_space_types(py)?;
+            let (obs_spaces, action_spaces) = self.space_types_option.as_ref().unwrap();
 
             Ok((
                 initial_obs_data_dict,
                 initial_state_info_dict,
-                obs_space,
-                action_space,
+                obs_spaces.bind(py).copy()?.into_any().unbind(),
+                action_spaces.bind(py).copy()?.into_any().unbind(),
             ))
         })
     }
 
-    pub fn add_process(
-        &mut self,
-        proc_package_def: (PyObject, PyObject, PyObject, String),
-    ) -> PyResult<()> {
+    pub fn add_process(&mut self, proc_package_def: ProcPackageDef) -> PyResult<()> {
+        Python::with_gil(|py| self.add_initialized_proc_package(py, Some(proc_package_def)))?;
+        Ok(())
+    }
+
+    // Waits for an env process started elsewhere to connect over TCP and adds it. Returns its proc id.
+    pub fn add_external_process(&mut self) -> PyResult<String> {
+        if self.tcp_listener_option.is_none() {
+            return Err(PyValueError::new_err(
+                "Env processes started elsewhere can only connect over TCP",
+            ));
+        }
+        Python::with_gil(|py| self.add_initialized_proc_package(py, None))
+    }
+
+    // The proc ids of the env processes, in the order they were added
+    pub fn env_process_proc_ids(&self) -> Vec<String> {
+        self.proc_packages
+            .iter()
+            .map(|(_, _, proc_id)| proc_id.clone())
+            .collect()
+    }
+
+    // The proc id of the env process with the given proc id, or of the one hosting the env with the given proc id
+    pub fn env_process_proc_id(&self, proc_id: String) -> PyResult<String> {
+        Ok(self.proc_packages[self.find_package_idx(&proc_id)?]
+            .2
+            .clone())
+    }
+
+    // Stops the env process with the given proc id (or hosting the env with the given proc id, or the last one if
+    // None) and compacts all pid_idx-indexed state. Any env action in flight for it is abandoned. Returns the proc
+    // ids of its sub-envs, so the caller can finalize their trajectories.
+    #[pyo3(signature = (proc_id_option=None))]
+    pub fn delete_process(&mut self, proc_id_option: Option<String>) -> PyResult<Vec<String>> {
+        let package_idx = match proc_id_option {
+            Some(proc_id) => self.find_package_idx(&proc_id)?,
+            None => self.proc_packages.len().checked_sub(1).ok_or_else(|| {
+                InvalidStateError::new_err("There are no env processes to delete")
+            })?,
+        };
+        let env_action_in_flight = self.pid_idx_env_action_sent_instant_list
+            [self.package_pid_idx_range(package_idx)]
+        .iter()
+        .any(Option::is_some);
+        let ((_, mut transport, proc_id), sub_env_proc_id_list) =
+            self.remove_proc_package(package_idx);
         Python::with_gil(|py| {
-            let pid_idx = self.proc_packages.len();
-            self.add_proc_package(py, proc_package_def)?;
-            let ((py_proc_id, (agent_id_list, obs_list)), state_info_kv) =
-                self.get_initial_obs_data_proc(py, pid_idx)?;
-            let n_agents = agent_id_list.len();
-            self.pid_idx_current_agent_id_list
-                .push(Some(clone_list(py, &agent_id_list)));
-            self.pid_idx_current_obs_list
-                .push(clone_list(py, &obs_list));
-            self.pid_idx_prev_timestep_id_list
-                .push(vec![None; n_agents]);
-            self.pid_idx_current_env_action_list.push(None);
-            self.pid_idx_current_action_list
-                .push(Vec::with_capacity(n_agents));
-            self.pid_idx_current_aald_list.push(None);
-            self.added_process_obs_data_kv_list
-                .push((py_proc_id, (agent_id_list, obs_list)));
-            self.added_process_state_info_kv_list.push(state_info_kv);
-            Ok(())
+            if !self.stop_proc_package(py, &mut transport, &proc_id, env_action_in_flight)? {
+                println!("Env process {} did not acknowledge the stop", proc_id);
+            }
+            Ok(sub_env_proc_id_list)
         })
     }
 
-    pub fn delete_process(&mut self) -> PyResult<()> {
-        let (parent_end, mut shmem, proc_id) = self.proc_packages.pop().unwrap();
-        self.proc_id_pid_idx_map.remove(&proc_id);
-        let (ep_evt, used_bytes) = unsafe {
-            Event::from_existing(shmem.as_ptr()).map_err(|err| {
-                InvalidStateError::new_err(format!("Failed to get event: {}", err.to_string()))
-            })?
+    // Queues a replacement env build function for each given env process (by its proc id or that of any of its envs),
+    // which is sent along with the next env actions for that process. Each env process builds its new envs right away,
+    // checks their spaces with the EnvProcessInterface and switches each env over at the end of its current episode.
+    pub fn queue_env_rebuild(
+        &mut self,
+        build_env_fn: PyObject,
+        proc_id_seed_list: Vec<(String, u64)>,
+    ) -> PyResult<()> {
+        let pickled_build_env_fn = Python::with_gil(|py| {
+            PyModule::import(py, "pickle")?
+                .call_method1(intern!(py, "dumps"), (build_env_fn,))?
+                .extract::<Vec<u8>>()
+        })?;
+        for (proc_id, seed) in proc_id_seed_list.into_iter() {
+            let package_idx = self.find_package_idx(&proc_id)?;
+            self.pending_env_rebuild_map.insert(
+                self.proc_packages[package_idx].2.clone(),
+                (pickled_build_env_fn.clone(), seed),
+            );
+        }
+        Ok(())
+    }
+
+    // Queues an env command for each given env process (by its proc id or that of any of its envs, all of them if
+    // None), which is sent along with the next env actions for that process. The env process calls the handler method
+    // with the given name on each of its envs, passing the payload. Returns the id of the env command, which its
+    // replies are reported with.
+    #[pyo3(signature = (method_name, payload, proc_id_list_option=None))]
+    pub fn queue_env_command(
+        &mut self,
+        method_name: String,
+        payload: PyObject,
+        proc_id_list_option: Option<Vec<String>>,
+    ) -> PyResult<usize> {
+        let proc_id_list = match proc_id_list_option {
+            Some(proc_id_list) => proc_id_list,
+            None => self
+                .proc_packages
+                .iter()
+                .map(|(_, _, proc_id)| proc_id.clone())
+                .collect(),
         };
-        let shm_slice = unsafe { &mut shmem.as_slice_mut()[used_bytes..] };
-        append_header(shm_slice, 0, Header::Stop);
-        ep_evt
-            .set(EventState::Signaled)
-            .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-        self.pid_idx_current_agent_id_list.pop();
-        self.pid_idx_prev_timestep_id_list.pop();
-        self.pid_idx_current_obs_list.pop();
-        self.pid_idx_current_env_action_list.pop();
-        self.pid_idx_current_action_list.pop();
-        self.pid_idx_current_aald_list.pop();
-        self.added_process_state_info_kv_list
-            .retain(|(py_proc_id, _)| py_proc_id.to_string() != proc_id);
-        self.min_process_steps_per_inference = min(
-            self.min_process_steps_per_inference,
-            self.proc_packages.len().try_into().unwrap(),
-        );
+        // Every env of a process handles the command, so it's only sent once to each process
+        let mut package_idx_list = proc_id_list
+            .iter()
+            .map(|proc_id| self.find_package_idx(proc_id))
+            .collect::<PyResult<Vec<_>>>()?;
+        package_idx_list.sort_unstable();
+        package_idx_list.dedup();
+        let env_command_id = self.next_env_command_id;
         Python::with_gil(|py| {
-            self.selector
-                .call_method1(py, intern!(py, "unregister"), (parent_end,))?;
-            Ok(())
+            for package_idx in package_idx_list.into_iter() {
+                let proc_id = self.proc_packages[package_idx].2.clone();
+                self.pending_env_command_map
+                    .entry(proc_id)
+                    .or_default()
+                    .push((env_command_id, method_name.clone(), payload.clone_ref(py)));
+            }
+        });
+        self.next_env_command_id += 1;
+        Ok(env_command_id)
+    }
+
+    // Returns the (env command id, proc id, optional reply, optional EnvProcessError) of each env handling an env
+    // command since the last call. The error is set instead of the reply if the handler method raised.
+    pub fn take_env_command_replies(&mut self) -> Vec<EnvCommandReply> {
+        self.env_command_reply_list.drain(..).collect()
+    }
+
+    // Returns the current obs and action spaces by agent id of the env with the given proc id. The env must be at an
+    // episode boundary (its last collected step data was from a reset or set state), and its process must not have
+    // an env action in flight.
+    pub fn get_space_types(&mut self, proc_id: String) -> PyResult<(PyObject, PyObject)> {
+        let &pid_idx = self
+            .proc_id_pid_idx_map
+            .get(&proc_id)
+            .ok_or_else(|| PyValueError::new_err(format!("No env with proc id {}", proc_id)))?;
+        let package_idx = pid_idx / self.n_envs_per_process;
+        if self.pid_idx_env_action_sent_instant_list[self.package_pid_idx_range(package_idx)]
+            .iter()
+            .any(Option::is_some)
+        {
+            return Err(InvalidStateError::new_err(format!(
+                "Can't request spaces of env with proc id {} while its env process has an env action in flight",
+                proc_id
+            )));
+        }
+        Python::with_gil(|py| {
+            let (obs_spaces, action_spaces) =
+                self.request_space_types(py, pid_idx)?.ok_or_else(|| {
+                    InvalidStateError::new_err(format!(
+                        "Env with proc id {} is not at an episode boundary, so its spaces can't be requested",
+                        proc_id
+                    ))
+                })?;
+            self.record_space_types(py, &obs_spaces, &action_spaces)?;
+            Ok((
+                obs_spaces.into_any().unbind(),
+                action_spaces.into_any().unbind(),
+            ))
         })
     }
 
+    // The measurements behind the last adjustment of min_process_steps_per_inference, if it is being tuned automatically
+    pub fn min_process_steps_tuner_metrics(&self) -> Option<HashMap<String, f64>> {
+        self.min_process_steps_tuner_option
+            .as_ref()
+            .map(|tuner| tuner.metrics().clone())
+    }
+
+    // Counters and timings of collection (including the env call timings reported by env processes), with times in
+    // seconds. Per-process env call timings are under "processes", by proc id of env process.
+    pub fn get_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.telemetry.to_py_dict(py)
+    }
+
+    // Step latency statistics by proc id of env process
+    pub fn step_latency_stats(&self) -> HashMap<String, StepLatencyStats> {
+        self.proc_id_step_latency_stats_map.clone()
+    }
+
+    // The address env processes should connect to when using the TCP transport
+    pub fn transport_address(&self) -> PyResult<Option<String>> {
+        self.tcp_listener_option
+            .as_ref()
+            .map(TcpTransportListener::address)
+            .transpose()
+    }
+
+    // Returns the (proc id, reason, proc ids of sub-envs) of processes retired since the last call.
+    // Only populated when respawn_dead_processes is set; the caller is responsible for joining and replacing them, and
+    // for finalizing the trajectories of their sub-envs.
+    pub fn take_dead_processes(&mut self) -> Vec<(String, String, Vec<String>)> {
+        self.dead_process_list.drain(..).collect()
+    }
+
     pub fn increase_min_process_steps_per_inference(&mut self) -> usize {
-        self.min_process_steps_per_inference = min(
-            self.min_process_steps_per_inference + 1,
-            self.proc_packages.len().try_into().unwrap(),
-        );
+        self.min_process_steps_per_inference =
+            min(self.min_process_steps_per_inference + 1, self.n_envs());
         self.min_process_steps_per_inference
     }
 
@@ -645,34 +1996,36 @@ impl EnvProcessInterface {
         self.min_process_steps_per_inference
     }
 
-    pub fn cleanup(&mut self) -> PyResult<()> {
-        while let Some(proc_package) = self.proc_packages.pop() {
-            let (parent_end, mut shmem, _) = proc_package;
-            let (ep_evt, used_bytes) = unsafe {
-                Event::from_existing(shmem.as_ptr()).map_err(|err| {
-                    InvalidStateError::new_err(format!("Failed to get event: {}", err.to_string()))
-                })?
-            };
-            let shm_slice = unsafe { &mut shmem.as_slice_mut()[used_bytes..] };
-            append_header(shm_slice, 0, Header::Stop);
-            ep_evt
-                .set(EventState::Signaled)
-                .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-            Python::with_gil(|py| {
-                self.selector
-                    .call_method1(py, intern!(py, "unregister"), (parent_end,))
-            })?;
-            // This sleep seems to be needed for the shared memory to get set/read correctly
-            thread::sleep(Duration::from_millis(1));
-        }
+    // Stops every env process, returning the proc ids of those which didn't acknowledge the stop in time. These
+    // should be terminated.
+    pub fn cleanup(&mut self) -> PyResult<Vec<String>> {
+        let mut unacknowledged_proc_id_list = Vec::new();
+        Python::with_gil(|py| {
+            while let Some((_, mut transport, proc_id)) = self.proc_packages.pop() {
+                // The pid_idx-indexed state may not exist yet if initialization failed
+                let env_action_in_flight = self
+                    .pid_idx_env_action_sent_instant_list
+                    .get(self.package_pid_idx_range(self.proc_packages.len()))
+                    .is_some_and(|sent_instant_list| sent_instant_list.iter().any(Option::is_some));
+                if !self.stop_proc_package(py, &mut transport, &proc_id, env_action_in_flight)? {
+                    unacknowledged_proc_id_list.push(proc_id);
+                }
+            }
+            Ok::<_, PyErr>(())
+        })?;
         self.proc_id_pid_idx_map.clear();
+        self.proc_id_step_latency_stats_map.clear();
+        self.pending_env_rebuild_map.clear();
+        self.pending_env_command_map.clear();
         self.pid_idx_current_agent_id_list.clear();
         self.pid_idx_prev_timestep_id_list.clear();
+        self.pid_idx_timestep_id_rng_list.clear();
         self.pid_idx_current_obs_list.clear();
         self.pid_idx_current_action_list.clear();
         self.pid_idx_current_aald_list.clear();
+        self.pid_idx_env_action_sent_instant_list.clear();
         self.added_process_state_info_kv_list.clear();
-        Ok(())
+        Ok(unacknowledged_proc_id_list)
     }
 
     // Returns: (
@@ -681,7 +2034,16 @@ impl EnvProcessInterface {
     // Dict of timesteps, state metrics, and state by proc id
     // Dict of state, terminated dict, and truncated dict by proc id
     // )
-    pub fn collect_step_data(&mut self) -> PyResult<(usize, Py<PyDict>, Py<PyDict>, Py<PyDict>)> {
+    // If latency_budget_option is provided, returns once it has passed as long as at least one process step has been
+    // collected, even if fewer than min_process_steps_per_inference have been.
+    #[pyo3(signature = (latency_budget_option=None))]
+    pub fn collect_step_data(
+        &mut self,
+        latency_budget_option: Option<Duration>,
+    ) -> PyResult<StepData> {
+        let collect_start = Instant::now();
+        let deadline_option =
+            latency_budget_option.map(|latency_budget| collect_start + latency_budget);
         let mut n_process_steps_collected = 0;
         let mut total_timesteps_collected = 0;
         let mut obs_data_kv_list = Vec::with_capacity(self.min_process_steps_per_inference);
@@ -692,32 +2054,117 @@ impl EnvProcessInterface {
         obs_data_kv_list.append(&mut self.added_process_obs_data_kv_list);
         state_info_kv_list.append(&mut self.added_process_state_info_kv_list);
         Python::with_gil(|py| {
-            let mut ready_pid_idxs = Vec::with_capacity(self.min_process_steps_per_inference);
-            while n_process_steps_collected < self.min_process_steps_per_inference {
-                for (key, event) in self
-                    .selector
-                    .bind(py)
-                    .call_method0(intern!(py, "select"))?
-                    .extract::<Vec<(PyObject, u8)>>()?
-                {
-                    if event & SELECTORS_EVENT_READ.get(py).unwrap() == 0 {
+            let mut ready_proc_ids = Vec::with_capacity(self.min_process_steps_per_inference);
+            let mut last_health_check = Instant::now();
+            while !self.is_done_collecting(n_process_steps_collected, deadline_option) {
+                let mut timeout = PROCESS_HEALTH_CHECK_INTERVAL;
+                if let (Some(deadline), true) = (deadline_option, n_process_steps_collected > 0) {
+                    timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+                }
+                let wait_start = Instant::now();
+                let notified_proc_id_list = self.wait_for_notifications(py, timeout)?;
+                self.telemetry.record_wait(wait_start.elapsed());
+                for proc_id in notified_proc_id_list {
+                    // The response covers every sub-env which had an env action in flight
+                    let pid_idx_range = self.package_pid_idx_range(self.package_idx(&proc_id));
+                    let sent_instant_list =
+                        &mut self.pid_idx_env_action_sent_instant_list[pid_idx_range];
+                    if let Some(sent_instant) = sent_instant_list.iter().flatten().min() {
+                        self.proc_id_step_latency_stats_map
+                            .get_mut(&proc_id)
+                            .unwrap()
+                            .record(sent_instant.elapsed());
+                    }
+                    for sent_instant_option in sent_instant_list.iter_mut() {
+                        if sent_instant_option.take().is_some() {
+                            n_process_steps_collected += 1;
+                        }
+                    }
+                    ready_proc_ids.push(proc_id);
+                }
+                if last_health_check.elapsed() >= PROCESS_HEALTH_CHECK_INTERVAL {
+                    self.check_process_health(py)?;
+                    last_health_check = Instant::now();
+                }
+            }
+            let decode_start = Instant::now();
+            // Retrieve the response headers first, since errors reported by env processes retire them
+            let mut proc_id_offset_list = Vec::with_capacity(n_process_steps_collected);
+            for proc_id in ready_proc_ids.into_iter() {
+                let package_idx = self.package_idx(&proc_id);
+                let (_, ref mut transport, ref proc_id_ref) = self.proc_packages[package_idx];
+                let offset = match retrieve_response_header(
+                    transport.as_mut(),
+                    proc_id_ref,
+                    Header::EnvAction,
+                ) {
+                    Ok(offset) => offset,
+                    Err(err)
+                        if self.respawn_dead_processes
+                            && err.is_instance_of::<EnvProcessError>(py) =>
+                    {
+                        let (_, sub_env_proc_id_list) = self.retire_process(py, package_idx)?;
+                        self.dead_process_list.push((
+                            proc_id,
+                            err.to_string(),
+                            sub_env_proc_id_list,
+                        ));
                         continue;
                     }
-                    let (parent_end, _, _, pid_idx) =
-                        key.extract::<(PyObject, PyObject, PyObject, usize)>(py)?;
-                    recvfrom_byte(py, &parent_end)?;
-                    ready_pid_idxs.push(pid_idx);
-                    n_process_steps_collected += 1;
+                    Err(err) => return Err(err),
+                };
+                let shm_slice = self.proc_packages[package_idx].1.slice();
+                let (n_entries, mut entry_offset) = retrieve_usize(shm_slice, offset)?;
+                for _ in 0..n_entries {
+                    let (sub_env_idx, offset, end_offset) =
+                        retrieve_sub_env_entry(shm_slice, entry_offset)?;
+                    proc_id_offset_list.push((
+                        sub_env_proc_id(&proc_id, sub_env_idx, self.n_envs_per_process),
+                        offset,
+                    ));
+                    entry_offset = end_offset;
                 }
+                let (env_call_timing_list, _) =
+                    retrieve_env_call_timing_list(shm_slice, entry_offset)?;
+                self.telemetry
+                    .record_env_calls(&proc_id, &env_call_timing_list);
             }
-            for pid_idx in ready_pid_idxs.into_iter() {
+            if self.lockstep {
+                // Processes become ready in whatever order the OS schedules them
+                proc_id_offset_list
+                    .sort_unstable_by(|(proc_id1, _), (proc_id2, _)| proc_id1.cmp(proc_id2));
+            }
+            let pid_idx_offset_list = proc_id_offset_list
+                .into_iter()
+                .map(|(proc_id, offset)| (*self.proc_id_pid_idx_map.get(&proc_id).unwrap(), offset))
+                .collect::<Vec<_>>();
+            let raw_agent_data_option_list =
+                self.decode_raw_agent_data(py, &pid_idx_offset_list)?;
+            for ((pid_idx, offset), raw_agent_data_option) in pid_idx_offset_list
+                .into_iter()
+                .zip(raw_agent_data_option_list)
+            {
                 let (n_timesteps, obs_data_kv, timestep_data_kv, state_info_kv) =
-                    self.collect_response(pid_idx)?;
+                    self.collect_response(pid_idx, offset, raw_agent_data_option)?;
                 obs_data_kv_list.push(obs_data_kv);
                 timestep_data_kv_list.push(timestep_data_kv);
                 state_info_kv_list.push(state_info_kv);
                 total_timesteps_collected += n_timesteps;
             }
+            self.telemetry.record_decode(decode_start.elapsed());
+            self.telemetry
+                .record_collection(n_process_steps_collected, total_timesteps_collected);
+            let n_envs = self.n_envs();
+            if let Some(tuner) = self.min_process_steps_tuner_option.as_mut() {
+                if let Some(min_process_steps_per_inference) = tuner.record_collection(
+                    collect_start,
+                    total_timesteps_collected,
+                    self.min_process_steps_per_inference,
+                    n_envs,
+                ) {
+                    self.min_process_steps_per_inference = min_process_steps_per_inference;
+                }
+            }
             Ok((
                 total_timesteps_collected,
                 PyDict::from_sequence(&obs_data_kv_list.into_pyobject(py)?)?.unbind(),
@@ -729,20 +2176,26 @@ impl EnvProcessInterface {
 
     pub fn send_env_actions(&mut self, env_actions: HashMap<String, EnvAction>) -> PyResult<()> {
         Python::with_gil(|py| {
-            for (proc_id, env_action) in env_actions.into_iter() {
-                let &pid_idx = self.proc_id_pid_idx_map.get(&proc_id).unwrap();
-                let (_, shmem, _) = self.proc_packages.get_mut(pid_idx).unwrap();
-                let (ep_evt, evt_used_bytes) = unsafe {
-                    Event::from_existing(shmem.as_ptr()).map_err(|err| {
-                        InvalidStateError::new_err(format!(
-                            "Failed to get event from epi to process with index {}: {}",
-                            pid_idx,
-                            err.to_string()
-                        ))
-                    })?
-                };
-                let shm_slice = unsafe { &mut shmem.as_slice_mut()[evt_used_bytes..] };
-
+            // Env actions for sub-envs of the same process are sent together in one message. They are grouped by the
+            // proc id of the process, since processes can be retired (shifting package indices) while sending.
+            let mut proc_id_env_action_list_map: HashMap<String, Vec<(usize, EnvAction)>> =
+                HashMap::new();
+            // Env actions for envs which have been deleted or retired are rejected before anything is sent
+            let pid_idx_env_action_list = env_actions
+                .into_iter()
+                .map(|(proc_id, env_action)| {
+                    self.proc_id_pid_idx_map
+                        .get(&proc_id)
+                        .map(|&pid_idx| (pid_idx, env_action))
+                        .ok_or_else(|| {
+                            PyValueError::new_err(format!(
+                                "Received env action for proc id {}, which isn't the proc id of a running env",
+                                proc_id
+                            ))
+                        })
+                })
+                .collect::<PyResult<Vec<_>>>()?;
+            for (pid_idx, env_action) in pid_idx_env_action_list.into_iter() {
                 if let EnvAction::STEP {
                     ref action_list,
                     ref action_associated_learning_data,
@@ -763,294 +2216,430 @@ impl EnvProcessInterface {
                     self.pid_idx_current_aald_list[pid_idx] = None;
                 }
 
-                let offset = append_header(shm_slice, 0, Header::EnvAction);
-                _ = append_env_action(
-                    py,
-                    shm_slice,
-                    offset,
-                    &env_action,
-                    &self.action_serde,
-                    &self.state_serde_option.as_ref(),
-                )?;
+                proc_id_env_action_list_map
+                    .entry(
+                        self.proc_packages[pid_idx / self.n_envs_per_process]
+                            .2
+                            .clone(),
+                    )
+                    .or_default()
+                    .push((pid_idx % self.n_envs_per_process, env_action));
+            }
 
-                ep_evt
-                    .set(EventState::Signaled)
-                    .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
-                self.pid_idx_current_env_action_list[pid_idx] = Some(env_action);
+            // Env actions are still sent after a rejected env rebuild. If an env rebuild or env command exchange fails
+            // though, the env process is in an unknown state, so it is retired instead of being sent its env actions.
+            let mut env_control_err_option = None;
+            let mut encode_time = Duration::ZERO;
+            for (proc_id, env_action_list) in proc_id_env_action_list_map.into_iter() {
+                let package_idx = self.package_idx(&proc_id);
+                let exchange_result =
+                    self.send_env_rebuild(py, package_idx)
+                        .and_then(|err_option| {
+                            if let Some(err) = err_option {
+                                env_control_err_option.get_or_insert(err);
+                            }
+                            self.send_env_commands(py, package_idx)
+                        });
+                if let Err(err) = exchange_result {
+                    let (_, sub_env_proc_id_list) = self.retire_process(py, package_idx)?;
+                    if self.respawn_dead_processes {
+                        self.dead_process_list.push((
+                            proc_id,
+                            err.to_string(),
+                            sub_env_proc_id_list,
+                        ));
+                    } else {
+                        env_control_err_option.get_or_insert(err);
+                    }
+                    continue;
+                }
+                let (_, transport, _) = self.proc_packages.get_mut(package_idx).unwrap();
+                let encode_start = Instant::now();
+                transport.write_message(py, &mut |buf| {
+                    let mut offset = append_header(buf, 0, Header::EnvAction);
+                    offset =
+                        append_usize_checked(buf, offset, env_action_list.len(), "sub-env count")?;
+                    for (sub_env_idx, env_action) in env_action_list.iter() {
+                        offset = append_sub_env_entry(buf, offset, *sub_env_idx, |buf, offset| {
+                            append_env_action(
+                                py,
+                                buf,
+                                offset,
+                                env_action,
+                                &self.action_serde,
+                                self.state_serde_option.as_ref(),
+                            )
+                        })?;
+                    }
+                    Ok(offset)
+                })?;
+                encode_time += encode_start.elapsed();
+                transport.send(py)?;
+                let sent_instant = Instant::now();
+                for (sub_env_idx, env_action) in env_action_list.into_iter() {
+                    let pid_idx = package_idx * self.n_envs_per_process + sub_env_idx;
+                    self.pid_idx_current_env_action_list[pid_idx] = Some(env_action);
+                    self.pid_idx_env_action_sent_instant_list[pid_idx] = Some(sent_instant);
+                }
             }
-            Ok(())
+            self.telemetry.record_encode(encode_time);
+            env_control_err_option.map_or(Ok(()), Err)
         })
     }
 }
 
-// struct ThreadState {
-//     agent_id_serde: Box<dyn PyAnySerde>,
-//     action_serde: Box<dyn PyAnySerde>,
-//     obs_serde: Box<dyn PyAnySerde>,
-//     reward_serde: Box<dyn PyAnySerde>,
-//     obs_space_serde: Box<dyn PyAnySerde>,
-//     action_space_serde: Box<dyn PyAnySerde>,
-//     state_serde_option: Option<Box<dyn PyAnySerde>>,
-//     state_metrics_serde_option: Option<Box<dyn PyAnySerde>>,
-//     recalculate_agent_id_every_step: bool,
-//     send_state_to_agent_controllers: bool,
-//     should_collect_state_metrics: bool,
-//     timestep_class: PyObject,
-//     shm_slice_ptr: *mut u8,
-//     shm_slice_len: usize,
-// }
-
-// fn test() {
-//     let thread_local = ThreadLocal::<ThreadState>::new();
-//     let pool = ThreadPoolBuilder::new().num_threads(10).build().unwrap();
-//     pool.scope(|s| {
-//         for i in 0..10 {
-//             s.spawn(|_| {
-//                 let state = thread_local
-//             })
-//         }
-//     })
-// }
-
-// Returns number of timesteps collected, plus three kv pairs: the keys are all the proc id,
-// and the values are (agent id list, obs list),
-// (timestep list, optional state metrics, optional state),
-// and (optional state, optional terminated dict, optional truncated dict) respectively
-// fn collect_response(
-//     &mut self,
-//     pid_idx: usize,
-// ) -> PyResult<(
-//     usize,
-//     (PyObject, (Vec<PyObject>, Vec<PyObject>)),
-//     (
-//         PyObject,
-//         (Vec<PyObject>, PyObject, Option<PyObject>, Option<PyObject>),
-//     ),
-//     (
-//         PyObject,
-//         (Option<PyObject>, Option<Py<PyDict>>, Option<Py<PyDict>>),
-//     ),
-// )> {
-//     let env_action = self.pid_idx_current_env_action_list[pid_idx]
-//         .as_ref()
-//         .ok_or_else(|| {
-//             InvalidStateError::new_err(
-//                 "Tried to collect response from env which doesn't have an env action yet",
-//             )
-//         })?;
-//     let is_step_action = matches!(env_action, EnvAction::STEP { .. });
-//     let new_episode = !is_step_action;
-//     let (_, shmem, proc_id) = self.proc_packages.get(pid_idx).unwrap();
-//     let evt_used_bytes = Event::size_of(None);
-//     let shm_slice = unsafe { &shmem.as_slice()[evt_used_bytes..] };
-//     let mut offset = 0;
-//     Python::with_gil(|py| {
-//         let current_agent_id_list = self
-//             .pid_idx_current_agent_id_list
-//             .get_mut(pid_idx)
-//             .unwrap()
-//             .take()
-//             .unwrap();
-
-//         // Get n_agents for incoming data and instantiate lists
-//         let n_agents;
-//         let (
-//             mut agent_id_list,
-//             mut obs_list,
-//             mut reward_list_option,
-//             mut terminated_list_option,
-//             mut truncated_list_option,
-//         );
-
-//         if new_episode {
-//             (n_agents, offset) = retrieve_usize(shm_slice, offset)?;
-//             agent_id_list = Vec::with_capacity(n_agents);
-//         } else {
-//             n_agents = current_agent_id_list.len();
-//             if self.recalculate_agent_id_every_step {
-//                 agent_id_list = Vec::with_capacity(n_agents);
-//             } else {
-//                 agent_id_list = current_agent_id_list;
-//             }
-//         }
-//         obs_list = Vec::with_capacity(n_agents);
-//         if is_step_action {
-//             reward_list_option = Some(Vec::with_capacity(n_agents));
-//             terminated_list_option = Some(Vec::with_capacity(n_agents));
-//             truncated_list_option = Some(Vec::with_capacity(n_agents));
-//         } else {
-//             reward_list_option = None;
-//             terminated_list_option = None;
-//             truncated_list_option = None;
-//         }
-
-//         // Populate lists
-//         for _ in 0..n_agents {
-//             if self.recalculate_agent_id_every_step || new_episode {
-//                 let agent_id;
-//                 (agent_id, offset) = self.agent_id_serde.retrieve(py, shm_slice, offset)?;
-//                 agent_id_list.push(agent_id.unbind());
-//             }
-//             let obs;
-//             (obs, offset) = self.obs_serde.retrieve(py, shm_slice, offset)?;
-//             obs_list.push(obs.unbind());
-//             if is_step_action {
-//                 let reward;
-//                 (reward, offset) = self.reward_serde.retrieve(py, shm_slice, offset)?;
-//                 reward_list_option.as_mut().unwrap().push(reward.unbind());
-//                 let terminated;
-//                 (terminated, offset) = retrieve_bool(shm_slice, offset)?;
-//                 terminated_list_option.as_mut().unwrap().push(terminated);
-//                 let truncated;
-//                 (truncated, offset) = retrieve_bool(shm_slice, offset)?;
-//                 truncated_list_option.as_mut().unwrap().push(truncated);
-//             }
-//         }
-
-//         let state_option;
-//         if self.send_state_to_agent_controllers {
-//             let state;
-//             (state, offset) = self
-//                 .state_serde_option
-//                 .as_ref()
-//                 .unwrap()
-//                 .retrieve(py, shm_slice, offset)?;
-//             state_option = Some(state.unbind());
-//         } else {
-//             state_option = None;
-//         }
-
-//         let metrics_option;
-//         if self.should_collect_state_metrics {
-//             let state_metrics;
-//             (state_metrics, offset) = self
-//                 .state_metrics_serde_option
-//                 .as_ref()
-//                 .unwrap()
-//                 .retrieve(py, shm_slice, offset)?;
-//             metrics_option = Some(state_metrics.unbind());
-//         } else {
-//             metrics_option = None;
-//         }
-
-//         let timestep_id_list_option;
-//         let mut timestep_list;
-//         if is_step_action {
-//             let timestep_class = self.timestep_class.bind(py);
-//             let mut timestep_id_list = Vec::with_capacity(n_agents);
-//             timestep_list = Vec::with_capacity(n_agents);
-//             for (
-//                 prev_timestep_id,
-//                 agent_id,
-//                 obs,
-//                 next_obs,
-//                 action,
-//                 reward,
-//                 &terminated,
-//                 &truncated,
-//             ) in izip!(
-//                 self.pid_idx_prev_timestep_id_list.get(pid_idx).unwrap(),
-//                 &agent_id_list,
-//                 self.pid_idx_current_obs_list.get(pid_idx).unwrap(),
-//                 &obs_list,
-//                 &self.pid_idx_current_action_list[pid_idx],
-//                 reward_list_option.as_ref().unwrap(),
-//                 terminated_list_option.as_ref().unwrap(),
-//                 truncated_list_option.as_ref().unwrap()
-//             ) {
-//                 let timestep_id = fastrand::u128(..);
-//                 timestep_id_list.push(Some(timestep_id));
-//                 timestep_list.push(
-//                     timestep_class
-//                         .call1((
-//                             proc_id.into_py_any(py)?,
-//                             timestep_id,
-//                             *prev_timestep_id,
-//                             agent_id.clone_ref(py),
-//                             obs,
-//                             next_obs,
-//                             action,
-//                             reward,
-//                             terminated,
-//                             truncated,
-//                         ))?
-//                         .unbind(),
-//                 );
-//             }
-//             timestep_id_list_option = Some(timestep_id_list);
-//         } else {
-//             timestep_id_list_option = None;
-//             timestep_list = Vec::new();
-//         }
-//         let n_timesteps = timestep_list.len();
-
-//         let terminated_dict_option;
-//         let truncated_dict_option;
-//         if new_episode {
-//             terminated_dict_option = None;
-//             truncated_dict_option = None;
-//         } else {
-//             let mut terminated_kv_list = Vec::with_capacity(n_agents);
-//             let mut truncated_kv_list = Vec::with_capacity(n_agents);
-//             for (agent_id, terminated, truncated) in izip!(
-//                 &agent_id_list,
-//                 terminated_list_option.unwrap(),
-//                 truncated_list_option.unwrap()
-//             ) {
-//                 terminated_kv_list.push((agent_id.clone_ref(py), terminated));
-//                 truncated_kv_list.push((agent_id.clone_ref(py), truncated));
-//             }
-//             terminated_dict_option =
-//                 Some(PyDict::from_sequence(&terminated_kv_list.into_pyobject(py)?)?.unbind());
-//             truncated_dict_option =
-//                 Some(PyDict::from_sequence(&truncated_kv_list.into_pyobject(py)?)?.unbind());
-//         }
-
-//         // Set prev_timestep_id_list for proc
-//         let prev_timestep_id_list = &mut self.pid_idx_prev_timestep_id_list[pid_idx];
-//         if is_step_action {
-//             prev_timestep_id_list.clear();
-//             prev_timestep_id_list.append(&mut timestep_id_list_option.unwrap());
-//         } else if let EnvAction::SET_STATE {
-//             prev_timestep_id_dict_option: Some(prev_timestep_id_dict),
-//             ..
-//         } = env_action
-//         {
-//             let prev_timestep_id_dict = prev_timestep_id_dict.downcast_bound::<PyDict>(py)?;
-//             prev_timestep_id_list.clear();
-//             for agent_id in agent_id_list.iter() {
-//                 let agent_id = agent_id.bind(py);
-//                 prev_timestep_id_list.push(
-//                     prev_timestep_id_dict
-//                         .get_item(agent_id)?
-//                         .map_or(Ok(None), |prev_timestep_id| {
-//                             prev_timestep_id.extract::<Option<u128>>()
-//                         })?,
-//                 );
-//             }
-//         } else {
-//             prev_timestep_id_list.clear();
-//             prev_timestep_id_list.append(&mut vec![None; n_agents]);
-//         }
-//         self.pid_idx_current_agent_id_list[pid_idx] = Some(clone_list(py, &agent_id_list));
-//         self.pid_idx_current_obs_list[pid_idx] = clone_list(py, &obs_list);
-
-//         let py_proc_id = proc_id.into_py_any(py)?;
-//         let obs_data_kv = (py_proc_id.clone_ref(py), (agent_id_list, obs_list));
-//         let timestep_data_kv = (
-//             py_proc_id.clone_ref(py),
-//             (
-//                 timestep_list,
-//                 (&self.pid_idx_current_aald_list[pid_idx]).into_py_any(py)?,
-//                 metrics_option,
-//                 state_option.as_ref().map(|state| state.clone_ref(py)),
-//             ),
-//         );
-//         let state_info_kv = (
-//             py_proc_id,
-//             (state_option, terminated_dict_option, truncated_dict_option),
-//         );
-
-//         Ok((n_timesteps, obs_data_kv, timestep_data_kv, state_info_kv))
-//     })
-// }
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::thread;
+
+    use pyo3::types::PyList;
+
+    use super::*;
+    use crate::env_process::env_process;
+    use crate::env_process_config::EnvProcessConfig;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    // rlgym_learn.experience.timestep can't be imported without the built extension module, so it is replaced by a
+    // stand-in. Env steps through the agent id lists in agents_by_step, starting from the first one on reset.
+    const TEST_ENV_CODE: &std::ffi::CStr = cr#"
+import socket
+import sys
+import types
+
+
+class Timestep:
+    def __init__(self, env_id, timestep_id, previous_timestep_id, agent_id, obs, next_obs, action, reward, terminated, truncated):
+        self.agent_id = agent_id
+        self.obs = obs
+        self.next_obs = next_obs
+        self.action = action
+        self.reward = reward
+        self.terminated = terminated
+        self.truncated = truncated
+
+
+for module_name in ["rlgym_learn", "rlgym_learn.experience"]:
+    sys.modules.setdefault(module_name, types.ModuleType(module_name))
+timestep_module = types.ModuleType("rlgym_learn.experience.timestep")
+timestep_module.Timestep = Timestep
+sys.modules["rlgym_learn.experience.timestep"] = timestep_module
+
+
+class Env:
+    def __init__(self, agents_by_step):
+        self.agents_by_step = agents_by_step
+        self.n_steps = 0
+        self.state = None
+        self.shared_info = {}
+
+    @property
+    def agents(self):
+        return self.agents_by_step[self.n_steps]
+
+    @property
+    def observation_spaces(self):
+        return {agent: "obs space" for agent in self.agents}
+
+    @property
+    def action_spaces(self):
+        return {agent: "action space" for agent in self.agents}
+
+    def reset(self):
+        self.n_steps = 0
+        return {agent: 0.0 for agent in self.agents}
+
+    def step(self, actions):
+        self.n_steps += 1
+        obs = {agent: float(self.n_steps) for agent in self.agents}
+        rewards = {agent: float(action) for agent, action in actions.items()}
+        # Agents which left aren't in the terminated and truncated dicts
+        dones = {agent: False for agent in actions if agent in self.agents}
+        return obs, rewards, dones, dict(dones)
+
+    def close(self):
+        pass
+
+
+class EnvThread:
+    exitcode = None
+
+    def is_alive(self):
+        return True
+
+    def terminate(self):
+        pass
+
+
+def new_sync_socket():
+    sync_socket = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sync_socket.bind(("127.0.0.1", 0))
+    return sync_socket
+"#;
+
+    fn extract_serde<'py, T: FromPyObject<'py>>(
+        py: Python<'py>,
+        serde_type_option: Option<PyAnySerdeType>,
+    ) -> T {
+        serde_type_option
+            .into_pyobject(py)
+            .unwrap()
+            .extract()
+            .unwrap()
+    }
+
+    fn serde_type_config(py: Python<'_>) -> SerdeTypeConfig {
+        SerdeTypeConfig {
+            agent_id_serde: extract_serde(py, Some(PyAnySerdeType::INT {})),
+            action_serde: extract_serde(py, Some(PyAnySerdeType::INT {})),
+            obs_serde: extract_serde(py, Some(PyAnySerdeType::FLOAT {})),
+            reward_serde: extract_serde(py, Some(PyAnySerdeType::FLOAT {})),
+            obs_space_serde: extract_serde(py, Some(PyAnySerdeType::PICKLE {})),
+            action_space_serde: extract_serde(py, Some(PyAnySerdeType::PICKLE {})),
+            state_serde_option: extract_serde(py, None),
+            state_metrics_serde_option: extract_serde(py, None),
+        }
+    }
+
+    // Runs test against an in-process EnvProcessInterface with a single env thread hosting an Env which steps through
+    // agents_by_step (a Python list of agent id lists). The env thread must have exited once the EnvProcessInterface
+    // is cleaned up.
+    fn with_env_thread(
+        proc_id: &str,
+        agents_by_step: &str,
+        test: impl FnOnce(Python<'_>, &mut EnvProcessInterface, (Py<PyDict>, Py<PyDict>)),
+    ) {
+        pyo3::prepare_freethreaded_python();
+        let timeout_config = TimeoutConfig {
+            startup: TIMEOUT,
+            env_shapes: TIMEOUT,
+            step: TIMEOUT,
+            rebuild: TIMEOUT,
+            shutdown: TIMEOUT,
+        };
+        let transport_config = TransportConfig {
+            tcp_address: None,
+            tcp_token: None,
+            in_process: true,
+            native_signalling: false,
+            validate_shm_buffer_size: false,
+            sweep_orphaned_flinks: false,
+            external_processes: false,
+        };
+        let flinks_folder = std::env::temp_dir().to_str().unwrap().to_string();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            py.run(TEST_ENV_CODE, Some(&globals), None).unwrap();
+            let build_env_fn = py
+                .eval(
+                    &CString::new(format!("lambda: Env({})", agents_by_step)).unwrap(),
+                    Some(&globals),
+                    None,
+                )
+                .unwrap()
+                .unbind();
+            let new_sync_socket = globals.get_item("new_sync_socket").unwrap().unwrap();
+            let parent_end = new_sync_socket.call0().unwrap();
+            let child_end = new_sync_socket.call0().unwrap();
+            let parent_sockname = parent_end.call_method0("getsockname").unwrap().unbind();
+            let child_sockname = child_end.call_method0("getsockname").unwrap().unbind();
+
+            let mut epi = EnvProcessInterface::new(
+                serde_type_config(py),
+                EnvProcessInterfaceConfig {
+                    flinks_folder: flinks_folder.clone(),
+                    min_process_steps_per_inference: 1,
+                    send_state_to_agent_controllers: false,
+                    should_collect_state_metrics: false,
+                    recalculate_agent_id_every_step: false,
+                    n_envs_per_process: 1,
+                    seed_option: None,
+                },
+                timeout_config,
+                transport_config.clone(),
+                CollectionConfig {
+                    lockstep: false,
+                    auto_tune_window: None,
+                    respawn_dead_processes: false,
+                },
+            )
+            .unwrap();
+            let env_process_config = EnvProcessConfig {
+                proc_id: proc_id.to_string(),
+                flinks_folder,
+                shm_buffer_size: INITIAL_BUFFER_SIZE,
+                send_state_to_agent_controllers: false,
+                render: false,
+                render_delay_option: None,
+                recalculate_agent_id_every_step: false,
+                n_envs: 1,
+            };
+            let startup_sync = (child_end.unbind(), parent_sockname);
+            let env_thread = thread::spawn(move || {
+                let serde_type_config = Python::with_gil(serde_type_config);
+                env_process(
+                    env_process_config,
+                    Some(startup_sync),
+                    build_env_fn,
+                    serde_type_config,
+                    None,
+                    timeout_config,
+                    transport_config,
+                )
+            });
+
+            let process = globals
+                .get_item("EnvThread")
+                .unwrap()
+                .unwrap()
+                .call0()
+                .unwrap()
+                .unbind();
+            let (initial_obs_data_dict, initial_state_info_dict, _, _) = epi
+                .init_processes(
+                    vec![(
+                        process,
+                        Some(parent_end.unbind()),
+                        Some(child_sockname),
+                        proc_id.to_string(),
+                    )],
+                    0,
+                )
+                .unwrap();
+            test(
+                py,
+                &mut epi,
+                (initial_obs_data_dict, initial_state_info_dict),
+            );
+            assert!(epi.cleanup().unwrap().is_empty());
+            py.allow_threads(|| env_thread.join().unwrap()).unwrap();
+        });
+    }
+
+    fn step(py: Python<'_>, epi: &mut EnvProcessInterface, proc_id: &str, action_list: Vec<i64>) {
+        epi.send_env_actions(HashMap::from([(
+            proc_id.to_string(),
+            EnvAction::STEP {
+                action_list: PyList::new(py, action_list).unwrap().unbind(),
+                action_associated_learning_data: py.None(),
+            },
+        )]))
+        .unwrap();
+    }
+
+    // (agent id list, obs list) of the env
+    fn obs_data(py: Python<'_>, obs_data_dict: &Py<PyDict>, proc_id: &str) -> (Vec<i64>, Vec<f64>) {
+        obs_data_dict
+            .bind(py)
+            .get_item(proc_id)
+            .unwrap()
+            .unwrap()
+            .extract()
+            .unwrap()
+    }
+
+    // (agent id, obs, next obs, action, reward, terminated, truncated) of each timestep of the env
+    fn timesteps(
+        py: Python<'_>,
+        timestep_data_dict: &Py<PyDict>,
+        proc_id: &str,
+    ) -> Vec<(i64, f64, f64, i64, f64, bool, bool)> {
+        let (timestep_list, _, _, _) = timestep_data_dict
+            .bind(py)
+            .get_item(proc_id)
+            .unwrap()
+            .unwrap()
+            .extract::<TimestepData>()
+            .unwrap();
+        timestep_list
+            .iter()
+            .map(|timestep| {
+                let timestep = timestep.bind(py);
+                let attr = |name: &str| timestep.getattr(name).unwrap();
+                (
+                    attr("agent_id").extract().unwrap(),
+                    attr("obs").extract().unwrap(),
+                    attr("next_obs").extract().unwrap(),
+                    attr("action").extract().unwrap(),
+                    attr("reward").extract().unwrap(),
+                    attr("terminated").extract().unwrap(),
+                    attr("truncated").extract().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn in_process_env_thread_round_trip() {
+        with_env_thread(
+            "in_process_round_trip",
+            "[[0, 1], [0, 1]]",
+            |py, epi, (initial_obs_data_dict, _)| {
+                let proc_id = "in_process_round_trip";
+                assert_eq!(
+                    obs_data(py, &initial_obs_data_dict, proc_id),
+                    (vec![0, 1], vec![0.0, 0.0])
+                );
+                step(py, epi, proc_id, vec![1, 2]);
+                let (n_timesteps, obs_data_dict, timestep_data_dict, _) =
+                    epi.collect_step_data(None).unwrap();
+                assert_eq!(n_timesteps, 2);
+                assert_eq!(
+                    obs_data(py, &obs_data_dict, proc_id),
+                    (vec![0, 1], vec![1.0, 1.0])
+                );
+                assert_eq!(
+                    timesteps(py, &timestep_data_dict, proc_id),
+                    vec![
+                        (0, 0.0, 1.0, 1, 1.0, false, false),
+                        (1, 0.0, 1.0, 2, 2.0, false, false),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn agents_joining_and_leaving_mid_episode() {
+        with_env_thread(
+            "agents_joining_and_leaving",
+            "[[0, 1], [0, 2], [0, 2]]",
+            |py, epi, _| {
+                let proc_id = "agents_joining_and_leaving";
+                // Agent 1 leaves and agent 2 joins. Agent 1's trajectory is truncated, with its last obs as its next obs.
+                step(py, epi, proc_id, vec![1, 2]);
+                let (n_timesteps, obs_data_dict, timestep_data_dict, _) =
+                    epi.collect_step_data(None).unwrap();
+                assert_eq!(n_timesteps, 2);
+                assert_eq!(
+                    obs_data(py, &obs_data_dict, proc_id),
+                    (vec![0, 2], vec![1.0, 1.0])
+                );
+                assert_eq!(
+                    timesteps(py, &timestep_data_dict, proc_id),
+                    vec![
+                        (0, 0.0, 1.0, 1, 1.0, false, false),
+                        (1, 0.0, 0.0, 2, 2.0, false, true),
+                    ]
+                );
+                // The actions now go to the new agents
+                step(py, epi, proc_id, vec![3, 4]);
+                let (n_timesteps, obs_data_dict, timestep_data_dict, _) =
+                    epi.collect_step_data(None).unwrap();
+                assert_eq!(n_timesteps, 2);
+                assert_eq!(
+                    obs_data(py, &obs_data_dict, proc_id),
+                    (vec![0, 2], vec![2.0, 2.0])
+                );
+                assert_eq!(
+                    timesteps(py, &timestep_data_dict, proc_id),
+                    vec![
+                        (0, 1.0, 2.0, 3, 3.0, false, false),
+                        (2, 1.0, 2.0, 4, 4.0, false, false),
+                    ]
+                );
+            },
+        );
+    }
+}
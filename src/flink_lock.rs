@@ -0,0 +1,202 @@
+#[cfg(unix)]
+use std::fs::{self, File};
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(unix)]
+use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::prelude::*;
+#[cfg(unix)]
+use shared_memory::ShmemConf;
+
+#[cfg(unix)]
+use crate::synchronization::get_flink;
+
+// Env processes using shared memory hold an exclusive lock on a file next to their shmem flinks for as long as they
+// run. The OS releases the lock when the process dies, however it dies, so a flink whose lock can be taken belongs to
+// a process which is gone. This lets the EnvProcessInterface remove flinks left behind by crashed runs without
+// touching those of other runs sharing the flinks folder.
+
+#[cfg(unix)]
+fn lock_path(flinks_folder: &str, proc_id: &str) -> String {
+    format!("{}.lock", get_flink(flinks_folder, proc_id))
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+// Held by an env process for its whole lifetime. Must be acquired before any flink of the process is created.
+pub struct FlinkLock {
+    #[cfg(unix)]
+    _file: File,
+    #[cfg(unix)]
+    path: String,
+}
+
+impl FlinkLock {
+    pub fn acquire(flinks_folder: &str, proc_id: &str) -> PyResult<Self> {
+        #[cfg(unix)]
+        {
+            let path = lock_path(flinks_folder, proc_id);
+            let file = File::create(&path).map_err(|err| {
+                InvalidStateError::new_err(format!("Unable to create flink lock {}: {}", path, err))
+            })?;
+            if !try_lock(&file) {
+                return Err(InvalidStateError::new_err(format!(
+                    "Flink lock {} is held by another process",
+                    path
+                )));
+            }
+            Ok(FlinkLock { _file: file, path })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (flinks_folder, proc_id);
+            Ok(FlinkLock {})
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for FlinkLock {
+    fn drop(&mut self) {
+        // The file is removed while the lock is still held, so a sweep can't mistake this process for a dead one
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Used by the EnvProcessInterface when it removes the flinks of a process which may have died without removing its
+// lock file
+pub fn remove_flink_lock(flinks_folder: &str, proc_id: &str) {
+    #[cfg(unix)]
+    {
+        let _ = fs::remove_file(lock_path(flinks_folder, proc_id));
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (flinks_folder, proc_id);
+    }
+}
+
+// Removes the shmem segment behind the flink along with the flink itself. Returns false if there was nothing to remove.
+#[cfg(unix)]
+fn remove_flink(path: &str) -> bool {
+    match ShmemConf::new().flink(path).open() {
+        Ok(mut shmem) => {
+            shmem.set_owner(true);
+            true
+        }
+        Err(_) => fs::remove_file(path).is_ok(),
+    }
+}
+
+// Whether file_name is one of the shmem flinks of the process, which are flinked at {proc_id} for the first segment
+// and {proc_id}_{generation} for later ones
+#[cfg(unix)]
+fn is_flink_of(file_name: &str, proc_id: &str) -> bool {
+    match file_name.strip_prefix(proc_id) {
+        Some("") => true,
+        Some(suffix) => suffix
+            .strip_prefix('_')
+            .is_some_and(|generation| generation.parse::<usize>().is_ok()),
+        None => false,
+    }
+}
+
+// Removes the flinks and notification sockets in flinks_folder of processes which are no longer running, along with
+// their lock files. Only files named after a process whose lock file can be taken are touched, so other files in the
+// folder and the flinks of runs which don't use lock files are left alone. Returns the number of files removed.
+pub fn sweep_orphaned_flinks(flinks_folder: &str) -> PyResult<usize> {
+    #[cfg(unix)]
+    {
+        let Ok(dir) = fs::read_dir(flinks_folder) else {
+            return Ok(0);
+        };
+        let file_name_list = dir
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect::<Vec<_>>();
+        let mut n_removed = 0;
+        for lock_file_name in file_name_list.iter() {
+            let Some(proc_id) = lock_file_name.strip_suffix(".lock") else {
+                continue;
+            };
+            let lock_path = format!("{}/{}", flinks_folder, lock_file_name);
+            // The lock is held until the lock file is removed, so the process can't be started again in the meantime
+            let Ok(lock_file) = File::open(&lock_path) else {
+                continue;
+            };
+            if !try_lock(&lock_file) {
+                continue;
+            }
+            let socket_file_name = format!("{}.sock", proc_id);
+            for file_name in file_name_list.iter() {
+                let path = format!("{}/{}", flinks_folder, file_name);
+                if is_flink_of(file_name, proc_id) {
+                    if remove_flink(&path) {
+                        n_removed += 1;
+                    }
+                } else if *file_name == socket_file_name {
+                    // Notification sockets are bound by the EnvProcessInterface, so nobody is listening on an
+                    // orphaned one
+                    let is_orphaned = UnixDatagram::unbound()
+                        .and_then(|socket| socket.connect(&path))
+                        .is_err_and(|err| err.kind() == std::io::ErrorKind::ConnectionRefused);
+                    if is_orphaned && fs::remove_file(&path).is_ok() {
+                        n_removed += 1;
+                    }
+                }
+            }
+            if fs::remove_file(&lock_path).is_ok() {
+                n_removed += 1;
+            }
+        }
+        Ok(n_removed)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = flinks_folder;
+        Ok(0)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_only_removes_flinks_of_dead_processes() {
+        let flinks_folder = std::env::temp_dir()
+            .join(format!("rlgym_learn_sweep_test_{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&flinks_folder).unwrap();
+        let path = |file_name: &str| format!("{}/{}", flinks_folder, file_name);
+        for file_name in [
+            "dead.lock",
+            "dead",
+            "dead_1",
+            "dead_notes",
+            "live",
+            "unlocked",
+            "notes.txt",
+        ] {
+            File::create(path(file_name)).unwrap();
+        }
+        let live_lock = FlinkLock::acquire(&flinks_folder, "live").unwrap();
+
+        assert_eq!(sweep_orphaned_flinks(&flinks_folder).unwrap(), 3);
+        for file_name in ["dead.lock", "dead", "dead_1"] {
+            assert!(!fs::exists(path(file_name)).unwrap(), "{}", file_name);
+        }
+        for file_name in ["dead_notes", "live.lock", "live", "unlocked", "notes.txt"] {
+            assert!(fs::exists(path(file_name)).unwrap(), "{}", file_name);
+        }
+
+        drop(live_lock);
+        fs::remove_dir_all(&flinks_folder).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use std::mem::size_of;
+
+use pyany_serde::communication::{
+    append_string_vec, append_usize_vec, retrieve_string, retrieve_u64, retrieve_usize,
+};
+use pyany_serde::{PickleablePyAnySerdeType, PyAnySerdeType};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use crate::shm_buffer::overflow_err;
+use crate::transport::Transport;
+
+create_exception!(rlgym_learn, ProtocolMismatchError, PyException);
+
+// Bump this whenever the messages exchanged between the EnvProcessInterface and env processes change
+pub const PROTOCOL_VERSION: usize = 11;
+
+// FNV-1a, used instead of std's hashers for hashes which must be stable across builds
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325_u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Fingerprints an (optional) PyAnySerdeType using its pickled form
+pub fn serde_fingerprint<'py>(
+    py: Python<'py>,
+    serde_type_option: Option<PyAnySerdeType>,
+) -> PyResult<u64> {
+    let state = Bound::new(py, PickleablePyAnySerdeType(Some(serde_type_option)))?
+        .call_method0("__getstate__")?
+        .extract::<Vec<u8>>()?;
+    Ok(fnv1a(&state))
+}
+
+// Extracts T from a serde type argument while keeping the serde type it was built from and its fingerprint
+pub struct Fingerprinted<T> {
+    pub value: T,
+    pub serde_type_option: Option<PyAnySerdeType>,
+    pub fingerprint: u64,
+}
+
+impl<'py, T: FromPyObject<'py>> FromPyObject<'py> for Fingerprinted<T> {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        let serde_type_option = match ob.extract::<Option<PyAnySerdeType>>() {
+            Ok(serde_type_option) => serde_type_option,
+            Err(_) => ob.extract::<PickleablePyAnySerdeType>()?.0.unwrap(),
+        };
+        Ok(Fingerprinted {
+            value: ob.extract()?,
+            fingerprint: serde_fingerprint(ob.py(), serde_type_option.clone())?,
+            serde_type_option,
+        })
+    }
+}
+
+// Sent by both sides during the startup sync so that a mismatch in configuration or build is caught before any
+// messages are exchanged through shared memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    protocol_version: usize,
+    crate_version: String,
+    serde_fingerprint_list: Vec<(String, u64)>,
+    // Settings which change how messages are exchanged, so both sides need to use the same values
+    setting_list: Vec<(String, String)>,
+}
+
+impl Handshake {
+    pub fn new(
+        serde_fingerprint_list: Vec<(&str, u64)>,
+        setting_list: Vec<(&str, String)>,
+    ) -> Self {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            serde_fingerprint_list: serde_fingerprint_list
+                .into_iter()
+                .map(|(name, fingerprint)| (name.to_string(), fingerprint))
+                .collect(),
+            setting_list: setting_list
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        append_usize_vec(&mut bytes, self.protocol_version);
+        append_string_vec(&mut bytes, &self.crate_version);
+        append_usize_vec(&mut bytes, self.serde_fingerprint_list.len());
+        for (name, fingerprint) in self.serde_fingerprint_list.iter() {
+            append_string_vec(&mut bytes, name);
+            bytes.extend_from_slice(&fingerprint.to_ne_bytes());
+        }
+        append_usize_vec(&mut bytes, self.setting_list.len());
+        for (name, value) in self.setting_list.iter() {
+            append_string_vec(&mut bytes, name);
+            append_string_vec(&mut bytes, value);
+        }
+        bytes
+    }
+
+    // Sends the handshake as a message of its own, for transports which carry the startup sync themselves
+    pub fn send_over(&self, py: Python<'_>, transport: &mut dyn Transport) -> PyResult<()> {
+        let bytes = self.to_bytes();
+        transport.write_message(py, &mut |buf| {
+            if buf.len() < bytes.len() {
+                return Err(overflow_err("handshake", bytes.len(), buf.len()));
+            }
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        })?;
+        transport.send(py)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> PyResult<Self> {
+        // Builds from before the handshake was introduced sync with a single byte
+        if buf.len() < size_of::<usize>() {
+            return Ok(Handshake {
+                protocol_version: 0,
+                crate_version: "<unknown>".to_string(),
+                serde_fingerprint_list: Vec::new(),
+                setting_list: Vec::new(),
+            });
+        }
+        let (protocol_version, mut offset) = retrieve_usize(buf, 0)?;
+        // The rest of the handshake may have a different layout in other protocol versions
+        if protocol_version != PROTOCOL_VERSION {
+            return Ok(Handshake {
+                protocol_version,
+                crate_version: "<unknown>".to_string(),
+                serde_fingerprint_list: Vec::new(),
+                setting_list: Vec::new(),
+            });
+        }
+        let crate_version;
+        (crate_version, offset) = retrieve_string(buf, offset)?;
+        let n_serdes;
+        (n_serdes, offset) = retrieve_usize(buf, offset)?;
+        let mut serde_fingerprint_list = Vec::with_capacity(n_serdes);
+        for _ in 0..n_serdes {
+            let name;
+            (name, offset) = retrieve_string(buf, offset)?;
+            let fingerprint;
+            (fingerprint, offset) = retrieve_u64(buf, offset)?;
+            serde_fingerprint_list.push((name, fingerprint));
+        }
+        let n_settings;
+        (n_settings, offset) = retrieve_usize(buf, offset)?;
+        let mut setting_list = Vec::with_capacity(n_settings);
+        for _ in 0..n_settings {
+            let name;
+            (name, offset) = retrieve_string(buf, offset)?;
+            let value;
+            (value, offset) = retrieve_string(buf, offset)?;
+            setting_list.push((name, value));
+        }
+        Ok(Handshake {
+            protocol_version,
+            crate_version,
+            serde_fingerprint_list,
+            setting_list,
+        })
+    }
+
+    // Returns a ProtocolMismatchError listing every difference between this handshake and the other side's
+    pub fn check(&self, other: &Handshake, this_side: &str, other_side: &str) -> PyResult<()> {
+        if self == other {
+            return Ok(());
+        }
+        let mut diff_list = Vec::new();
+        if self.protocol_version != other.protocol_version {
+            diff_list.push(format!(
+                "- protocol version: {} uses {}, {} uses {}",
+                this_side, self.protocol_version, other_side, other.protocol_version
+            ));
+        }
+        if self.crate_version != other.crate_version {
+            diff_list.push(format!(
+                "- rlgym-learn version: {} uses {}, {} uses {}",
+                this_side, self.crate_version, other_side, other.crate_version
+            ));
+        }
+        if self.protocol_version == other.protocol_version {
+            for (name, fingerprint) in self.serde_fingerprint_list.iter() {
+                match other
+                    .serde_fingerprint_list
+                    .iter()
+                    .find(|(other_name, _)| other_name == name)
+                {
+                    Some((_, other_fingerprint)) if other_fingerprint == fingerprint => (),
+                    Some((_, other_fingerprint)) => diff_list.push(format!(
+                        "- {}: {} has fingerprint {:016x}, {} has fingerprint {:016x}",
+                        name, this_side, fingerprint, other_side, other_fingerprint
+                    )),
+                    None => diff_list.push(format!(
+                        "- {}: configured in {} but missing in {}",
+                        name, this_side, other_side
+                    )),
+                }
+            }
+            for (other_name, _) in other.serde_fingerprint_list.iter() {
+                if !self
+                    .serde_fingerprint_list
+                    .iter()
+                    .any(|(name, _)| name == other_name)
+                {
+                    diff_list.push(format!(
+                        "- {}: configured in {} but missing in {}",
+                        other_name, other_side, this_side
+                    ));
+                }
+            }
+            for (name, value) in self.setting_list.iter() {
+                let other_value = other
+                    .setting_list
+                    .iter()
+                    .find(|(other_name, _)| other_name == name)
+                    .map_or("<unset>", |(_, other_value)| other_value.as_str());
+                if other_value != value {
+                    diff_list.push(format!(
+                        "- {}: {} uses {}, {} uses {}",
+                        name, this_side, value, other_side, other_value
+                    ));
+                }
+            }
+        }
+        Err(ProtocolMismatchError::new_err(format!(
+            "{} and {} are incompatible:\n{}",
+            this_side,
+            other_side,
+            diff_list.join("\n")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mismatch_message(handshake: &Handshake, other: &Handshake) -> String {
+        Python::with_gil(|py| {
+            let err = handshake
+                .check(other, "This env process", "the EnvProcessInterface")
+                .unwrap_err();
+            assert!(err.is_instance_of::<ProtocolMismatchError>(py));
+            err.value(py).to_string()
+        })
+    }
+
+    #[test]
+    fn mismatch_lists_every_difference() {
+        pyo3::prepare_freethreaded_python();
+        let handshake = Handshake::new(
+            vec![("agent_id_serde", 1), ("obs_serde", 2)],
+            vec![
+                ("n_envs_per_process", "1".to_string()),
+                ("transport", "shm".to_string()),
+            ],
+        );
+        let other = Handshake::new(
+            vec![("agent_id_serde", 1), ("obs_serde", 3), ("state_serde", 4)],
+            vec![
+                ("n_envs_per_process", "2".to_string()),
+                ("transport", "shm".to_string()),
+            ],
+        );
+        let other = Handshake::from_bytes(&other.to_bytes()).unwrap();
+        assert!(handshake
+            .check(
+                &Handshake::from_bytes(&handshake.to_bytes()).unwrap(),
+                "This env process",
+                "the EnvProcessInterface"
+            )
+            .is_ok());
+        assert_eq!(
+            mismatch_message(&handshake, &other),
+            "This env process and the EnvProcessInterface are incompatible:\n\
+             - obs_serde: This env process has fingerprint 0000000000000002, the EnvProcessInterface has fingerprint \
+             0000000000000003\n\
+             - state_serde: configured in the EnvProcessInterface but missing in This env process\n\
+             - n_envs_per_process: This env process uses 1, the EnvProcessInterface uses 2"
+        );
+    }
+
+    #[test]
+    fn mismatch_with_other_protocol_version_only_compares_versions() {
+        pyo3::prepare_freethreaded_python();
+        let handshake = Handshake::new(vec![("agent_id_serde", 1)], Vec::new());
+        let mut other_bytes = Handshake::new(vec![("agent_id_serde", 2)], Vec::new()).to_bytes();
+        other_bytes[..size_of::<usize>()].copy_from_slice(&(PROTOCOL_VERSION + 1).to_ne_bytes());
+        assert_eq!(
+            mismatch_message(&handshake, &Handshake::from_bytes(&other_bytes).unwrap()),
+            format!(
+                "This env process and the EnvProcessInterface are incompatible:\n\
+                 - protocol version: This env process uses {}, the EnvProcessInterface uses {}\n\
+                 - rlgym-learn version: This env process uses {}, the EnvProcessInterface uses <unknown>",
+                PROTOCOL_VERSION,
+                PROTOCOL_VERSION + 1,
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        // Builds from before the handshake was introduced sync with a single byte
+        assert!(
+            mismatch_message(&handshake, &Handshake::from_bytes(&[0]).unwrap()).contains(&format!(
+                "- protocol version: This env process uses {}, the EnvProcessInterface uses 0",
+                PROTOCOL_VERSION
+            ))
+        );
+    }
+}
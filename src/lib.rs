@@ -4,13 +4,23 @@ extern crate rayon;
 
 pub mod agent_manager;
 pub mod env_action;
+pub mod env_error;
 pub mod env_process;
+pub mod env_process_config;
 pub mod env_process_interface;
+pub mod flink_lock;
+pub mod handshake;
+pub mod min_process_steps_tuner;
 pub mod misc;
+pub mod notification;
+pub mod raw_decode;
+pub mod shm_buffer;
 // pub mod pyany_serde_extension;
 // pub mod pyany_serde_type_extension;
 pub mod standard_impl;
 pub mod synchronization;
+pub mod telemetry;
+pub mod transport;
 
 #[pymodule]
 #[pyo3(name = "rlgym_learn")]
@@ -19,6 +29,7 @@ fn rlgym_learn(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(synchronization::recvfrom_byte_py, m)?)?;
     m.add_function(wrap_pyfunction!(synchronization::sendto_byte_py, m)?)?;
     m.add_class::<env_process_interface::EnvProcessInterface>()?;
+    m.add_class::<env_process_interface::StepLatencyStats>()?;
     m.add_class::<agent_manager::AgentManager>()?;
     m.add_class::<standard_impl::ppo::gae_trajectory_processor::GAETrajectoryProcessor>()?;
     m.add_class::<standard_impl::ppo::gae_trajectory_processor::DerivedGAETrajectoryProcessorConfig>()?;
@@ -26,6 +37,18 @@ fn rlgym_learn(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<env_action::EnvActionResponse>()?;
     m.add_class::<env_action::EnvActionResponseType>()?;
     m.add_class::<env_action::EnvAction>()?;
+    m.add(
+        "EnvProcessError",
+        m.py().get_type::<env_error::EnvProcessError>(),
+    )?;
+    m.add(
+        "ShmBufferOverflowError",
+        m.py().get_type::<shm_buffer::ShmBufferOverflowError>(),
+    )?;
+    m.add(
+        "ProtocolMismatchError",
+        m.py().get_type::<handshake::ProtocolMismatchError>(),
+    )?;
     m.add_class::<pyany_serde::PyAnySerdeType>()?;
     m.add_class::<pyany_serde::PickleablePyAnySerdeType>()?;
     m.add_class::<pyany_serde::pyany_serde_impl::InitStrategy>()?;
@@ -0,0 +1,141 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// A collection, along with the time spent on inference between the end of the previous collection and its start
+struct CollectionSample {
+    n_timesteps: usize,
+    collect_time: Duration,
+    inference_time: Duration,
+}
+
+// Adjusts min_process_steps_per_inference to maximize timesteps collected per second. Throughput and the split between
+// waiting on envs and inference are measured over a window sliding over the most recent collections. Once the window
+// only holds collections made with the current value, the value is moved, and it keeps moving in the same direction as
+// long as throughput improves. The first move is towards smaller batches if more time is spent waiting on envs than on
+// inference (which happens between collections), and towards larger batches otherwise.
+pub struct MinProcessStepsTuner {
+    window_size: usize,
+    samples: VecDeque<CollectionSample>,
+    last_collect_end_option: Option<Instant>,
+    n_collections_since_decision: usize,
+    direction_option: Option<isize>,
+    prev_steps_per_second_option: Option<f64>,
+    metrics: HashMap<String, f64>,
+}
+
+impl MinProcessStepsTuner {
+    pub fn new(window_size: usize) -> Self {
+        let window_size = max(window_size, 1);
+        MinProcessStepsTuner {
+            window_size,
+            samples: VecDeque::with_capacity(window_size + 1),
+            last_collect_end_option: None,
+            n_collections_since_decision: 0,
+            direction_option: None,
+            prev_steps_per_second_option: None,
+            metrics: HashMap::new(),
+        }
+    }
+
+    // Called at the end of each collection. Returns the new min_process_steps_per_inference when a decision is made.
+    pub fn record_collection(
+        &mut self,
+        collect_start: Instant,
+        n_timesteps: usize,
+        min_process_steps_per_inference: usize,
+        n_envs: usize,
+    ) -> Option<usize> {
+        let now = Instant::now();
+        // The first collection has no previous one to measure the inference time from
+        let last_collect_end = self.last_collect_end_option.replace(now)?;
+        self.samples.push_back(CollectionSample {
+            n_timesteps,
+            collect_time: now - collect_start,
+            inference_time: collect_start.saturating_duration_since(last_collect_end),
+        });
+        if self.samples.len() > self.window_size {
+            self.samples.pop_front();
+        }
+        self.n_collections_since_decision += 1;
+        if self.samples.len() < self.window_size {
+            return None;
+        }
+
+        let (n_window_timesteps, collect_time, inference_time) = self.samples.iter().fold(
+            (0, Duration::ZERO, Duration::ZERO),
+            |(n_timesteps, collect_time, inference_time), sample| {
+                (
+                    n_timesteps + sample.n_timesteps,
+                    collect_time + sample.collect_time,
+                    inference_time + sample.inference_time,
+                )
+            },
+        );
+        let window_time = (collect_time + inference_time).as_secs_f64();
+        let steps_per_second = n_window_timesteps as f64 / window_time;
+        let collect_time_fraction = collect_time.as_secs_f64() / window_time;
+        self.metrics.extend([
+            ("steps_per_second".to_string(), steps_per_second),
+            ("collect_time_fraction".to_string(), collect_time_fraction),
+            (
+                "min_process_steps_per_inference".to_string(),
+                min_process_steps_per_inference as f64,
+            ),
+        ]);
+        // The window slides over collections made with the previous value until it has fully moved past them
+        if self.n_collections_since_decision < self.window_size {
+            return None;
+        }
+
+        let direction = match (self.direction_option, self.prev_steps_per_second_option) {
+            (None, _) | (_, None) => {
+                if collect_time_fraction > 0.5 {
+                    -1
+                } else {
+                    1
+                }
+            }
+            (Some(direction), Some(prev_steps_per_second)) => {
+                if steps_per_second >= prev_steps_per_second {
+                    direction
+                } else {
+                    -direction
+                }
+            }
+        };
+        let step = max(1, min_process_steps_per_inference / 8) as isize;
+        let new_min_process_steps_per_inference = min(
+            max(
+                min_process_steps_per_inference as isize + direction * step,
+                1,
+            ) as usize,
+            max(n_envs, 1),
+        );
+
+        self.metrics.extend([
+            (
+                "min_process_steps_per_inference".to_string(),
+                new_min_process_steps_per_inference as f64,
+            ),
+            (
+                "previous_steps_per_second".to_string(),
+                self.prev_steps_per_second_option.unwrap_or(f64::NAN),
+            ),
+            (
+                "change".to_string(),
+                new_min_process_steps_per_inference as f64 - min_process_steps_per_inference as f64,
+            ),
+        ]);
+        self.direction_option = Some(direction);
+        self.prev_steps_per_second_option = Some(steps_per_second);
+        self.n_collections_since_decision = 0;
+        Some(new_min_process_steps_per_inference)
+    }
+
+    // The measurements over the current window, the current value, and the last decision (the throughput it was
+    // compared against and the change it made). Empty until the window first fills.
+    pub fn metrics(&self) -> &HashMap<String, f64> {
+        &self.metrics
+    }
+}
@@ -0,0 +1,228 @@
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use pyo3::exceptions::asyncio::InvalidStateError;
+#[cfg(not(unix))]
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::synchronization::{get_flink, recvfrom_byte_timeout, sendto_byte};
+
+// Env processes tell the EnvProcessInterface that a response is ready in shared memory by sending it a byte. By
+// default this goes through the Python sockets used during startup. Native signalling instead sends the byte over a
+// Unix datagram socket bound next to the shmem flink, so that neither side needs the GIL to signal or wait.
+
+#[cfg(unix)]
+fn notification_path(flinks_folder: &str, proc_id: &str) -> String {
+    format!("{}.sock", get_flink(flinks_folder, proc_id))
+}
+
+fn io_err(context: &str, err: std::io::Error) -> PyErr {
+    InvalidStateError::new_err(format!("{}: {}", context, err))
+}
+
+#[cfg(not(unix))]
+fn native_signalling_unsupported() -> PyErr {
+    PyValueError::new_err("native_signalling is only supported on unix platforms")
+}
+
+// Used by env processes
+pub enum Notifier {
+    PySocket {
+        socket: PyObject,
+        address: PyObject,
+    },
+    #[cfg(unix)]
+    Native {
+        socket: UnixDatagram,
+        path: String,
+    },
+}
+
+impl Notifier {
+    pub fn new(
+        native_signalling: bool,
+        socket: PyObject,
+        address: PyObject,
+        flinks_folder: &str,
+        proc_id: &str,
+    ) -> PyResult<Self> {
+        if !native_signalling {
+            return Ok(Notifier::PySocket { socket, address });
+        }
+        #[cfg(unix)]
+        {
+            // The EnvProcessInterface may not have bound its end yet, so we send to the path rather than connecting
+            Ok(Notifier::Native {
+                socket: UnixDatagram::unbound()
+                    .map_err(|err| io_err("Unable to create notification socket", err))?,
+                path: notification_path(flinks_folder, proc_id),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (flinks_folder, proc_id);
+            Err(native_signalling_unsupported())
+        }
+    }
+
+    pub fn notify<'py>(&self, py: Python<'py>) -> PyResult<()> {
+        match self {
+            Notifier::PySocket { socket, address } => sendto_byte(py, socket, address),
+            #[cfg(unix)]
+            Notifier::Native { socket, path } => {
+                socket.send_to(&[0], path).map_err(|err| {
+                    io_err(&format!("Unable to send notification to {}", path), err)
+                })?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// Used by the EnvProcessInterface, one per env process
+pub enum NotificationReceiver {
+    PySocket(PyObject),
+    #[cfg(unix)]
+    Native {
+        socket: UnixDatagram,
+        path: String,
+    },
+}
+
+impl NotificationReceiver {
+    // This needs to happen before syncing with the env process, since it starts sending notifications right after
+    pub fn new(
+        native_signalling: bool,
+        socket: PyObject,
+        flinks_folder: &str,
+        proc_id: &str,
+    ) -> PyResult<Self> {
+        if !native_signalling {
+            return Ok(NotificationReceiver::PySocket(socket));
+        }
+        #[cfg(unix)]
+        {
+            let path = notification_path(flinks_folder, proc_id);
+            // A socket file left behind by a previous run would make bind fail
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).map_err(|err| {
+                io_err(&format!("Unable to bind notification socket {}", path), err)
+            })?;
+            Ok(NotificationReceiver::Native { socket, path })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (flinks_folder, proc_id);
+            Err(native_signalling_unsupported())
+        }
+    }
+
+    pub fn py_socket(&self) -> Option<&PyObject> {
+        match self {
+            NotificationReceiver::PySocket(socket) => Some(socket),
+            #[cfg(unix)]
+            NotificationReceiver::Native { .. } => None,
+        }
+    }
+
+    pub fn raw_fd(&self) -> Option<i32> {
+        match self {
+            NotificationReceiver::PySocket(_) => None,
+            #[cfg(unix)]
+            NotificationReceiver::Native { socket, .. } => Some(socket.as_raw_fd()),
+        }
+    }
+
+    // Waits for a notification, giving up after the timeout (if provided). Returns false if the timeout elapsed.
+    pub fn recv_timeout<'py>(
+        &self,
+        py: Python<'py>,
+        timeout_option: Option<Duration>,
+    ) -> PyResult<bool> {
+        match self {
+            NotificationReceiver::PySocket(socket) => {
+                Ok(recvfrom_byte_timeout(py, socket, timeout_option)?.is_some())
+            }
+            #[cfg(unix)]
+            NotificationReceiver::Native { socket, path } => {
+                socket
+                    .set_read_timeout(timeout_option)
+                    .map_err(|err| io_err("Unable to set notification socket timeout", err))?;
+                let mut buf = [0_u8; 1];
+                match py.allow_threads(|| socket.recv(&mut buf)) {
+                    Ok(_) => Ok(true),
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        Ok(false)
+                    }
+                    Err(err) => Err(io_err(
+                        &format!("Unable to receive notification on {}", path),
+                        err,
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for NotificationReceiver {
+    fn drop(&mut self) {
+        if let NotificationReceiver::Native { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Waits up to timeout for any of the file descriptors to become readable, returning the indices of those which did.
+// Used to wait on native notification sockets and TCP connections.
+#[cfg(unix)]
+pub fn poll_fds<'py>(py: Python<'py>, fd_list: &[i32], timeout: Duration) -> PyResult<Vec<usize>> {
+    let mut pollfd_list = fd_list
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    let n_ready = py.allow_threads(|| unsafe {
+        libc::poll(
+            pollfd_list.as_mut_ptr(),
+            pollfd_list.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    });
+    if n_ready < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(io_err("Unable to poll notification sockets", err));
+    }
+    Ok(pollfd_list
+        .iter()
+        .enumerate()
+        .filter(|(_, pollfd)| pollfd.revents & (libc::POLLIN | libc::POLLHUP) != 0)
+        .map(|(idx, _)| idx)
+        .collect())
+}
+
+#[cfg(not(unix))]
+pub fn poll_fds<'py>(
+    _py: Python<'py>,
+    _fd_list: &[i32],
+    _timeout: Duration,
+) -> PyResult<Vec<usize>> {
+    Err(PyValueError::new_err(
+        "native_signalling and the TCP transport are only supported on unix platforms",
+    ))
+}
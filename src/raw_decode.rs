@@ -0,0 +1,298 @@
+use std::mem::size_of;
+
+use numpy::ndarray::ArrayD;
+use numpy::IntoPyArray;
+use pyany_serde::common::{get_bytes_to_alignment, NumpyDtype};
+use pyany_serde::communication::{
+    retrieve_bool, retrieve_bytes, retrieve_f64, retrieve_i64, retrieve_usize,
+};
+use pyany_serde::PyAnySerdeType;
+use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::prelude::*;
+use pyo3::IntoPyObjectExt;
+
+// Some serde types have a wire format simple enough to decode without the GIL. This lets the EnvProcessInterface
+// decode the responses of many env processes in parallel and only create the Python objects afterwards.
+
+#[derive(Clone, Copy, Debug)]
+pub enum RawDecoder {
+    Bool,
+    Int,
+    Float,
+    String,
+    Numpy(NumpyDtype),
+}
+
+macro_rules! define_numpy_array {
+    ($($variant:ident => $type:ty),* $(,)?) => {
+        pub enum NumpyArray {
+            $($variant(ArrayD<$type>),)*
+        }
+
+        fn decode_numpy_array(
+            dtype: NumpyDtype,
+            shape: Vec<usize>,
+            buf: &[u8],
+            offset: usize,
+        ) -> PyResult<(NumpyArray, usize)> {
+            match dtype {
+                $(NumpyDtype::$variant => {
+                    // The numpy serde aligns the data relative to the address of the buffer, so we have to decode
+                    // from the same buffer it was written to rather than a copy
+                    let offset =
+                        offset + get_bytes_to_alignment::<$type>(buf.as_ptr() as usize + offset);
+                    let (bytes, offset) = retrieve_bytes(buf, offset)?;
+                    let data = bytes
+                        .chunks_exact(size_of::<$type>())
+                        .map(|chunk| <$type>::from_ne_bytes(chunk.try_into().unwrap()))
+                        .collect::<Vec<_>>();
+                    let array = ArrayD::from_shape_vec(shape, data).map_err(|err| {
+                        InvalidStateError::new_err(format!(
+                            "Failed to create numpy array from shape and data: {}",
+                            err
+                        ))
+                    })?;
+                    Ok((NumpyArray::$variant(array), offset))
+                })*
+            }
+        }
+
+        impl NumpyArray {
+            fn into_py_any<'py>(self, py: Python<'py>) -> PyResult<PyObject> {
+                match self {
+                    $(NumpyArray::$variant(array) => array.into_pyarray(py).into_py_any(py),)*
+                }
+            }
+        }
+    };
+}
+
+define_numpy_array!(
+    INT8 => i8,
+    INT16 => i16,
+    INT32 => i32,
+    INT64 => i64,
+    UINT8 => u8,
+    UINT16 => u16,
+    UINT32 => u32,
+    UINT64 => u64,
+    FLOAT32 => f32,
+    FLOAT64 => f64,
+);
+
+// A value decoded without the GIL, which still needs to be converted to a Python object
+pub enum RawValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Numpy(NumpyArray),
+}
+
+impl RawValue {
+    pub fn into_py_any<'py>(self, py: Python<'py>) -> PyResult<PyObject> {
+        match self {
+            RawValue::Bool(val) => val.into_py_any(py),
+            RawValue::Int(val) => val.into_py_any(py),
+            RawValue::Float(val) => val.into_py_any(py),
+            RawValue::String(val) => val.into_py_any(py),
+            RawValue::Numpy(array) => array.into_py_any(py),
+        }
+    }
+}
+
+impl RawDecoder {
+    // Returns None if values of this serde type can only be decoded under the GIL
+    pub fn from_serde_type(serde_type: &PyAnySerdeType) -> Option<Self> {
+        match serde_type {
+            PyAnySerdeType::BOOL {} => Some(RawDecoder::Bool),
+            PyAnySerdeType::INT {} => Some(RawDecoder::Int),
+            PyAnySerdeType::FLOAT {} => Some(RawDecoder::Float),
+            PyAnySerdeType::STRING {} => Some(RawDecoder::String),
+            PyAnySerdeType::NUMPY { dtype } => Some(RawDecoder::Numpy(*dtype)),
+            _ => None,
+        }
+    }
+
+    pub fn decode(&self, buf: &[u8], offset: usize) -> PyResult<(RawValue, usize)> {
+        Ok(match self {
+            RawDecoder::Bool => {
+                let (val, offset) = retrieve_bool(buf, offset)?;
+                (RawValue::Bool(val), offset)
+            }
+            RawDecoder::Int => {
+                let (val, offset) = retrieve_i64(buf, offset)?;
+                (RawValue::Int(val), offset)
+            }
+            RawDecoder::Float => {
+                let (val, offset) = retrieve_f64(buf, offset)?;
+                (RawValue::Float(val), offset)
+            }
+            RawDecoder::String => {
+                let (bytes, offset) = retrieve_bytes(buf, offset)?;
+                (RawValue::String(String::from_utf8(bytes.to_vec())?), offset)
+            }
+            RawDecoder::Numpy(dtype) => {
+                let (shape_len, mut offset) = retrieve_usize(buf, offset)?;
+                let mut shape = Vec::with_capacity(shape_len);
+                for _ in 0..shape_len {
+                    let dim;
+                    (dim, offset) = retrieve_usize(buf, offset)?;
+                    shape.push(dim);
+                }
+                let (array, offset) = decode_numpy_array(*dtype, shape, buf, offset)?;
+                (RawValue::Numpy(array), offset)
+            }
+        })
+    }
+}
+
+// The agent data of a response, decoded without the GIL
+pub struct RawAgentData {
+    pub agent_id_list_option: Option<Vec<RawValue>>,
+    pub obs_list: Vec<RawValue>,
+    pub reward_list_option: Option<Vec<RawValue>>,
+    pub terminated_list_option: Option<Vec<bool>>,
+    pub truncated_list_option: Option<Vec<bool>>,
+    // Where the rest of the response (state and state metrics) starts
+    pub offset: usize,
+}
+
+// Describes how to decode the agent data of one response
+pub struct RawAgentDataDecoder {
+    pub agent_id_decoder_option: Option<RawDecoder>,
+    pub obs_decoder: RawDecoder,
+    pub reward_decoder_option: Option<RawDecoder>,
+}
+
+impl RawAgentDataDecoder {
+    pub fn decode(&self, buf: &[u8], offset: usize, n_agents: usize) -> PyResult<RawAgentData> {
+        let mut offset = offset;
+        let mut agent_id_list_option = self
+            .agent_id_decoder_option
+            .map(|_| Vec::with_capacity(n_agents));
+        let mut obs_list = Vec::with_capacity(n_agents);
+        let mut reward_list_option = self
+            .reward_decoder_option
+            .map(|_| Vec::with_capacity(n_agents));
+        let mut terminated_list_option = self
+            .reward_decoder_option
+            .map(|_| Vec::with_capacity(n_agents));
+        let mut truncated_list_option = self
+            .reward_decoder_option
+            .map(|_| Vec::with_capacity(n_agents));
+        for _ in 0..n_agents {
+            if let Some(agent_id_decoder) = &self.agent_id_decoder_option {
+                let agent_id;
+                (agent_id, offset) = agent_id_decoder.decode(buf, offset)?;
+                agent_id_list_option.as_mut().unwrap().push(agent_id);
+            }
+            let obs;
+            (obs, offset) = self.obs_decoder.decode(buf, offset)?;
+            obs_list.push(obs);
+            if let Some(reward_decoder) = &self.reward_decoder_option {
+                let reward;
+                (reward, offset) = reward_decoder.decode(buf, offset)?;
+                reward_list_option.as_mut().unwrap().push(reward);
+                let terminated;
+                (terminated, offset) = retrieve_bool(buf, offset)?;
+                terminated_list_option.as_mut().unwrap().push(terminated);
+                let truncated;
+                (truncated, offset) = retrieve_bool(buf, offset)?;
+                truncated_list_option.as_mut().unwrap().push(truncated);
+            }
+        }
+        Ok(RawAgentData {
+            agent_id_list_option,
+            obs_list,
+            reward_list_option,
+            terminated_list_option,
+            truncated_list_option,
+            offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pyany_serde::communication::append_bool;
+    use pyany_serde::PyAnySerde;
+
+    use super::*;
+
+    #[test]
+    fn raw_decoding_matches_serde_retrieve() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let case_list = [
+                (PyAnySerdeType::BOOL {}, c"True"),
+                (PyAnySerdeType::INT {}, c"-12345678901"),
+                (PyAnySerdeType::FLOAT {}, c"-1.5e300"),
+                (PyAnySerdeType::STRING {}, c"'agent_\u{e9}'"),
+            ];
+            let mut buf = vec![0; 1024];
+            for (serde_type, obj) in case_list {
+                let decoder = RawDecoder::from_serde_type(&serde_type).unwrap();
+                let serde: Box<dyn PyAnySerde> = (&serde_type).try_into().unwrap();
+                let obj = py.eval(obj, None, None).unwrap();
+                // Written after a value so the decoder has to start from the offset it's given
+                let offset = serde.append(&mut buf, 0, &obj).unwrap();
+                let end = serde.append(&mut buf, offset, &obj).unwrap();
+                let (retrieved, retrieved_end) = serde.retrieve(py, &buf, offset).unwrap();
+                let (raw_value, raw_end) = decoder.decode(&buf, offset).unwrap();
+                assert_eq!(raw_end, end);
+                assert_eq!(retrieved_end, end);
+                let decoded = raw_value.into_py_any(py).unwrap().into_bound(py);
+                assert!(decoded.get_type().is(&retrieved.get_type()));
+                assert!(decoded.eq(&retrieved).unwrap());
+                assert!(decoded.eq(&obj).unwrap());
+            }
+        })
+    }
+
+    #[test]
+    fn raw_numpy_decoding_matches_serde_retrieve() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // Only checked where the Python the tests link against has numpy installed
+            let Ok(np) = PyModule::import(py, "numpy") else {
+                return;
+            };
+            let mut buf = vec![0; 1024];
+            for (dtype, dtype_name) in [
+                (NumpyDtype::UINT8, "uint8"),
+                (NumpyDtype::FLOAT32, "float32"),
+                (NumpyDtype::INT64, "int64"),
+            ] {
+                let serde_type = PyAnySerdeType::NUMPY { dtype };
+                let decoder = RawDecoder::from_serde_type(&serde_type).unwrap();
+                let serde: Box<dyn PyAnySerde> = (&serde_type).try_into().unwrap();
+                let obj = np
+                    .call_method1("arange", (6,))
+                    .unwrap()
+                    .call_method1("astype", (dtype_name,))
+                    .unwrap()
+                    .call_method1("reshape", ((2, 3),))
+                    .unwrap();
+                // Starting at an odd offset makes the serde pad the data to the alignment of its dtype
+                let offset = append_bool(&mut buf, 0, true);
+                let end = serde.append(&mut buf, offset, &obj).unwrap();
+                let (retrieved, retrieved_end) = serde.retrieve(py, &buf, offset).unwrap();
+                let (raw_value, raw_end) = decoder.decode(&buf, offset).unwrap();
+                assert_eq!(raw_end, end);
+                assert_eq!(retrieved_end, end);
+                let decoded = raw_value.into_py_any(py).unwrap().into_bound(py);
+                assert!(decoded
+                    .getattr("dtype")
+                    .unwrap()
+                    .eq(retrieved.getattr("dtype").unwrap())
+                    .unwrap());
+                assert!(np
+                    .call_method1("array_equal", (&decoded, &retrieved))
+                    .unwrap()
+                    .is_truthy()
+                    .unwrap());
+            }
+        })
+    }
+}
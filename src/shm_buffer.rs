@@ -0,0 +1,697 @@
+use std::cell::RefCell;
+use std::mem::{align_of, replace, size_of};
+use std::ops::Deref;
+
+use numpy::{PyUntypedArray, PyUntypedArrayMethods};
+use pyany_serde::common::NumpyDtype;
+use pyany_serde::communication::{append_bool, append_bytes, append_usize, retrieve_usize};
+use pyany_serde::{DynPyAnySerdeOption, PyAnySerde, PyAnySerdeType};
+use pyo3::create_exception;
+use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList, PySet, PyString, PyTuple};
+use raw_sync::events::{Event, EventImpl, EventInit, EventState};
+use shared_memory::{Shmem, ShmemConf};
+
+use crate::handshake::Fingerprinted;
+use crate::synchronization::{append_header, get_flink, retrieve_header, Header};
+
+create_exception!(rlgym_learn, ShmBufferOverflowError, PyException);
+
+// Upper bound on the size of a message, which buffers don't grow beyond
+pub const MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+// Serdes which write numpy arrays pad them to the alignment of their dtype, measured from the address they are written
+// to. Payloads are written to the scratch buffer at the same address modulo this, so they can be copied as is.
+const MAX_SERDE_ALIGNMENT: usize = 64;
+
+thread_local! {
+    // PyAnySerde implementations index directly into the buffer they're given and panic if it's too short. Payloads
+    // whose size can't be worked out from their serde type are written here first and only copied once they're known
+    // to fit. It's only allocated the first time it's needed, and zero-allocated, so only the pages payloads have
+    // actually used take up memory.
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// A serde along with the serde type it was built from, which lets append_checked work out the size of most payloads
+// before writing them
+#[derive(Clone)]
+pub struct CheckedSerde {
+    serde: Box<dyn PyAnySerde>,
+    serde_type_option: Option<PyAnySerdeType>,
+}
+
+impl CheckedSerde {
+    pub fn new(serde: Box<dyn PyAnySerde>, serde_type_option: Option<PyAnySerdeType>) -> Self {
+        CheckedSerde {
+            serde,
+            serde_type_option,
+        }
+    }
+}
+
+impl Deref for CheckedSerde {
+    type Target = dyn PyAnySerde;
+
+    fn deref(&self) -> &Self::Target {
+        self.serde.as_ref()
+    }
+}
+
+impl From<Fingerprinted<Box<dyn PyAnySerde>>> for CheckedSerde {
+    fn from(value: Fingerprinted<Box<dyn PyAnySerde>>) -> Self {
+        CheckedSerde::new(value.value, value.serde_type_option)
+    }
+}
+
+impl From<Fingerprinted<DynPyAnySerdeOption>> for Option<CheckedSerde> {
+    fn from(value: Fingerprinted<DynPyAnySerdeOption>) -> Self {
+        match value.value {
+            DynPyAnySerdeOption::Some(serde) => {
+                Some(CheckedSerde::new(serde, value.serde_type_option))
+            }
+            DynPyAnySerdeOption::None => None,
+        }
+    }
+}
+
+macro_rules! numpy_dtype_layout {
+    ($dtype:expr, $($variant:ident => $type:ty),* $(,)?) => {
+        match $dtype {
+            $(NumpyDtype::$variant => (size_of::<$type>(), align_of::<$type>()),)*
+        }
+    };
+}
+
+// Sums the sizes of items written one after another, starting at addr
+fn items_size<'a, 'py>(
+    items: impl Iterator<Item = (&'a PyAnySerdeType, Bound<'py, PyAny>)>,
+    addr: usize,
+) -> PyResult<Option<usize>> {
+    let mut n_bytes = 0;
+    for (item_serde_type, item) in items {
+        match serde_type_size(item_serde_type, addr + n_bytes, &item)? {
+            Some(item_n_bytes) => n_bytes += item_n_bytes,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(n_bytes))
+}
+
+// Returns the number of bytes the serde built from serde_type writes for obj when it starts writing at addr, or None
+// if that depends on Python code or obj doesn't have the type the serde expects
+fn serde_type_size<'py>(
+    serde_type: &PyAnySerdeType,
+    addr: usize,
+    obj: &Bound<'py, PyAny>,
+) -> PyResult<Option<usize>> {
+    let py = obj.py();
+    Ok(match serde_type {
+        PyAnySerdeType::BOOL {} => Some(size_of::<u8>()),
+        PyAnySerdeType::INT {} => Some(size_of::<i64>()),
+        PyAnySerdeType::FLOAT {} => Some(size_of::<f64>()),
+        PyAnySerdeType::COMPLEX {} => Some(2 * size_of::<f64>()),
+        PyAnySerdeType::BYTES {} => obj
+            .downcast::<PyBytes>()
+            .ok()
+            .map(|bytes| size_of::<usize>() + bytes.as_bytes().len()),
+        PyAnySerdeType::STRING {} => match obj.downcast::<PyString>() {
+            Ok(string) => string
+                .to_str()
+                .ok()
+                .map(|string| size_of::<usize>() + string.len()),
+            Err(_) => None,
+        },
+        PyAnySerdeType::NUMPY { dtype } => match obj.downcast::<PyUntypedArray>() {
+            Ok(array) => {
+                let (item_size, alignment) = numpy_dtype_layout!(
+                    dtype,
+                    INT8 => i8,
+                    INT16 => i16,
+                    INT32 => i32,
+                    INT64 => i64,
+                    UINT8 => u8,
+                    UINT16 => u16,
+                    UINT32 => u32,
+                    UINT64 => u64,
+                    FLOAT32 => f32,
+                    FLOAT64 => f64,
+                );
+                let header_size = (1 + array.ndim()) * size_of::<usize>();
+                let padding = (alignment - (addr + header_size) % alignment) % alignment;
+                Some(header_size + padding + size_of::<usize>() + array.len() * item_size)
+            }
+            Err(_) => None,
+        },
+        PyAnySerdeType::OPTION { value_serde_type } => {
+            if obj.is_none() {
+                Some(size_of::<u8>())
+            } else {
+                serde_type_size(&value_serde_type.borrow(py), addr + size_of::<u8>(), obj)?
+                    .map(|n_bytes| size_of::<u8>() + n_bytes)
+            }
+        }
+        PyAnySerdeType::LIST { items_serde_type } => match obj.downcast::<PyList>() {
+            Ok(list) => {
+                let items_serde_type = items_serde_type.borrow(py);
+                items_size(
+                    list.iter().map(|item| (&*items_serde_type, item)),
+                    addr + size_of::<usize>(),
+                )?
+                .map(|n_bytes| size_of::<usize>() + n_bytes)
+            }
+            Err(_) => None,
+        },
+        PyAnySerdeType::SET { items_serde_type } => match obj.downcast::<PySet>() {
+            Ok(set) => {
+                let items_serde_type = items_serde_type.borrow(py);
+                items_size(
+                    set.iter().map(|item| (&*items_serde_type, item)),
+                    addr + size_of::<usize>(),
+                )?
+                .map(|n_bytes| size_of::<usize>() + n_bytes)
+            }
+            Err(_) => None,
+        },
+        PyAnySerdeType::DICT {
+            keys_serde_type,
+            values_serde_type,
+        } => match obj.downcast::<PyDict>() {
+            Ok(dict) => {
+                let keys_serde_type = keys_serde_type.borrow(py);
+                let values_serde_type = values_serde_type.borrow(py);
+                items_size(
+                    dict.iter().flat_map(|(key, value)| {
+                        [(&*keys_serde_type, key), (&*values_serde_type, value)]
+                    }),
+                    addr + size_of::<usize>(),
+                )?
+                .map(|n_bytes| size_of::<usize>() + n_bytes)
+            }
+            Err(_) => None,
+        },
+        PyAnySerdeType::TUPLE { item_serde_types } => match obj.downcast::<PyTuple>() {
+            Ok(tuple) => items_size(item_serde_types.iter().zip(tuple.iter()), addr)?,
+            Err(_) => None,
+        },
+        PyAnySerdeType::TYPEDDICT {
+            key_serde_type_dict,
+        } => {
+            let mut item_list = Vec::with_capacity(key_serde_type_dict.len());
+            for (key, item_serde_type) in key_serde_type_dict.iter() {
+                match obj.get_item(key) {
+                    Ok(item) => item_list.push((item_serde_type, item)),
+                    Err(_) => return Ok(None),
+                }
+            }
+            items_size(item_list.into_iter(), addr)?
+        }
+        _ => None,
+    })
+}
+
+// Writes obj to the scratch buffer as if it were written at addr, returning the scratch offset it starts at and the
+// number of bytes written
+fn append_scratch<'py>(
+    scratch: &mut Vec<u8>,
+    addr: usize,
+    serde: &dyn PyAnySerde,
+    obj: &Bound<'py, PyAny>,
+) -> PyResult<(usize, usize)> {
+    if scratch.is_empty() {
+        *scratch = vec![0; MAX_MESSAGE_SIZE + MAX_SERDE_ALIGNMENT];
+    }
+    let scratch_addr = scratch.as_ptr() as usize;
+    let start = (addr % MAX_SERDE_ALIGNMENT + MAX_SERDE_ALIGNMENT
+        - scratch_addr % MAX_SERDE_ALIGNMENT)
+        % MAX_SERDE_ALIGNMENT;
+    let end = serde.append(scratch, start, obj)?;
+    Ok((start, end - start))
+}
+
+pub fn overflow_err(payload: &str, required: usize, available: usize) -> PyErr {
+    ShmBufferOverflowError::new_err(format!(
+        "Shared memory buffer overflowed while writing {} payload: {} bytes are required but only {} are available. Increase shm_buffer_size.",
+        payload, required, available
+    ))
+}
+
+// Ensures n_bytes can be written at offset
+pub fn check_capacity(buf: &[u8], offset: usize, n_bytes: usize, payload: &str) -> PyResult<()> {
+    if offset + n_bytes > buf.len() {
+        return Err(overflow_err(payload, offset + n_bytes, buf.len()));
+    }
+    Ok(())
+}
+
+// Returns the number of bytes the serde writes for obj
+pub fn serialized_size<'py>(serde: &CheckedSerde, obj: &Bound<'py, PyAny>) -> PyResult<usize> {
+    if let Some(serde_type) = &serde.serde_type_option {
+        if let Some(n_bytes) = serde_type_size(serde_type, 0, obj)? {
+            return Ok(n_bytes);
+        }
+    }
+    SCRATCH.with_borrow_mut(|scratch| Ok(append_scratch(scratch, 0, &**serde, obj)?.1))
+}
+
+// Like serde.append, but returns a ShmBufferOverflowError describing the payload instead of panicking
+// if obj doesn't fit in buf
+pub fn append_checked<'py>(
+    serde: &CheckedSerde,
+    buf: &mut [u8],
+    offset: usize,
+    obj: &Bound<'py, PyAny>,
+    payload: &str,
+) -> PyResult<usize> {
+    let addr = buf.as_ptr() as usize + offset;
+    if let Some(serde_type) = &serde.serde_type_option {
+        if let Some(n_bytes) = serde_type_size(serde_type, addr, obj)? {
+            check_capacity(buf, offset, n_bytes, payload)?;
+            return serde.append(buf, offset, obj);
+        }
+    }
+    SCRATCH.with_borrow_mut(|scratch| {
+        let (start, n_bytes) = append_scratch(scratch, addr, &**serde, obj)?;
+        check_capacity(buf, offset, n_bytes, payload)?;
+        buf[offset..offset + n_bytes].copy_from_slice(&scratch[start..start + n_bytes]);
+        Ok(offset + n_bytes)
+    })
+}
+
+pub fn append_usize_checked(
+    buf: &mut [u8],
+    offset: usize,
+    val: usize,
+    payload: &str,
+) -> PyResult<usize> {
+    check_capacity(buf, offset, size_of::<usize>(), payload)?;
+    Ok(append_usize(buf, offset, val))
+}
+
+pub fn append_bool_checked(
+    buf: &mut [u8],
+    offset: usize,
+    val: bool,
+    payload: &str,
+) -> PyResult<usize> {
+    check_capacity(buf, offset, size_of::<u8>(), payload)?;
+    Ok(append_bool(buf, offset, val))
+}
+
+pub fn append_bytes_checked(
+    buf: &mut [u8],
+    offset: usize,
+    bytes: &[u8],
+    payload: &str,
+) -> PyResult<usize> {
+    check_capacity(buf, offset, size_of::<usize>() + bytes.len(), payload)?;
+    append_bytes(buf, offset, bytes)
+}
+
+// Env action messages and their responses hold one entry per sub-env of the env process they concern. Each entry
+// starts with the sub-env index and the offset the entry ends at, so the reader can find every entry without
+// decoding the ones before it.
+pub fn append_sub_env_entry(
+    buf: &mut [u8],
+    offset: usize,
+    sub_env_idx: usize,
+    write: impl FnOnce(&mut [u8], usize) -> PyResult<usize>,
+) -> PyResult<usize> {
+    let offset = append_usize_checked(buf, offset, sub_env_idx, "sub-env index")?;
+    let end_offset_offset = offset;
+    let offset = append_usize_checked(buf, offset, 0, "sub-env entry end")?;
+    let end_offset = write(buf, offset)?;
+    append_usize(buf, end_offset_offset, end_offset);
+    Ok(end_offset)
+}
+
+// Returns (sub-env index, offset of the entry's contents, offset the entry ends at)
+pub fn retrieve_sub_env_entry(buf: &[u8], offset: usize) -> PyResult<(usize, usize, usize)> {
+    let (sub_env_idx, offset) = retrieve_usize(buf, offset)?;
+    let (end_offset, offset) = retrieve_usize(buf, offset)?;
+    Ok((sub_env_idx, offset, end_offset))
+}
+
+// Spaces are sent as a count followed by (agent id, space) pairs
+pub fn append_space_dict<'py>(
+    buf: &mut [u8],
+    offset: usize,
+    agent_id_serde: &CheckedSerde,
+    space_serde: &CheckedSerde,
+    space_dict: &Bound<'py, PyDict>,
+    payload: &str,
+) -> PyResult<usize> {
+    let mut offset = append_usize_checked(buf, offset, space_dict.len(), payload)?;
+    for (agent_id, space) in space_dict.iter() {
+        offset = append_checked(agent_id_serde, buf, offset, &agent_id, "agent id")?;
+        offset = append_checked(space_serde, buf, offset, &space, payload)?;
+    }
+    Ok(offset)
+}
+
+pub fn retrieve_space_dict<'py>(
+    py: Python<'py>,
+    buf: &[u8],
+    offset: usize,
+    agent_id_serde: &dyn PyAnySerde,
+    space_serde: &dyn PyAnySerde,
+) -> PyResult<(Bound<'py, PyDict>, usize)> {
+    let (n_agents, mut offset) = retrieve_usize(buf, offset)?;
+    let space_dict = PyDict::new(py);
+    for _ in 0..n_agents {
+        let agent_id;
+        (agent_id, offset) = agent_id_serde.retrieve(py, buf, offset)?;
+        let space;
+        (space, offset) = space_serde.retrieve(py, buf, offset)?;
+        space_dict.set_item(agent_id, space)?;
+    }
+    Ok((space_dict, offset))
+}
+
+fn create_segment(flink: &str, size: usize) -> PyResult<(Shmem, Box<dyn EventImpl>, usize)> {
+    let shmem = ShmemConf::new()
+        .size(size)
+        .flink(flink)
+        .create()
+        .map_err(|err| {
+            InvalidStateError::new_err(format!("Unable to create shmem flink {}: {}", flink, err))
+        })?;
+    let (event, used_bytes) = unsafe {
+        Event::new(shmem.as_ptr(), true).map_err(|err| {
+            InvalidStateError::new_err(format!(
+                "Failed to create event in shmem flink {}: {}",
+                flink, err
+            ))
+        })?
+    };
+    Ok((shmem, event, used_bytes))
+}
+
+fn open_segment(flink: &str) -> PyResult<(Shmem, Box<dyn EventImpl>, usize)> {
+    let shmem = ShmemConf::new().flink(flink).open().map_err(|err| {
+        InvalidStateError::new_err(format!("Unable to open shmem flink {}: {}", flink, err))
+    })?;
+    let (event, used_bytes) = unsafe {
+        Event::from_existing(shmem.as_ptr()).map_err(|err| {
+            InvalidStateError::new_err(format!(
+                "Failed to get event from shmem flink {}: {}",
+                flink, err
+            ))
+        })?
+    };
+    Ok((shmem, event, used_bytes))
+}
+
+// A shared memory segment used to exchange messages with a single env process, starting with an Event used by the
+// EnvProcessInterface to wake the env process. When a message doesn't fit, the writer moves to a segment twice the
+// size and leaves a ShmResize notice in the segment the reader currently knows about, which the reader follows
+// when retrieving the message header. The env process owns every segment, so only it removes their flinks.
+// Requests and responses share the segment rather than using separate regions: the next env action of an env depends
+// on the obs in its last response, which collect_step_data has fully decoded before any env action can be written,
+// so a separate request region would never be in use while the response region is being read.
+pub struct GrowableShmem {
+    flinks_folder: String,
+    proc_id: String,
+    is_owner: bool,
+    generation: usize,
+    shmem: Shmem,
+    event: Box<dyn EventImpl>,
+    event_used_bytes: usize,
+    retired_segment_option: Option<(Shmem, Box<dyn EventImpl>, usize)>,
+}
+
+impl GrowableShmem {
+    // Used by the env process
+    pub fn create(flinks_folder: &str, proc_id: &str, size: usize) -> PyResult<Self> {
+        let (shmem, event, event_used_bytes) =
+            create_segment(&get_flink(flinks_folder, proc_id), size)?;
+        Ok(GrowableShmem {
+            flinks_folder: flinks_folder.to_string(),
+            proc_id: proc_id.to_string(),
+            is_owner: true,
+            generation: 0,
+            shmem,
+            event,
+            event_used_bytes,
+            retired_segment_option: None,
+        })
+    }
+
+    // Used by the EnvProcessInterface
+    pub fn open(flinks_folder: &str, proc_id: &str) -> PyResult<Self> {
+        let (shmem, event, event_used_bytes) = open_segment(&get_flink(flinks_folder, proc_id))?;
+        Ok(GrowableShmem {
+            flinks_folder: flinks_folder.to_string(),
+            proc_id: proc_id.to_string(),
+            is_owner: false,
+            generation: 0,
+            shmem,
+            event,
+            event_used_bytes,
+            retired_segment_option: None,
+        })
+    }
+
+    fn flink(&self, generation: usize) -> String {
+        let flink = get_flink(&self.flinks_folder, &self.proc_id);
+        if generation == 0 {
+            flink
+        } else {
+            format!("{}_{}", flink, generation)
+        }
+    }
+
+    pub fn event(&self) -> &dyn EventImpl {
+        self.event.as_ref()
+    }
+
+    pub fn slice(&self) -> &[u8] {
+        unsafe { &self.shmem.as_slice()[self.event_used_bytes..] }
+    }
+
+    pub fn slice_mut(&mut self) -> &mut [u8] {
+        unsafe { &mut self.shmem.as_slice_mut()[self.event_used_bytes..] }
+    }
+
+    // Makes dropping this remove the flink of the current segment, for when the env process can't do it anymore
+    pub fn set_owner(&mut self, is_owner: bool) {
+        self.is_owner = is_owner;
+        self.shmem.set_owner(is_owner);
+    }
+
+    fn grow(&mut self) -> PyResult<()> {
+        let generation = self.generation + 1;
+        let size = 2 * self.shmem.len();
+        let (mut shmem, event, event_used_bytes) = create_segment(&self.flink(generation), size)?;
+        shmem.set_owner(self.is_owner);
+        let old_shmem = replace(&mut self.shmem, shmem);
+        let old_event = replace(&mut self.event, event);
+        let old_event_used_bytes = replace(&mut self.event_used_bytes, event_used_bytes);
+        self.generation = generation;
+        // If this message already caused a resize, the previous segment was never seen by the reader and can be dropped
+        if self.retired_segment_option.is_none() {
+            self.retired_segment_option = Some((old_shmem, old_event, old_event_used_bytes));
+        }
+        let (retired_shmem, _, retired_event_used_bytes) =
+            self.retired_segment_option.as_mut().unwrap();
+        let retired_slice =
+            unsafe { &mut retired_shmem.as_slice_mut()[*retired_event_used_bytes..] };
+        let offset = append_header(retired_slice, 0, Header::ShmResize);
+        append_usize(retired_slice, offset, generation);
+        Ok(())
+    }
+
+    // Writes a message using write, moving to larger segments until it fits
+    pub fn write_message<'py>(
+        &mut self,
+        py: Python<'py>,
+        mut write: impl FnMut(&mut [u8]) -> PyResult<usize>,
+    ) -> PyResult<usize> {
+        loop {
+            match write(self.slice_mut()) {
+                Err(err) if err.is_instance_of::<ShmBufferOverflowError>(py) => {
+                    let size = self.shmem.len();
+                    if 2 * size > MAX_MESSAGE_SIZE {
+                        return Err(err);
+                    }
+                    self.grow()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Wakes the env process. If the last message caused a resize, the env process is still waiting on the event of
+    // the segment with the ShmResize notice.
+    pub fn signal(&mut self) -> PyResult<()> {
+        let event = match &self.retired_segment_option {
+            Some((_, event, _)) => event.as_ref(),
+            None => self.event.as_ref(),
+        };
+        event
+            .set(EventState::Signaled)
+            .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
+        self.retired_segment_option = None;
+        Ok(())
+    }
+
+    // Drops the segment which held the last ShmResize notice. The env process calls this once it receives a message,
+    // since by then the EnvProcessInterface has moved to the current segment.
+    pub fn release_retired_segment(&mut self) {
+        self.retired_segment_option = None;
+    }
+
+    // Retrieves the header of the current message, following any ShmResize notices
+    pub fn retrieve_message_header(&mut self) -> PyResult<(Header, usize)> {
+        let (mut header, mut offset) = retrieve_header(self.slice(), 0)?;
+        while header == Header::ShmResize {
+            let (generation, _) = retrieve_usize(self.slice(), offset)?;
+            let (mut shmem, event, event_used_bytes) = open_segment(&self.flink(generation))?;
+            shmem.set_owner(self.is_owner);
+            self.shmem = shmem;
+            self.event = event;
+            self.event_used_bytes = event_used_bytes;
+            self.generation = generation;
+            (header, offset) = retrieve_header(self.slice(), 0)?;
+        }
+        Ok((header, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use pyany_serde::communication::retrieve_bytes;
+
+    use super::*;
+
+    fn checked_serde(serde_type: PyAnySerdeType) -> CheckedSerde {
+        CheckedSerde::new((&serde_type).try_into().unwrap(), Some(serde_type))
+    }
+
+    #[test]
+    fn serde_type_sizes_match_serialized_sizes() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let int_serde_type = Py::new(py, PyAnySerdeType::INT {}).unwrap();
+            let float_serde_type = Py::new(py, PyAnySerdeType::FLOAT {}).unwrap();
+            let string_serde_type = Py::new(py, PyAnySerdeType::STRING {}).unwrap();
+            let case_list = [
+                (PyAnySerdeType::BOOL {}, c"True"),
+                (PyAnySerdeType::COMPLEX {}, c"1 + 2j"),
+                (PyAnySerdeType::BYTES {}, c"b'bytes'"),
+                (PyAnySerdeType::STRING {}, c"'agent_0'"),
+                (
+                    PyAnySerdeType::LIST {
+                        items_serde_type: int_serde_type.clone_ref(py),
+                    },
+                    c"[1, 2, 3]",
+                ),
+                (
+                    PyAnySerdeType::SET {
+                        items_serde_type: string_serde_type.clone_ref(py),
+                    },
+                    c"{'a', 'bc'}",
+                ),
+                (
+                    PyAnySerdeType::DICT {
+                        keys_serde_type: string_serde_type.clone_ref(py),
+                        values_serde_type: float_serde_type.clone_ref(py),
+                    },
+                    c"{'a': 1.0, 'bcd': 2.0}",
+                ),
+                (
+                    PyAnySerdeType::OPTION {
+                        value_serde_type: string_serde_type.clone_ref(py),
+                    },
+                    c"'some'",
+                ),
+                (
+                    PyAnySerdeType::OPTION {
+                        value_serde_type: string_serde_type.clone_ref(py),
+                    },
+                    c"None",
+                ),
+                (
+                    PyAnySerdeType::TUPLE {
+                        item_serde_types: vec![PyAnySerdeType::INT {}, PyAnySerdeType::STRING {}],
+                    },
+                    c"(1, 'ab')",
+                ),
+                (
+                    PyAnySerdeType::TYPEDDICT {
+                        key_serde_type_dict: BTreeMap::from([
+                            ("reward".to_string(), PyAnySerdeType::FLOAT {}),
+                            ("name".to_string(), PyAnySerdeType::STRING {}),
+                        ]),
+                    },
+                    c"{'reward': 1.0, 'name': 'abc'}",
+                ),
+            ];
+            let mut buf = vec![0; 1024];
+            for (serde_type, obj) in case_list {
+                let serde = checked_serde(serde_type);
+                let obj = py.eval(obj, None, None).unwrap();
+                let n_bytes = serde.append(&mut buf, 0, &obj).unwrap();
+                assert_eq!(serialized_size(&serde, &obj).unwrap(), n_bytes);
+                assert_eq!(
+                    append_checked(&serde, &mut buf, 0, &obj, "test").unwrap(),
+                    n_bytes
+                );
+            }
+        })
+    }
+
+    #[test]
+    fn append_checked_reports_overflow() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let obj = py.eval(c"'agent_0'", None, None).unwrap();
+            let mut buf = vec![0; 8];
+            for serde in [
+                checked_serde(PyAnySerdeType::STRING {}),
+                // Payloads which can't be sized from their serde type go through the scratch buffer
+                checked_serde(PyAnySerdeType::PICKLE {}),
+            ] {
+                let err = append_checked(&serde, &mut buf, 0, &obj, "test").unwrap_err();
+                assert!(err.is_instance_of::<ShmBufferOverflowError>(py));
+            }
+        })
+    }
+
+    #[test]
+    fn reader_follows_growing_shmem() {
+        pyo3::prepare_freethreaded_python();
+        let flinks_folder = std::env::temp_dir().join(format!(
+            "rlgym_learn_shm_buffer_test_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&flinks_folder).unwrap();
+        let flinks_folder = flinks_folder.to_str().unwrap();
+        let mut writer = GrowableShmem::create(flinks_folder, "proc", 1024).unwrap();
+        let mut reader = GrowableShmem::open(flinks_folder, "proc").unwrap();
+        // Needs more than one resize, so the notice left for the reader must point past the intermediate segment
+        let payload: Vec<u8> = (0..5000).map(|idx| idx as u8).collect();
+        Python::with_gil(|py| {
+            writer
+                .write_message(py, |buf| {
+                    let offset = append_header(buf, 0, Header::EnvAction);
+                    append_bytes_checked(buf, offset, &payload, "test")
+                })
+                .unwrap();
+        });
+        assert_eq!(writer.generation, 3);
+        writer.signal().unwrap();
+        let (header, offset) = reader.retrieve_message_header().unwrap();
+        assert_eq!(header, Header::EnvAction);
+        assert_eq!(reader.generation, 3);
+        let (bytes, _) = retrieve_bytes(reader.slice(), offset).unwrap();
+        assert_eq!(bytes, payload.as_slice());
+        drop(reader);
+        drop(writer);
+        fs::remove_dir_all(flinks_folder).unwrap();
+    }
+}
@@ -1,14 +1,22 @@
 use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::exceptions::PyTimeoutError;
 use pyo3::sync::GILOnceCell;
-use pyo3::types::PyBytes;
+use pyo3::types::{PyBytes, PyNone};
 use pyo3::{intern, prelude::*, IntoPyObjectExt};
 use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+const MAX_DATAGRAM_SIZE: usize = 65507;
 
 #[derive(Debug, PartialEq)]
 pub enum Header {
     EnvShapesRequest,
     EnvAction,
     Stop,
+    EnvError,
+    ShmResize,
+    EnvRebuild,
+    EnvCommand,
 }
 
 impl Display for Header {
@@ -17,6 +25,10 @@ impl Display for Header {
             Self::EnvShapesRequest => write!(f, "EnvShapesRequest"),
             Self::EnvAction => write!(f, "EnvAction"),
             Self::Stop => write!(f, "Stop"),
+            Self::EnvError => write!(f, "EnvError"),
+            Self::ShmResize => write!(f, "ShmResize"),
+            Self::EnvRebuild => write!(f, "EnvRebuild"),
+            Self::EnvCommand => write!(f, "EnvCommand"),
         }
     }
 }
@@ -35,6 +47,56 @@ pub fn recvfrom_byte<'py>(py: Python<'py>, socket: &PyObject) -> PyResult<PyObje
     )
 }
 
+// Runs recv with the socket's timeout set (if provided). Returns None if the timeout elapsed.
+fn recv_with_timeout<'py, T>(
+    py: Python<'py>,
+    socket: &PyObject,
+    timeout_option: Option<Duration>,
+    recv: impl FnOnce() -> PyResult<T>,
+) -> PyResult<Option<T>> {
+    let Some(timeout) = timeout_option else {
+        return recv().map(Some);
+    };
+    socket.call_method1(py, intern!(py, "settimeout"), (timeout.as_secs_f64(),))?;
+    let result = recv();
+    socket.call_method1(py, intern!(py, "settimeout"), (PyNone::get(py),))?;
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(err) if err.is_instance_of::<PyTimeoutError>(py) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Like recvfrom_byte, but gives up after the timeout (if provided). Returns None if the timeout elapsed.
+pub fn recvfrom_byte_timeout<'py>(
+    py: Python<'py>,
+    socket: &PyObject,
+    timeout_option: Option<Duration>,
+) -> PyResult<Option<PyObject>> {
+    recv_with_timeout(py, socket, timeout_option, || recvfrom_byte(py, socket))
+}
+
+// Receives the contents of a single datagram, giving up after the timeout (if provided)
+pub fn recvfrom_bytes_timeout<'py>(
+    py: Python<'py>,
+    socket: &PyObject,
+    timeout_option: Option<Duration>,
+) -> PyResult<Option<Vec<u8>>> {
+    recv_with_timeout(py, socket, timeout_option, || {
+        let (bytes, _) = socket
+            .call_method1(py, intern!(py, "recvfrom"), (MAX_DATAGRAM_SIZE,))?
+            .extract::<(Vec<u8>, PyObject)>(py)?;
+        Ok(bytes)
+    })
+}
+
+pub fn timeout_err(proc_id: &str, phase: &str, timeout: Duration) -> PyErr {
+    PyTimeoutError::new_err(format!(
+        "Env process with proc id {} did not respond within {:?} during {}",
+        proc_id, timeout, phase
+    ))
+}
+
 #[pyfunction]
 pub fn sendto_byte_py(socket: PyObject, address: PyObject) -> PyResult<()> {
     Python::with_gil(|py| sendto_byte(py, &socket, &address))
@@ -54,6 +116,20 @@ pub fn sendto_byte<'py>(py: Python<'py>, socket: &PyObject, address: &PyObject)
     Ok(())
 }
 
+pub fn sendto_bytes<'py>(
+    py: Python<'py>,
+    socket: &PyObject,
+    address: &PyObject,
+    bytes: &[u8],
+) -> PyResult<()> {
+    socket.call_method1(
+        py,
+        intern!(py, "sendto"),
+        (PyBytes::new(py, bytes), address),
+    )?;
+    Ok(())
+}
+
 pub fn get_flink(flinks_folder: &str, proc_id: &str) -> String {
     format!("{}/{}", flinks_folder, proc_id)
 }
@@ -63,6 +139,10 @@ pub fn append_header(buf: &mut [u8], offset: usize, header: Header) -> usize {
         Header::EnvShapesRequest => 0,
         Header::EnvAction => 1,
         Header::Stop => 2,
+        Header::EnvError => 3,
+        Header::ShmResize => 4,
+        Header::EnvRebuild => 5,
+        Header::EnvCommand => 6,
     };
     offset + 1
 }
@@ -72,6 +152,10 @@ pub fn retrieve_header(slice: &[u8], offset: usize) -> PyResult<(Header, usize)>
         0 => Ok(Header::EnvShapesRequest),
         1 => Ok(Header::EnvAction),
         2 => Ok(Header::Stop),
+        3 => Ok(Header::EnvError),
+        4 => Ok(Header::ShmResize),
+        5 => Ok(Header::EnvRebuild),
+        6 => Ok(Header::EnvCommand),
         v => Err(InvalidStateError::new_err(format!(
             "tried to retrieve header from shared_memory but got value {}",
             v
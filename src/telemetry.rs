@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pyany_serde::communication::retrieve_usize;
+use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::shm_buffer::append_usize_checked;
+
+// Calls on the env which env processes time and report to the EnvProcessInterface
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EnvCall {
+    Step = 0,
+    Reset = 1,
+    SetState = 2,
+    Render = 3,
+}
+
+impl EnvCall {
+    fn from_usize(val: usize) -> PyResult<Self> {
+        match val {
+            0 => Ok(EnvCall::Step),
+            1 => Ok(EnvCall::Reset),
+            2 => Ok(EnvCall::SetState),
+            3 => Ok(EnvCall::Render),
+            _ => Err(InvalidStateError::new_err(format!(
+                "Received unknown env call {} in env call timings",
+                val
+            ))),
+        }
+    }
+
+    fn stats_name(&self) -> &'static str {
+        match self {
+            EnvCall::Step => "env_step",
+            EnvCall::Reset => "env_reset",
+            EnvCall::SetState => "env_set_state",
+            EnvCall::Render => "env_render",
+        }
+    }
+}
+
+// Step responses end with the timings of the env calls made since the previous response. Renders happen after a
+// response is sent, so they are reported with the next one.
+pub fn append_env_call_timing_list(
+    buf: &mut [u8],
+    offset: usize,
+    env_call_timing_list: &[(EnvCall, Duration)],
+) -> PyResult<usize> {
+    let mut offset = append_usize_checked(
+        buf,
+        offset,
+        env_call_timing_list.len(),
+        "env call timing count",
+    )?;
+    for (env_call, duration) in env_call_timing_list.iter() {
+        offset = append_usize_checked(buf, offset, *env_call as usize, "env call")?;
+        offset = append_usize_checked(buf, offset, duration.as_nanos() as usize, "env call time")?;
+    }
+    Ok(offset)
+}
+
+pub fn retrieve_env_call_timing_list(
+    buf: &[u8],
+    offset: usize,
+) -> PyResult<(Vec<(EnvCall, Duration)>, usize)> {
+    let (n_timings, mut offset) = retrieve_usize(buf, offset)?;
+    let mut env_call_timing_list = Vec::with_capacity(n_timings);
+    for _ in 0..n_timings {
+        let env_call;
+        (env_call, offset) = retrieve_usize(buf, offset)?;
+        let nanos;
+        (nanos, offset) = retrieve_usize(buf, offset)?;
+        env_call_timing_list.push((
+            EnvCall::from_usize(env_call)?,
+            Duration::from_nanos(nanos as u64),
+        ));
+    }
+    Ok((env_call_timing_list, offset))
+}
+
+// Bucket i of the histogram counts durations under 2^i microseconds which don't fit in an earlier bucket. The last
+// bucket also counts everything longer.
+const N_HISTOGRAM_BUCKETS: usize = 24;
+
+#[derive(Clone, Default)]
+pub struct DurationStats {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    histogram: [u64; N_HISTOGRAM_BUCKETS],
+}
+
+impl DurationStats {
+    pub fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+        let micros = duration.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.histogram[bucket.min(N_HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+
+    // Times are in seconds. The histogram maps the upper bound of each non-empty bucket to its count.
+    pub fn to_py_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let histogram = PyDict::new(py);
+        for (bucket, &count) in self.histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let upper_bound = if bucket == N_HISTOGRAM_BUCKETS - 1 {
+                f64::INFINITY
+            } else {
+                (1_u64 << bucket) as f64 / 1e6
+            };
+            histogram.set_item(upper_bound, count)?;
+        }
+        let stats = PyDict::new(py);
+        stats.set_item("count", self.count)?;
+        stats.set_item("total", self.total.as_secs_f64())?;
+        stats.set_item(
+            "mean",
+            if self.count == 0 {
+                0.0
+            } else {
+                self.total.as_secs_f64() / self.count as f64
+            },
+        )?;
+        stats.set_item("max", self.max.as_secs_f64())?;
+        stats.set_item("histogram", histogram)?;
+        Ok(stats)
+    }
+}
+
+fn env_call_stats_to_py_dict<'py>(
+    py: Python<'py>,
+    env_call_stats_map: &HashMap<EnvCall, DurationStats>,
+    stats: &Bound<'py, PyDict>,
+) -> PyResult<()> {
+    for (env_call, duration_stats) in env_call_stats_map.iter() {
+        stats.set_item(env_call.stats_name(), duration_stats.to_py_dict(py)?)?;
+    }
+    Ok(())
+}
+
+// Counters and timings collected by the EnvProcessInterface, including the env call timings reported by env processes
+#[derive(Default)]
+pub struct Telemetry {
+    n_collections: u64,
+    n_process_steps: u64,
+    n_timesteps: u64,
+    wait_stats: DurationStats,
+    decode_stats: DurationStats,
+    encode_stats: DurationStats,
+    env_call_stats_map: HashMap<EnvCall, DurationStats>,
+    // By the proc id of the env process, not of its sub-envs
+    proc_id_env_call_stats_map: HashMap<String, HashMap<EnvCall, DurationStats>>,
+}
+
+impl Telemetry {
+    pub fn record_collection(&mut self, n_process_steps: usize, n_timesteps: usize) {
+        self.n_collections += 1;
+        self.n_process_steps += n_process_steps as u64;
+        self.n_timesteps += n_timesteps as u64;
+    }
+
+    pub fn record_wait(&mut self, duration: Duration) {
+        self.wait_stats.record(duration);
+    }
+
+    pub fn record_decode(&mut self, duration: Duration) {
+        self.decode_stats.record(duration);
+    }
+
+    pub fn record_encode(&mut self, duration: Duration) {
+        self.encode_stats.record(duration);
+    }
+
+    pub fn record_env_calls(
+        &mut self,
+        proc_id: &str,
+        env_call_timing_list: &[(EnvCall, Duration)],
+    ) {
+        let proc_env_call_stats_map = self
+            .proc_id_env_call_stats_map
+            .entry(proc_id.to_string())
+            .or_default();
+        for &(env_call, duration) in env_call_timing_list.iter() {
+            self.env_call_stats_map
+                .entry(env_call)
+                .or_default()
+                .record(duration);
+            proc_env_call_stats_map
+                .entry(env_call)
+                .or_default()
+                .record(duration);
+        }
+    }
+
+    // The totals keep what was recorded for the process
+    pub fn remove_process(&mut self, proc_id: &str) {
+        self.proc_id_env_call_stats_map.remove(proc_id);
+    }
+
+    pub fn to_py_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let stats = PyDict::new(py);
+        stats.set_item("n_collections", self.n_collections)?;
+        stats.set_item("n_process_steps", self.n_process_steps)?;
+        stats.set_item("n_timesteps", self.n_timesteps)?;
+        stats.set_item("wait_for_envs", self.wait_stats.to_py_dict(py)?)?;
+        stats.set_item("decode", self.decode_stats.to_py_dict(py)?)?;
+        stats.set_item("encode", self.encode_stats.to_py_dict(py)?)?;
+        env_call_stats_to_py_dict(py, &self.env_call_stats_map, &stats)?;
+        let processes = PyDict::new(py);
+        for (proc_id, env_call_stats_map) in self.proc_id_env_call_stats_map.iter() {
+            let proc_stats = PyDict::new(py);
+            env_call_stats_to_py_dict(py, env_call_stats_map, &proc_stats)?;
+            processes.set_item(proc_id, proc_stats)?;
+        }
+        stats.set_item("processes", processes)?;
+        Ok(stats)
+    }
+}
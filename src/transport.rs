@@ -0,0 +1,827 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::mem::{size_of, size_of_val};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{LazyLock, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use pyo3::exceptions::asyncio::InvalidStateError;
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use raw_sync::events::EventState;
+use raw_sync::Timeout;
+
+use crate::notification::{NotificationReceiver, Notifier};
+use crate::shm_buffer::{GrowableShmem, ShmBufferOverflowError, MAX_MESSAGE_SIZE};
+use crate::synchronization::{retrieve_header, Header};
+
+// The name of the transport selected by the given options. Both sides include it in their handshake.
+pub fn transport_name(
+    tcp_address_option: Option<&str>,
+    in_process: bool,
+) -> PyResult<&'static str> {
+    match (tcp_address_option, in_process) {
+        (Some(_), true) => Err(PyValueError::new_err(
+            "Envs hosted in this process can't be reached over TCP",
+        )),
+        (Some(_), false) => Ok("tcp"),
+        (None, true) => Ok("channel"),
+        (None, false) => Ok("shm"),
+    }
+}
+
+// Moves messages between the EnvProcessInterface and a single env process. Each side writes a message into the
+// transport's outgoing buffer and sends it, and the other side receives it and reads it from the incoming buffer.
+pub trait Transport {
+    // Writes a message using write, growing the outgoing buffer until it fits
+    fn write_message(
+        &mut self,
+        py: Python<'_>,
+        write: &mut dyn FnMut(&mut [u8]) -> PyResult<usize>,
+    ) -> PyResult<usize>;
+
+    // Sends the last written message to the other side
+    fn send(&mut self, py: Python<'_>) -> PyResult<()>;
+
+    // Waits for the next message, giving up after the timeout (if provided). Returns false if no message was
+    // received.
+    fn recv_timeout(&mut self, py: Python<'_>, timeout_option: Option<Duration>) -> PyResult<bool>;
+
+    // Retrieves the header of the last received message
+    fn retrieve_message_header(&mut self) -> PyResult<(Header, usize)>;
+
+    // The buffer received messages are read from
+    fn slice(&self) -> &[u8];
+
+    // The buffer messages are written to
+    fn slice_mut(&mut self) -> &mut [u8];
+
+    // A file descriptor which becomes readable when a message is available, if the transport can be polled natively
+    fn poll_fd(&self) -> Option<i32>;
+
+    // The Python socket notifications arrive on, if the transport is notified through one
+    fn py_socket(&self) -> Option<&PyObject>;
+
+    // Whether the last receive found that the other side is gone, for transports which can tell
+    fn is_closed(&self) -> bool;
+
+    // Called by the EnvProcessInterface when it retires the env process, so that anything the env process would
+    // normally clean up on exit is cleaned up when this is dropped
+    fn take_ownership(&mut self);
+}
+
+// How the env process tells the EnvProcessInterface that a message is ready, for transports that can't be polled
+// directly
+enum Signal {
+    EnvProcess(Notifier),
+    Interface(NotificationReceiver),
+}
+
+// Exchanges messages through a shared memory flink in flinks_folder, so both sides need to be on the same host
+pub struct ShmTransport {
+    shm: GrowableShmem,
+    signal: Signal,
+}
+
+impl ShmTransport {
+    // Used by the env process
+    pub fn create(
+        flinks_folder: &str,
+        proc_id: &str,
+        size: usize,
+        notifier: Notifier,
+    ) -> PyResult<Self> {
+        Ok(ShmTransport {
+            shm: GrowableShmem::create(flinks_folder, proc_id, size)?,
+            signal: Signal::EnvProcess(notifier),
+        })
+    }
+
+    // Used by the EnvProcessInterface
+    pub fn open(
+        flinks_folder: &str,
+        proc_id: &str,
+        receiver: NotificationReceiver,
+    ) -> PyResult<Self> {
+        Ok(ShmTransport {
+            shm: GrowableShmem::open(flinks_folder, proc_id)?,
+            signal: Signal::Interface(receiver),
+        })
+    }
+}
+
+impl Transport for ShmTransport {
+    fn write_message(
+        &mut self,
+        py: Python<'_>,
+        write: &mut dyn FnMut(&mut [u8]) -> PyResult<usize>,
+    ) -> PyResult<usize> {
+        self.shm.write_message(py, write)
+    }
+
+    fn send(&mut self, py: Python<'_>) -> PyResult<()> {
+        match &self.signal {
+            Signal::EnvProcess(notifier) => notifier.notify(py),
+            Signal::Interface(_) => self.shm.signal(),
+        }
+    }
+
+    fn recv_timeout(&mut self, py: Python<'_>, timeout_option: Option<Duration>) -> PyResult<bool> {
+        match &self.signal {
+            Signal::EnvProcess(_) => {
+                let timeout = timeout_option.map_or(Timeout::Infinite, Timeout::Val);
+                if let Err(err) = self.shm.event().wait(timeout) {
+                    // raw_sync reports timeouts as errors
+                    if timeout_option.is_some() {
+                        return Ok(false);
+                    }
+                    return Err(InvalidStateError::new_err(err.to_string()));
+                }
+                self.shm
+                    .event()
+                    .set(EventState::Clear)
+                    .map_err(|err| InvalidStateError::new_err(err.to_string()))?;
+                self.shm.release_retired_segment();
+                Ok(true)
+            }
+            Signal::Interface(receiver) => receiver.recv_timeout(py, timeout_option),
+        }
+    }
+
+    fn retrieve_message_header(&mut self) -> PyResult<(Header, usize)> {
+        self.shm.retrieve_message_header()
+    }
+
+    fn slice(&self) -> &[u8] {
+        self.shm.slice()
+    }
+
+    fn slice_mut(&mut self) -> &mut [u8] {
+        self.shm.slice_mut()
+    }
+
+    fn poll_fd(&self) -> Option<i32> {
+        match &self.signal {
+            Signal::EnvProcess(_) => None,
+            Signal::Interface(receiver) => receiver.raw_fd(),
+        }
+    }
+
+    fn py_socket(&self) -> Option<&PyObject> {
+        match &self.signal {
+            Signal::EnvProcess(_) => None,
+            Signal::Interface(receiver) => receiver.py_socket(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    fn take_ownership(&mut self) {
+        // The child process created the shmem and would normally remove it on exit
+        self.shm.set_owner(true);
+    }
+}
+
+fn io_err(context: &str, err: std::io::Error) -> PyErr {
+    InvalidStateError::new_err(format!("{}: {}", context, err))
+}
+
+// Buffers are stored as u64s so they are 8 byte aligned. The numpy serde pads data based on the address it is
+// written to, so the buffers on both ends of the connection need the same alignment.
+fn as_bytes(buf: &[u64]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, size_of_val(buf)) }
+}
+
+fn as_bytes_mut(buf: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, size_of_val(buf)) }
+}
+
+// Writes a message into buf using write, doubling buf until it fits. Returns the length of the message.
+fn write_growable(
+    py: Python<'_>,
+    buf: &mut Vec<u64>,
+    write: &mut dyn FnMut(&mut [u8]) -> PyResult<usize>,
+) -> PyResult<usize> {
+    loop {
+        match write(as_bytes_mut(buf)) {
+            Err(err) if err.is_instance_of::<ShmBufferOverflowError>(py) => {
+                let n_words = 2 * buf.len();
+                if n_words * size_of::<u64>() > MAX_MESSAGE_SIZE {
+                    return Err(err);
+                }
+                buf.resize(n_words, 0);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, bytes: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    stream.write_all(bytes)
+}
+
+// Reads a frame into buf, resizing it as needed. Returns the length of the frame.
+fn read_frame(stream: &mut TcpStream, buf: &mut Vec<u64>) -> std::io::Result<usize> {
+    let mut len_bytes = [0_u8; size_of::<u64>()];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u64::from_le_bytes(len_bytes) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("received frame of {} bytes", len),
+        ));
+    }
+    let n_words = len.div_ceil(size_of::<u64>());
+    if buf.len() < n_words {
+        buf.resize(n_words, 0);
+    }
+    stream.read_exact(&mut as_bytes_mut(buf)[..len])?;
+    Ok(len)
+}
+
+// Exchanges messages as length-prefixed frames over a TCP connection, so env processes can run on other hosts.
+// The env process connects to the address the EnvProcessInterface listens on and identifies itself by sending the
+// shared token and its proc id as the first frames, after which both sides exchange their handshakes as the first
+// message. Messages carry pickled objects and aren't encrypted, so the address should only be reachable from trusted
+// hosts.
+pub struct TcpTransport {
+    stream: TcpStream,
+    send_buf: Vec<u64>,
+    send_len: usize,
+    recv_buf: Vec<u64>,
+    recv_len: usize,
+    closed: bool,
+}
+
+impl TcpTransport {
+    fn new(stream: TcpStream, buffer_size: usize) -> PyResult<Self> {
+        stream
+            .set_nodelay(true)
+            .map_err(|err| io_err("Unable to configure TCP connection", err))?;
+        let n_words = buffer_size.div_ceil(size_of::<u64>());
+        Ok(TcpTransport {
+            stream,
+            send_buf: vec![0; n_words],
+            send_len: 0,
+            recv_buf: vec![0; n_words],
+            recv_len: 0,
+            closed: false,
+        })
+    }
+
+    // Used by the env process
+    pub fn connect(
+        py: Python<'_>,
+        address: &str,
+        token: &str,
+        proc_id: &str,
+        buffer_size: usize,
+    ) -> PyResult<Self> {
+        let mut stream = py
+            .allow_threads(|| TcpStream::connect(address))
+            .map_err(|err| io_err(&format!("Unable to connect to {}", address), err))?;
+        write_frame(&mut stream, token.as_bytes())
+            .and_then(|_| write_frame(&mut stream, proc_id.as_bytes()))
+            .map_err(|err| io_err(&format!("Unable to identify to {}", address), err))?;
+        TcpTransport::new(stream, buffer_size)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn write_message(
+        &mut self,
+        py: Python<'_>,
+        write: &mut dyn FnMut(&mut [u8]) -> PyResult<usize>,
+    ) -> PyResult<usize> {
+        self.send_len = write_growable(py, &mut self.send_buf, write)?;
+        Ok(self.send_len)
+    }
+
+    fn send(&mut self, py: Python<'_>) -> PyResult<()> {
+        let stream = &mut self.stream;
+        let bytes = &as_bytes(&self.send_buf)[..self.send_len];
+        py.allow_threads(|| write_frame(stream, bytes))
+            .map_err(|err| io_err("Unable to send message over TCP", err))
+    }
+
+    fn recv_timeout(&mut self, py: Python<'_>, timeout_option: Option<Duration>) -> PyResult<bool> {
+        let stream = &mut self.stream;
+        let recv_buf = &mut self.recv_buf;
+        let closed = &mut self.closed;
+        let result = py.allow_threads(|| -> std::io::Result<Option<usize>> {
+            // Only the wait for the start of the frame is subject to the timeout
+            stream.set_read_timeout(timeout_option)?;
+            let peeked = stream.peek(&mut [0_u8; 1]);
+            stream.set_read_timeout(None)?;
+            match peeked {
+                // The connection was closed or reset, which means the other side is gone. The health check takes
+                // care of it.
+                Ok(0) => {
+                    *closed = true;
+                    Ok(None)
+                }
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => {
+                    *closed = true;
+                    Ok(None)
+                }
+                Ok(_) => Ok(Some(read_frame(stream, recv_buf)?)),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    Ok(None)
+                }
+                Err(err) => Err(err),
+            }
+        });
+        match result.map_err(|err| io_err("Unable to receive message over TCP", err))? {
+            Some(len) => {
+                self.recv_len = len;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn retrieve_message_header(&mut self) -> PyResult<(Header, usize)> {
+        retrieve_header(self.slice(), 0)
+    }
+
+    fn slice(&self) -> &[u8] {
+        &as_bytes(&self.recv_buf)[..self.recv_len]
+    }
+
+    fn slice_mut(&mut self) -> &mut [u8] {
+        as_bytes_mut(&mut self.send_buf)
+    }
+
+    #[cfg(unix)]
+    fn poll_fd(&self) -> Option<i32> {
+        Some(self.stream.as_raw_fd())
+    }
+
+    #[cfg(not(unix))]
+    fn poll_fd(&self) -> Option<i32> {
+        None
+    }
+
+    fn py_socket(&self) -> Option<&PyObject> {
+        None
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn take_ownership(&mut self) {}
+}
+
+// The EnvProcessInterface doesn't know the shm_buffer_size of env processes, so the buffers it creates start at this
+// size and grow as needed
+pub const INITIAL_BUFFER_SIZE: usize = 1 << 16;
+
+// How long a peer has to identify itself after connecting before it is dropped
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Compares the tokens in time independent of where they first differ, so the token can't be guessed byte by byte
+fn tokens_match(token: &[u8], other_token: &[u8]) -> bool {
+    token.len() == other_token.len()
+        && token
+            .iter()
+            .zip(other_token)
+            .fold(0_u8, |diff, (byte, other_byte)| diff | (byte ^ other_byte))
+            == 0
+}
+
+// Accepts the connections of env processes for the EnvProcessInterface. Env processes may connect in any order, so
+// connections from processes other than the one being added are kept until they are asked for. Env processes started
+// by something other than the EnvProcessInterface (e.g. on another host) are accepted under whichever proc id they
+// identify with. Peers which don't identify with the token in time are dropped before anything else is read from them.
+pub struct TcpTransportListener {
+    listener: TcpListener,
+    token: String,
+    pending_stream_map: HashMap<String, TcpStream>,
+}
+
+impl TcpTransportListener {
+    pub fn bind(address: &str, token: &str) -> PyResult<Self> {
+        let listener = TcpListener::bind(address)
+            .map_err(|err| io_err(&format!("Unable to listen on {}", address), err))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| io_err("Unable to configure TCP listener", err))?;
+        Ok(TcpTransportListener {
+            listener,
+            token: token.to_string(),
+            pending_stream_map: HashMap::new(),
+        })
+    }
+
+    // Reads the token and proc id the peer identifies with, returning the proc id if the token matches
+    fn identify(&self, stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(IDENTIFY_TIMEOUT))?;
+        let mut buf = Vec::new();
+        let len = read_frame(stream, &mut buf)?;
+        if !tokens_match(self.token.as_bytes(), &as_bytes(&buf)[..len]) {
+            return Ok(None);
+        }
+        let len = read_frame(stream, &mut buf)?;
+        stream.set_read_timeout(None)?;
+        Ok(String::from_utf8(as_bytes(&buf)[..len].to_vec()).ok())
+    }
+
+    // The address env processes should connect to
+    pub fn address(&self) -> PyResult<String> {
+        Ok(self
+            .listener
+            .local_addr()
+            .map_err(|err| io_err("Unable to get TCP listener address", err))?
+            .to_string())
+    }
+
+    // Waits for the env process with the given proc id to connect, or for any env process to connect if None.
+    // Returns the proc id the env process identified with.
+    pub fn accept(
+        &mut self,
+        py: Python<'_>,
+        proc_id_option: Option<&str>,
+        timeout_option: Option<Duration>,
+    ) -> PyResult<(String, TcpTransport)> {
+        let start = Instant::now();
+        loop {
+            let pending_proc_id_option = match proc_id_option {
+                Some(proc_id) => self
+                    .pending_stream_map
+                    .contains_key(proc_id)
+                    .then_some(proc_id),
+                None => self.pending_stream_map.keys().next().map(String::as_str),
+            }
+            .map(str::to_string);
+            if let Some(proc_id) = pending_proc_id_option {
+                let stream = self.pending_stream_map.remove(&proc_id).unwrap();
+                return Ok((proc_id, TcpTransport::new(stream, INITIAL_BUFFER_SIZE)?));
+            }
+            match self.listener.accept() {
+                Ok((mut stream, _)) => {
+                    // Peers which fail to identify are dropped, closing their connection
+                    if let Ok(Some(other_proc_id)) = py.allow_threads(|| self.identify(&mut stream))
+                    {
+                        self.pending_stream_map.insert(other_proc_id, stream);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if let Some(timeout) = timeout_option {
+                        if start.elapsed() > timeout {
+                            return Err(PyTimeoutError::new_err(match proc_id_option {
+                                Some(proc_id) => format!(
+                                    "Env process with proc id {} did not connect within {:?}",
+                                    proc_id, timeout
+                                ),
+                                None => format!("No env process connected within {:?}", timeout),
+                            }));
+                        }
+                    }
+                    py.allow_threads(|| sleep(Duration::from_millis(1)));
+                }
+                Err(err) => return Err(io_err("Unable to accept TCP connection", err)),
+            }
+        }
+    }
+}
+
+// Exchanges messages with the other end of a channel_transport_pair, so both ends need to be in the same process.
+// Messages are copied into their own buffer when sent, so the sender can write the next message right away.
+// Without a signal attached, the receiving end can only wait on the channel itself.
+pub struct ChannelTransport {
+    signal_option: Option<Signal>,
+    sender: Sender<(Vec<u64>, usize)>,
+    receiver: Receiver<(Vec<u64>, usize)>,
+    send_buf: Vec<u64>,
+    send_len: usize,
+    recv_buf: Vec<u64>,
+    recv_len: usize,
+    closed: bool,
+}
+
+// Creates both ends of an in-process channel. Their buffers start at buffer_size and grow as needed.
+pub fn channel_transport_pair(buffer_size: usize) -> (ChannelTransport, ChannelTransport) {
+    let (sender_a, receiver_b) = channel();
+    let (sender_b, receiver_a) = channel();
+    let n_words = buffer_size.div_ceil(size_of::<u64>());
+    let new_end = |sender, receiver| ChannelTransport {
+        signal_option: None,
+        sender,
+        receiver,
+        send_buf: vec![0; n_words],
+        send_len: 0,
+        recv_buf: Vec::new(),
+        recv_len: 0,
+        closed: false,
+    };
+    (new_end(sender_a, receiver_a), new_end(sender_b, receiver_b))
+}
+
+impl ChannelTransport {
+    // Used by env threads, so the EnvProcessInterface is notified of messages the same way as with shared memory
+    pub fn attach_notifier(&mut self, notifier: Notifier) {
+        self.signal_option = Some(Signal::EnvProcess(notifier));
+    }
+
+    // Used by the EnvProcessInterface, so channels can be waited on alongside the notifications of other transports
+    pub fn attach_receiver(&mut self, receiver: NotificationReceiver) {
+        self.signal_option = Some(Signal::Interface(receiver));
+    }
+}
+
+// Env thread ends of the channels created by the EnvProcessInterface, waiting to be taken by their env threads
+static PENDING_CHANNEL_END_MAP: LazyLock<Mutex<HashMap<String, ChannelTransport>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn register_channel_end(proc_id: &str, channel_end: ChannelTransport) {
+    PENDING_CHANNEL_END_MAP
+        .lock()
+        .unwrap()
+        .insert(proc_id.to_string(), channel_end);
+}
+
+pub fn take_channel_end(proc_id: &str) -> PyResult<ChannelTransport> {
+    PENDING_CHANNEL_END_MAP
+        .lock()
+        .unwrap()
+        .remove(proc_id)
+        .ok_or_else(|| {
+            InvalidStateError::new_err(format!(
+                "No channel was created for the env thread with proc id {}",
+                proc_id
+            ))
+        })
+}
+
+impl Transport for ChannelTransport {
+    fn write_message(
+        &mut self,
+        py: Python<'_>,
+        write: &mut dyn FnMut(&mut [u8]) -> PyResult<usize>,
+    ) -> PyResult<usize> {
+        self.send_len = write_growable(py, &mut self.send_buf, write)?;
+        Ok(self.send_len)
+    }
+
+    fn send(&mut self, py: Python<'_>) -> PyResult<()> {
+        let n_words = self.send_len.div_ceil(size_of::<u64>());
+        self.sender
+            .send((self.send_buf[..n_words].to_vec(), self.send_len))
+            .map_err(|_| InvalidStateError::new_err("The other end of the channel was dropped"))?;
+        if let Some(Signal::EnvProcess(notifier)) = &self.signal_option {
+            notifier.notify(py)?;
+        }
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, py: Python<'_>, timeout_option: Option<Duration>) -> PyResult<bool> {
+        let mut timeout_option = timeout_option;
+        if let Some(Signal::Interface(notification_receiver)) = &self.signal_option {
+            if !notification_receiver.recv_timeout(py, timeout_option)? {
+                return Ok(false);
+            }
+            // The message is sent before the notification, so it is already waiting
+            timeout_option = None;
+        }
+        let receiver = &mut self.receiver;
+        let result = py.allow_threads(move || match timeout_option {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        });
+        match result {
+            Ok((buf, len)) => {
+                self.recv_buf = buf;
+                self.recv_len = len;
+                Ok(true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            // Like a closed TCP connection, a dropped sender means the other end is gone
+            Err(RecvTimeoutError::Disconnected) => {
+                self.closed = true;
+                Ok(false)
+            }
+        }
+    }
+
+    fn retrieve_message_header(&mut self) -> PyResult<(Header, usize)> {
+        retrieve_header(self.slice(), 0)
+    }
+
+    fn slice(&self) -> &[u8] {
+        &as_bytes(&self.recv_buf)[..self.recv_len]
+    }
+
+    fn slice_mut(&mut self) -> &mut [u8] {
+        as_bytes_mut(&mut self.send_buf)
+    }
+
+    fn poll_fd(&self) -> Option<i32> {
+        match &self.signal_option {
+            Some(Signal::Interface(receiver)) => receiver.raw_fd(),
+            _ => None,
+        }
+    }
+
+    fn py_socket(&self) -> Option<&PyObject> {
+        match &self.signal_option {
+            Some(Signal::Interface(receiver)) => receiver.py_socket(),
+            _ => None,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn take_ownership(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::handshake::{Handshake, ProtocolMismatchError};
+    use crate::shm_buffer::overflow_err;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+    const TOKEN: &str = "token";
+
+    fn send_bytes(py: Python<'_>, transport: &mut dyn Transport, bytes: &[u8]) {
+        transport
+            .write_message(py, &mut |buf| {
+                if buf.len() < bytes.len() {
+                    return Err(overflow_err("test", bytes.len(), buf.len()));
+                }
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok(bytes.len())
+            })
+            .unwrap();
+        transport.send(py).unwrap();
+    }
+
+    fn recv_bytes(py: Python<'_>, transport: &mut dyn Transport) -> Vec<u8> {
+        assert!(transport.recv_timeout(py, TIMEOUT).unwrap());
+        transport.slice().to_vec()
+    }
+
+    #[test]
+    fn tcp_loopback_round_trip() {
+        pyo3::prepare_freethreaded_python();
+        let mut listener = TcpTransportListener::bind("127.0.0.1:0", TOKEN).unwrap();
+        let address = listener.address().unwrap();
+        let env_process = thread::spawn(move || {
+            Python::with_gil(|py| {
+                let mut transport =
+                    TcpTransport::connect(py, &address, TOKEN, "external", 16).unwrap();
+                // Larger than the initial buffers on both sides, so both have to grow
+                let message = (0..INITIAL_BUFFER_SIZE * 2)
+                    .map(|idx| idx as u8)
+                    .collect::<Vec<_>>();
+                send_bytes(py, &mut transport, &message);
+                assert_eq!(recv_bytes(py, &mut transport), b"reply");
+            })
+        });
+        Python::with_gil(|py| {
+            let (proc_id, mut transport) = listener.accept(py, None, TIMEOUT).unwrap();
+            assert_eq!(proc_id, "external");
+            let message = recv_bytes(py, &mut transport);
+            assert_eq!(message.len(), INITIAL_BUFFER_SIZE * 2);
+            assert!(message
+                .iter()
+                .enumerate()
+                .all(|(idx, byte)| *byte == idx as u8));
+            send_bytes(py, &mut transport, b"reply");
+            py.allow_threads(|| env_process.join().unwrap());
+            assert!(!transport.recv_timeout(py, TIMEOUT).unwrap());
+            assert!(transport.is_closed());
+        });
+    }
+
+    #[test]
+    fn tcp_startup_sync_over_connection() {
+        pyo3::prepare_freethreaded_python();
+        let mut listener = TcpTransportListener::bind("127.0.0.1:0", TOKEN).unwrap();
+        let address = listener.address().unwrap();
+        let new_handshake = |n_envs_per_process: usize| {
+            Handshake::new(
+                vec![("agent_id_serde", 1)],
+                vec![("n_envs_per_process", n_envs_per_process.to_string())],
+            )
+        };
+        Python::with_gil(|py| {
+            let mut env_process_transport =
+                TcpTransport::connect(py, &address, TOKEN, "a", 16).unwrap();
+            new_handshake(2)
+                .send_over(py, &mut env_process_transport)
+                .unwrap();
+            let (_, mut transport) = listener.accept(py, None, TIMEOUT).unwrap();
+            assert!(transport.recv_timeout(py, TIMEOUT).unwrap());
+            let env_process_handshake = Handshake::from_bytes(transport.slice()).unwrap();
+            let epi_handshake = new_handshake(1);
+            epi_handshake.send_over(py, &mut transport).unwrap();
+            let err = epi_handshake
+                .check(
+                    &env_process_handshake,
+                    "The EnvProcessInterface",
+                    "env process a",
+                )
+                .err()
+                .unwrap();
+            assert!(err.is_instance_of::<ProtocolMismatchError>(py));
+            assert!(env_process_transport.recv_timeout(py, TIMEOUT).unwrap());
+            assert_eq!(
+                Handshake::from_bytes(env_process_transport.slice()).unwrap(),
+                epi_handshake
+            );
+        });
+    }
+
+    #[test]
+    fn tcp_listener_keeps_connections_until_asked_for() {
+        pyo3::prepare_freethreaded_python();
+        let mut listener = TcpTransportListener::bind("127.0.0.1:0", TOKEN).unwrap();
+        let address = listener.address().unwrap();
+        Python::with_gil(|py| {
+            let mut first = TcpTransport::connect(py, &address, TOKEN, "first", 16).unwrap();
+            let mut second = TcpTransport::connect(py, &address, TOKEN, "second", 16).unwrap();
+            send_bytes(py, &mut first, b"from first");
+            send_bytes(py, &mut second, b"from second");
+            let (proc_id, mut transport) = listener.accept(py, Some("second"), TIMEOUT).unwrap();
+            assert_eq!(proc_id, "second");
+            assert_eq!(recv_bytes(py, &mut transport), b"from second");
+            let (proc_id, mut transport) = listener.accept(py, Some("first"), TIMEOUT).unwrap();
+            assert_eq!(proc_id, "first");
+            assert_eq!(recv_bytes(py, &mut transport), b"from first");
+        });
+    }
+
+    #[test]
+    fn tcp_listener_drops_peers_with_wrong_token() {
+        pyo3::prepare_freethreaded_python();
+        let mut listener = TcpTransportListener::bind("127.0.0.1:0", TOKEN).unwrap();
+        let address = listener.address().unwrap();
+        Python::with_gil(|py| {
+            let mut intruder = TcpTransport::connect(py, &address, "guess", "a", 16).unwrap();
+            let env_process = TcpTransport::connect(py, &address, TOKEN, "b", 16).unwrap();
+            let (proc_id, _transport) = listener.accept(py, None, TIMEOUT).unwrap();
+            assert_eq!(proc_id, "b");
+            let err = listener
+                .accept(py, Some("a"), Some(Duration::from_millis(50)))
+                .err()
+                .unwrap();
+            assert!(err.is_instance_of::<PyTimeoutError>(py));
+            assert!(!intruder.recv_timeout(py, TIMEOUT).unwrap());
+            assert!(intruder.is_closed());
+            assert!(!env_process.is_closed());
+        });
+    }
+
+    #[test]
+    fn tcp_listener_times_out_without_connections() {
+        pyo3::prepare_freethreaded_python();
+        let mut listener = TcpTransportListener::bind("127.0.0.1:0", TOKEN).unwrap();
+        Python::with_gil(|py| {
+            let err = listener
+                .accept(py, None, Some(Duration::from_millis(50)))
+                .err()
+                .unwrap();
+            assert!(err.is_instance_of::<PyTimeoutError>(py));
+        });
+    }
+
+    #[test]
+    fn channel_round_trip() {
+        pyo3::prepare_freethreaded_python();
+        let (mut transport, mut env_thread_transport) = channel_transport_pair(16);
+        let env_thread = thread::spawn(move || {
+            Python::with_gil(|py| {
+                // Larger than the initial buffer, so it has to grow
+                let message = (0..64).map(|idx| idx as u8).collect::<Vec<_>>();
+                send_bytes(py, &mut env_thread_transport, &message);
+                assert_eq!(recv_bytes(py, &mut env_thread_transport), b"reply");
+            })
+        });
+        Python::with_gil(|py| {
+            let message = recv_bytes(py, &mut transport);
+            assert_eq!(message.len(), 64);
+            assert!(message
+                .iter()
+                .enumerate()
+                .all(|(idx, byte)| *byte == idx as u8));
+            send_bytes(py, &mut transport, b"reply");
+            py.allow_threads(|| env_thread.join().unwrap());
+            assert!(!transport.recv_timeout(py, TIMEOUT).unwrap());
+            assert!(transport.is_closed());
+        });
+    }
+}